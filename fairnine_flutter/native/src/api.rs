@@ -1,30 +1,217 @@
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::path::PathBuf;
 use std::fs;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use whisper_rs::{WhisperContext, FullParams, SamplingStrategy};
+use whisper_rs::{WhisperContext, WhisperContextParameters, WhisperState, FullParams, SamplingStrategy};
 use flutter_rust_bridge::StreamSink;
 use anyhow::{Result, Context, anyhow};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
 
 const APP_VERSION: &str = "1.2.9";
 const GITHUB_REPO: &str = "open-free-launching/Fair9";
+const UPDATE_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// whisper.cpp backend this build was compiled with, from the crate features that
+/// Cargo.toml forwards to whisper-rs
+const WHISPER_BACKEND: &str = if cfg!(feature = "cuda") {
+    "cuda"
+} else if cfg!(feature = "metal") {
+    "metal"
+} else if cfg!(feature = "hipblas") {
+    "hipblas"
+} else if cfg!(feature = "vulkan") {
+    "vulkan"
+} else if cfg!(feature = "openblas") {
+    "openblas" // Faster CPU matmuls, still no GPU
+} else {
+    "cpu"
+};
+const GPU_BUILD: bool = cfg!(any(feature = "cuda", feature = "metal", feature = "hipblas", feature = "vulkan"));
 
 /// Voice Snippet: trigger phrase → expanded content
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VoiceSnippet {
+    /// Stable identifier for syncing and UI keys; survives trigger edits
+    #[serde(default)]
+    pub id: String,
     pub trigger: String,
     pub content: String,
+    /// Alternative contents, one picked at random per expansion. Older files
+    /// without this field load as a single variant holding `content`.
+    #[serde(default)]
+    pub variants: Vec<String>,
+    /// `trigger` is a regular expression; its captures fill `$1` / `$name` in the content
+    #[serde(default)]
+    pub is_regex: bool,
+}
+
+/// One emission from the live transcription stream.
+/// Interim results re-transcribe the still-growing window and may be revised;
+/// a final result commits the window and is never revised.
+#[derive(Clone, Debug)]
+pub struct TranscriptionResult {
+    pub text: String,
+    pub is_final: bool,
+    /// Approximate IPA of `text` when phonetic output is enabled
+    pub ipa: Option<String>,
+    /// The committed transcript with this pass appended; for finals, the new committed text
+    pub transcript: String,
+    /// Words in `text`, so the UI needn't re-split it
+    pub word_count: u32,
+    /// Milliseconds since `start_listening`, on the engine's clock
+    pub elapsed_ms: u64,
+    /// Byte offset in `text` where an expanded snippet's `{cursor}` marker was
+    pub cursor_offset: Option<usize>,
+}
+
+/// Snippet expansion result: the text to insert and where the caret should end up
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnippetExpansion {
+    pub text: String,
+    /// Byte offset of the `{cursor}` marker in `text`; None leaves the caret at the end
+    pub cursor_offset: Option<usize>,
+}
+
+impl SnippetExpansion {
+    fn plain(text: String) -> Self {
+        SnippetExpansion { text, cursor_offset: None }
+    }
+}
+
+/// Out-of-band notices for the UI, separate from transcription text
+#[derive(Clone, Debug)]
+pub enum EngineEvent {
+    /// Share of near-full-scale samples over the last window; the input gain is too hot
+    ClippingDetected { ratio: f32 },
+    /// A GPU inference failed; the model was reloaded on the CPU and transcription continues
+    GpuFallback { reason: String },
+    /// A recording transcribed as one take hit its length limit; its oldest audio is being dropped
+    RecordingLimitReached { max_seconds: u32 },
+}
+
+/// What went wrong, for callers that need to react to a particular failure
+/// (e.g. show "start Ollama" rather than a generic error). Anything without a
+/// variant of its own arrives as `Other` with the full message.
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum Fair9Error {
+    #[error("Model not loaded")]
+    ModelNotLoaded,
+    #[error("Model not found at {0}")]
+    ModelNotFound(String),
+    #[error("No input device available")]
+    NoInputDevice,
+    #[error("Input device '{name}' not found. Available: {available}")]
+    InputDeviceNotFound { name: String, available: String },
+    #[error("Already recording")]
+    AlreadyRecording,
+    #[error("Not recording")]
+    NotRecording,
+    #[error("Not listening")]
+    NotListening,
+    #[error("Transcription busy: another transcription is running")]
+    TranscriptionBusy,
+    #[error("Ollama isn't running at {0} (connection refused). Start Ollama and try again.")]
+    OllamaOffline(String),
+    #[error("Nothing is accepting connections at {0} (connection refused). Check the endpoint URL.")]
+    AiEndpointOffline(String),
+    #[error("{0}")]
+    AiTimedOut(String),
+    #[error("{0} cancelled by shutdown")]
+    Cancelled(String),
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("Snippet '{0}' already exists")]
+    SnippetExists(String),
+    #[error("No snippet matches '{0}'")]
+    SnippetNotFound(String),
+    #[error("Snippet '{trigger}' expands to {chars} characters; confirmation required")]
+    SnippetNeedsConfirmation { trigger: String, chars: u32 },
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<anyhow::Error> for Fair9Error {
+    fn from(e: anyhow::Error) -> Self {
+        // A variant raised deeper down survives being passed back up through anyhow
+        if let Some(kind) = e.downcast_ref::<Fair9Error>() {
+            return kind.clone();
+        }
+        e.chain()
+            .find_map(|cause| cause.downcast_ref::<Fair9Error>().cloned())
+            .unwrap_or_else(|| Fair9Error::Other(format!("{:#}", e)))
+    }
+}
+
+impl From<std::io::Error> for Fair9Error {
+    fn from(e: std::io::Error) -> Self {
+        Fair9Error::Other(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Fair9Error {
+    fn from(e: serde_json::Error) -> Self {
+        Fair9Error::Other(e.to_string())
+    }
 }
 
 // Constants
-const VAD_THRESHOLD_RMS: f32 = 0.01; // Adjust based on mic sensitivity
+const DEFAULT_VAD_THRESHOLD_RMS: f32 = 0.01; // See set_vad_threshold
 const SILENCE_DURATION_MS: u128 = 1000; // 1 second silence to finalize/clear?
+const DEFAULT_TURN_GAP_MS: u64 = 1500;
+const DUTY_CYCLE_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+const VAD_CALIBRATION_FACTOR: f32 = 2.5; // Speech threshold as a multiple of room noise
+const MIN_CALIBRATED_VAD_THRESHOLD: f32 = 0.002;
+const VAD_FRAME_SAMPLES: usize = SAMPLE_RATE * 30 / 1000; // 30ms VAD frames
+/// The rate of every sample inside the engine. whisper.cpp only accepts 16kHz mono,
+/// so audio is converted once where it enters (`capture_to_model_input` for the mic,
+/// `pcm16_to_model_input` and `transcribe_file` for buffers and files) and the
+/// buffer, VAD frames, whisper-mode DSP and timestamps all assume it from there on.
 const SAMPLE_RATE: usize = 16000;
+const GGML_MAGIC: u32 = 0x6767_6d6c; // "ggml" read as a little-endian u32; on disk the first 4 bytes are "lmgg"
+const MIN_INTERIM_SAMPLES: usize = SAMPLE_RATE / 2; // 0.5s before the first interim pass
+const FINAL_WINDOW_SAMPLES: usize = SAMPLE_RATE * 3; // 3s window is committed as a final
+const DEFAULT_MAX_BUFFER_SAMPLES: usize = SAMPLE_RATE * 30; // Safety cap if inference falls behind
+const MAX_RECORDING_SAMPLES: usize = SAMPLE_RATE * 60 * 30; // 30 min (~115 MB) for takes transcribed whole
+const MAX_COMMAND_HISTORY: usize = 100;
+const DEFAULT_MIN_SESSION_MS: u64 = 250; // Shorter batch sessions are treated as accidental taps
+const WHISPER_MODE_GAIN_DB: f32 = 15.0; // Lifts hushed speech towards normal speaking level
+const WHISPER_MODE_HIGHPASS_HZ: f32 = 80.0; // Cuts rumble that the extra gain would amplify
+const CLIP_LEVEL: f32 = 0.99; // |sample| at or above this counts as clipped
+const CLIP_WINDOW_SAMPLES: usize = SAMPLE_RATE; // Evaluate the clip ratio once per ~1s of audio
+const DEFAULT_CLIP_WARN_RATIO: f32 = 0.001; // 0.1% clipped samples is already audible distortion
+const DEFAULT_MAX_SNIPPET_CHARS: usize = 10_000; // ~2 pages; anything bigger is almost certainly a mistake
+const SNIPPET_CONFIRM_CHARS: usize = 2_000; // Expansions above this need explicit confirmation before typing
+const DEFAULT_MIN_WORDS_FOR_AI: usize = 2;
+const DEFAULT_MAX_REPEATS: usize = 3;
+const MAX_LOOP_PHRASE_WORDS: usize = 4; // Longest phrase checked for repetition loops
+const DEFAULT_MAX_AI_INPUT_CHARS: usize = 12_000; // ~3k tokens, comfortably inside llama3's context
+const DEFAULT_OLLAMA_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_OLLAMA_RETRIES: usize = 2;
+const OLLAMA_RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_millis(500); // Doubles per attempt
+const DEFAULT_SNIPPET_LIBRARY: &str = "default";
+const DEFAULT_SNIPPET_ESCAPE_PREFIX: &str = "literally"; // "literally insert bio" types the words
+const DEFAULT_MODEL_LOAD_RETRIES: usize = 2;
+const SINC_ZERO_CROSSINGS: usize = 16; // Kernel half-width, in zero crossings of the sinc
+const BEAM_SIZE: i32 = 5; // whisper.cpp's own default for beam search
+const MODEL_LOAD_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+const DEFAULT_FILLER_WORDS: &[&str] = &[
+    "um", "uh", "hmm", "uhh", "umm",
+    "basically", "actually", "sort of", "kind of",
+    "you know", "i mean",
+    "like",
+];
+const PASTE_SETTLE_MS: u64 = 150;
+const CAPTURE_BACKLOG_SAMPLES: usize = SAMPLE_RATE; // Audio held while the buffer is locked before it's dropped
+const CURSOR_MARKER: &str = "{cursor}";
+const DEFAULT_SNIPPET_DATE_FORMAT: &str = "%Y-%m-%d";
+const DEFAULT_SNIPPET_TIME_FORMAT: &str = "%H:%M";
+const DEFAULT_SNIPPET_FUZZY_DISTANCE: usize = 2;
+const SNIPPET_NEAR_MISS_DISTANCE: usize = 3; // explain_snippet_match reports closer triggers as near misses
 
 // Global State
 struct AppState {
@@ -41,23 +228,228 @@ lazy_static! {
     });
     static ref SNIPPETS: Mutex<Vec<VoiceSnippet>> = Mutex::new(Vec::new());
     static ref WHISPER_MODE: AtomicBool = AtomicBool::new(false);
+    static ref WHISPER_MODE_DSP: AtomicBool = AtomicBool::new(false); // Gain + high-pass on live audio
     static ref SEMANTIC_CORRECTION: AtomicBool = AtomicBool::new(false);
+    static ref AUTO_AI_CLEANUP: Mutex<Option<String>> = Mutex::new(None); // The command, when enabled
+    static ref AI_PROVIDER: Mutex<AiProvider> = Mutex::new(AiProvider::Ollama);
+    static ref AI_API_KEY: Mutex<Option<String>> = Mutex::new(None); // Never written to settings.json
+    static ref CUSTOM_AI_SYSTEM_PROMPT: Mutex<Option<String>> = Mutex::new(None); // None = AI_SYSTEM_PROMPT
+    static ref MAX_SNIPPET_CHARS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_SNIPPET_CHARS);
+    // Capture that arrived while a pass held the buffer lock, appended on the next callback
+    static ref CAPTURE_BACKLOG: Mutex<Vec<f32>> = Mutex::new(Vec::new());
+    static ref DROPPED_FRAMES: AtomicU64 = AtomicU64::new(0);
+    static ref XRUNS: AtomicU64 = AtomicU64::new(0);
+    static ref VAD_THRESHOLD_RMS: AtomicU32 = AtomicU32::new(DEFAULT_VAD_THRESHOLD_RMS.to_bits());
+    static ref TURN_GAP_MS: AtomicUsize = AtomicUsize::new(DEFAULT_TURN_GAP_MS as usize);
+    static ref LIVE_PROCESSING: AtomicBool = AtomicBool::new(true);
+    static ref DEFERRED_AUDIO: Mutex<Option<Vec<f32>>> = Mutex::new(None);
+    static ref MIN_INTERIM_WORDS: AtomicUsize = AtomicUsize::new(0);
+    // Finals confirmed so far this session; the UI may edit it mid-dictation
+    static ref COMMITTED_TEXT: Mutex<String> = Mutex::new(String::new());
+    static ref MAX_BUFFER_SAMPLES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_BUFFER_SAMPLES);
+    static ref STRIP_NONSPEECH_TAGS: AtomicBool = AtomicBool::new(true);
+    static ref STRIP_TRAILING_PUNCT: AtomicBool = AtomicBool::new(false);
+    static ref DEDUP_REPEATS: AtomicBool = AtomicBool::new(false); // Opt-in: it rewrites transcripts
+    static ref MAX_REPEATS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_REPEATS);
+    // whisper.cpp defaults: suppress_blank on, suppress_non_speech_tokens off
+    static ref SUPPRESS_BLANK: AtomicBool = AtomicBool::new(true);
+    static ref SUPPRESS_NON_SPEECH: AtomicBool = AtomicBool::new(false);
+    static ref BATCH_RECORDING: AtomicBool = AtomicBool::new(false);
+    static ref RECORDING_LIMIT_REPORTED: AtomicBool = AtomicBool::new(false); // Once per take
+    static ref SESSION_STARTED: Mutex<Option<std::time::Instant>> = Mutex::new(None);
+    static ref LISTENING_STARTED: Mutex<Option<std::time::Instant>> = Mutex::new(None);
+    static ref LISTENING_PAUSED: AtomicBool = AtomicBool::new(false); // Stream stays open, capture is gated
+    static ref MIN_SESSION_MS: AtomicUsize = AtomicUsize::new(DEFAULT_MIN_SESSION_MS as usize);
+    static ref THREAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static ref INTERIM_STRATEGY: Mutex<DecodeStrategy> = Mutex::new(DecodeStrategy::Greedy);
+    static ref FINAL_STRATEGY: Mutex<DecodeStrategy> = Mutex::new(DecodeStrategy::Beam);
+    static ref MODEL_LOAD_RETRIES: AtomicUsize = AtomicUsize::new(DEFAULT_MODEL_LOAD_RETRIES);
+    static ref MAX_SEGMENT_CHARS: AtomicUsize = AtomicUsize::new(0);
+    static ref SPLIT_ON_WORD: AtomicBool = AtomicBool::new(false);
+    static ref LANGUAGE: Mutex<Option<&'static str>> = Mutex::new(Some(DEFAULT_LANGUAGE));
+    static ref TRANSLATE_MODE: AtomicBool = AtomicBool::new(false);
+    // Boosted words and weight, plus the initial prompt built from them
+    static ref KEYWORD_BOOST: Mutex<(Vec<String>, f32, Option<String>)> = Mutex::new((Vec::new(), 0.0, None));
+    static ref RECOMMENDED_THREADS: Mutex<Option<u32>> = Mutex::new(None);
+    static ref LAST_SPEAKING_RATE: Mutex<Option<f32>> = Mutex::new(None);
+    static ref AUTO_CODE_PROMPT: AtomicBool = AtomicBool::new(false);
+    static ref SNIPPET_EXPANSION: AtomicBool = AtomicBool::new(true);
+    static ref SNIPPET_ESCAPE_PREFIX: Mutex<String> = Mutex::new(DEFAULT_SNIPPET_ESCAPE_PREFIX.to_string());
+    static ref INJECTION_MODE: Mutex<InjectionMode> = Mutex::new(InjectionMode::Keystroke);
+    static ref SNIPPET_RESOLUTION: Mutex<SnippetResolution> = Mutex::new(SnippetResolution::Longest);
+    static ref SNIPPET_REGEX_CACHE: Mutex<std::collections::HashMap<String, Option<Regex>>> =
+        Mutex::new(std::collections::HashMap::new());
+    static ref SNIPPET_FUZZY: AtomicBool = AtomicBool::new(false);
+    static ref SNIPPET_FUZZY_DISTANCE: AtomicUsize = AtomicUsize::new(DEFAULT_SNIPPET_FUZZY_DISTANCE);
+    static ref SNIPPET_PREFIX: Mutex<Option<String>> = Mutex::new(None);
+    static ref TEXT_TRANSFORMS: Mutex<Vec<(TextTransform, Regex)>> = Mutex::new(Vec::new());
+    static ref OUTPUT_IPA: AtomicBool = AtomicBool::new(false);
+    static ref FLATTEN_NEWLINES: AtomicBool = AtomicBool::new(false);
+    static ref INFERENCE_DUTY: Mutex<DutyCycle> = Mutex::new(DutyCycle::default());
+    static ref TRANSCRIPTION_METRICS: Mutex<TranscriptionMetrics> = Mutex::new(TranscriptionMetrics::default());
+    static ref METRICS_DIRTY: AtomicBool = AtomicBool::new(false);
+    static ref PERSIST_METRICS: AtomicBool = AtomicBool::new(false);
+    static ref MAX_AI_INPUT_CHARS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_AI_INPUT_CHARS);
+    static ref OLLAMA_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_OLLAMA_TIMEOUT_MS);
+    static ref OLLAMA_RETRIES: AtomicUsize = AtomicUsize::new(DEFAULT_OLLAMA_RETRIES);
+    static ref AI_CHUNK_CHARS: AtomicUsize = AtomicUsize::new(0);
+    static ref MIN_WORDS_FOR_AI: AtomicUsize = AtomicUsize::new(DEFAULT_MIN_WORDS_FOR_AI);
+    static ref SESSION_RECORDING: Mutex<Option<SessionFixture>> = Mutex::new(None);
+    static ref SNIPPET_COOLDOWN_MS: AtomicUsize = AtomicUsize::new(0);
+    static ref SNIPPET_MIN_CONFIDENCE: AtomicU32 = AtomicU32::new(0f32.to_bits());
+    static ref PLACEHOLDER: Regex = Regex::new(r"\{([a-z]+)\}").unwrap();
+    static ref SNIPPET_DATE_FORMATS: Mutex<(String, String)> =
+        Mutex::new((DEFAULT_SNIPPET_DATE_FORMAT.to_string(), DEFAULT_SNIPPET_TIME_FORMAT.to_string()));
+    static ref SNIPPET_NORMALIZE: AtomicBool = AtomicBool::new(false);
+    static ref SNIPPET_END_ONLY: AtomicBool = AtomicBool::new(false);
+    static ref SNIPPET_RNG: Mutex<u64> = Mutex::new(
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+    );
+    // Lowercased trigger → when it last expanded, for the cooldown
+    static ref SNIPPET_LAST_FIRED: Mutex<std::collections::HashMap<String, std::time::Instant>> =
+        Mutex::new(std::collections::HashMap::new());
+    // Lowercased trigger → the variant this utterance's interims showed, for its final
+    static ref SNIPPET_UTTERANCE_VARIANTS: Mutex<std::collections::HashMap<String, usize>> =
+        Mutex::new(std::collections::HashMap::new());
+    // Lowercased, single-spaced fillers; None means DEFAULT_FILLER_WORDS
+    static ref FILLER_WORDS: Mutex<Option<Vec<String>>> = Mutex::new(None);
+    static ref PROFANITY_FILTER: AtomicBool = AtomicBool::new(false);
+    static ref SPOKEN_PUNCTUATION_ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref AUTOCAP_AFTER_PUNCT: AtomicBool = AtomicBool::new(true);
+    // Lowercased word list and its compiled whole-word matcher
+    static ref PROFANITY_LIST: Mutex<(Vec<String>, Option<Regex>)> =
+        Mutex::new(profanity_regex(DEFAULT_PROFANITY_LIST).expect("default profanity list compiles"));
+    static ref CLIP_WARN_RATIO: AtomicU32 = AtomicU32::new(DEFAULT_CLIP_WARN_RATIO.to_bits());
+    static ref CLIP_MONITOR: Mutex<ClipMonitor> = Mutex::new(ClipMonitor::default());
+    static ref EVENT_SINK: Mutex<Option<StreamSink<EngineEvent>>> = Mutex::new(None);
+    static ref DATA_DIR_OVERRIDE: Mutex<Option<PathBuf>> = Mutex::new(None);
+    static ref RESAMPLE_QUALITY: Mutex<Option<ResampleQuality>> = Mutex::new(None); // None = per-path default
+    static ref BUFFER_POS: Mutex<BufferPos> = Mutex::new(BufferPos::default()); // Lock after audio_buffer
+    static ref AUDIO_HOST: Mutex<Option<String>> = Mutex::new(None);
+    static ref INPUT_DEVICE: Mutex<Option<String>> = Mutex::new(None);
+    static ref LOADED_MODEL_PATH: Mutex<Option<String>> = Mutex::new(None);
+    // Where inference currently runs, and why it left the GPU if it did
+    static ref ACCELERATION: Mutex<(Acceleration, Option<String>)> = Mutex::new((Acceleration::for_build(), None));
+    static ref GPU_FALLBACK: AtomicBool = AtomicBool::new(true);
+    static ref DATA_DIR_READ_ONLY: AtomicBool = AtomicBool::new(false);
+    // Snippet edits are written behind; this marks an unsaved change
+    static ref SNIPPETS_DIRTY: AtomicBool = AtomicBool::new(false);
+    static ref COMMAND_HISTORY: Mutex<Vec<CommandHistoryEntry>> = Mutex::new(Vec::new());
+    static ref HISTORY_DIRTY: AtomicBool = AtomicBool::new(false);
+    // Settings as last read from or written to settings.json; None until then
+    static ref PERSISTED_SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+    // Name of the active snippet library; "default" is the legacy snippets.json
+    static ref ACTIVE_SNIPPET_LIBRARY: Mutex<String> = Mutex::new(DEFAULT_SNIPPET_LIBRARY.to_string());
+    // Bumped by shutdown(); long-running work captures it and bails when it changes
+    static ref SHUTDOWN_GEN: AtomicUsize = AtomicUsize::new(0);
 }
 
-pub fn set_semantic_correction(enabled: bool) -> Result<()> {
+pub fn set_semantic_correction(enabled: bool) -> Result<(), Fair9Error> {
     SEMANTIC_CORRECTION.store(enabled, Ordering::SeqCst);
     Ok(())
 }
 
-pub fn set_whisper_mode(enabled: bool) -> Result<()> {
+pub fn set_whisper_mode(enabled: bool) -> Result<(), Fair9Error> {
     WHISPER_MODE.store(enabled, Ordering::SeqCst);
     Ok(())
 }
 
-fn get_model_path() -> Result<PathBuf> {
+/// Run whisper mode's gain and high-pass over audio before every transcription.
+/// Separate from `set_whisper_mode`, which only tunes decoding; try it out with
+/// `transcribe_samples_both` first, as it can hurt normal-volume speech.
+pub fn set_whisper_mode_dsp(enabled: bool) -> Result<(), Fair9Error> {
+    WHISPER_MODE_DSP.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Override the data directory (portable installs, tests). Empty string restores the default.
+pub fn set_data_dir(path: String) -> Result<(), Fair9Error> {
+    let mut guard = DATA_DIR_OVERRIDE.lock().unwrap();
+    *guard = if path.trim().is_empty() { None } else { Some(PathBuf::from(path)) };
+    // A different directory needs its own probe
+    DATA_DIR_READ_ONLY.store(false, Ordering::SeqCst);
+    // ...and its settings.json hasn't been read, so flushing mustn't overwrite it
+    *PERSISTED_SETTINGS.lock().unwrap() = None;
+    Ok(())
+}
+
+/// Whether settings, snippets and history can be saved this session
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageStatus {
+    Writable,
+    /// The data dir can't be written (locked-down machine); everything keeps
+    /// working in memory but nothing persists past this session
+    DataDirReadOnly,
+}
+
+/// Engine health at a glance, for the UI's status bar
+#[derive(Clone, Debug)]
+pub struct AppStatus {
+    pub storage: StorageStatus,
+    pub data_dir: String,
+    pub model_loaded: bool,
+    pub is_listening: bool,
+    /// Listening, but capture is paused with `pause_listening`
+    pub is_paused: bool,
+}
+
+/// Probe the data dir at startup. If it can't be written, switch to in-memory-only
+/// mode instead of failing every save later with an opaque error.
+pub fn init_storage() -> Result<StorageStatus, Fair9Error> {
+    let dir = get_data_dir()?;
+    let probe = dir.join(".write_probe");
+    let writable = fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(&probe, b"ok"))
+        .and_then(|_| fs::remove_file(&probe));
+    if let Err(e) = &writable {
+        eprintln!("data dir {:?} is not writable, changes won't be saved: {}", dir, e);
+    }
+    DATA_DIR_READ_ONLY.store(writable.is_err(), Ordering::SeqCst);
+    Ok(storage_status())
+}
+
+fn storage_status() -> StorageStatus {
+    if DATA_DIR_READ_ONLY.load(Ordering::SeqCst) {
+        StorageStatus::DataDirReadOnly
+    } else {
+        StorageStatus::Writable
+    }
+}
+
+pub fn get_status() -> AppStatus {
+    AppStatus {
+        storage: storage_status(),
+        data_dir: get_data_dir().map(|d| d.to_string_lossy().to_string()).unwrap_or_default(),
+        // Held means a transcription is running, which needs a model
+        model_loaded: STATE.model_ctx.try_lock().map_or(true, |ctx| ctx.is_some()),
+        is_listening: STATE.is_listening.load(Ordering::SeqCst),
+        is_paused: LISTENING_PAUSED.load(Ordering::SeqCst),
+    }
+}
+
+/// Write a file under the data dir, or silently keep it in memory when the dir is read-only
+fn write_data_file(path: &std::path::Path, data: String, what: &str) -> Result<()> {
+    if DATA_DIR_READ_ONLY.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("Failed to create data directory")?;
+    }
+    fs::write(path, data).with_context(|| format!("Failed to write {}", what))
+}
+
+fn get_data_dir() -> Result<PathBuf> {
+    if let Some(dir) = DATA_DIR_OVERRIDE.lock().unwrap().clone() {
+        return Ok(dir);
+    }
     let mut path = dirs::data_dir().ok_or_else(|| anyhow!("Could not find data directory"))?;
     path.push("OpenFL");
     path.push("Fair9");
+    Ok(path)
+}
+
+fn get_model_path() -> Result<PathBuf> {
+    let mut path = get_data_dir()?;
     path.push("models");
     // Check if models are directly in models/ or in whisper-cpp subdirectory
     // We'll check the direct path first for simplicity based on Flutter code
@@ -72,433 +464,7129 @@ fn get_model_path() -> Result<PathBuf> {
     Ok(path)
 }
 
-pub fn init_model() -> Result<String> {
+const MODEL_FAMILIES: &[&str] = &["tiny", "base", "small", "medium", "large"];
+
+/// Installed ggml models as a JSON array of `{"name", "path", "size_bytes", "family"}`,
+/// for the model picker. Missing model directories just mean nothing is installed yet.
+pub fn list_available_models() -> Result<String, Fair9Error> {
+    let models_dir = get_data_dir()?.join("models");
+    let mut models = Vec::new();
+    for dir in [models_dir.join("whisper-cpp"), models_dir] {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_bin = path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("bin"));
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            if !is_bin || !meta.is_file() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            models.push(json!({
+                "family": model_family(&name),
+                "name": name,
+                "path": path.to_string_lossy(),
+                "size_bytes": meta.len(),
+            }));
+        }
+    }
+    models.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Ok(serde_json::to_string(&models)?)
+}
+
+/// "ggml-small.en-q8_0.bin" → "small"; None for names that don't say
+fn model_family(filename: &str) -> Option<&'static str> {
+    let lower = filename.to_lowercase();
+    lower.split(|c: char| !c.is_ascii_alphanumeric())
+        .find_map(|part| MODEL_FAMILIES.iter().find(|f| part == **f))
+        .copied()
+}
+
+/// Load the bundled default model from the data dir; see `init_model_from_path`
+pub fn init_model() -> Result<String, Fair9Error> {
     let model_path = get_model_path()?;
+    init_model_from_path(model_path.to_string_lossy().to_string())
+}
+
+/// Load a model from an arbitrary absolute path instead of the data dir,
+/// e.g. a larger or multilingual `.bin` chosen with a file picker
+pub fn init_model_from_path(path: String) -> Result<String, Fair9Error> {
+    let model_path = PathBuf::from(&path);
+    if !model_path.is_absolute() {
+        return Err(Fair9Error::InvalidArgument(format!("Model path must be absolute: {:?}", model_path)));
+    }
     if !model_path.exists() {
-        return Err(anyhow!("Model not found at {:?}", model_path));
+        return Err(Fair9Error::ModelNotFound(model_path.display().to_string()));
     }
 
-    let ctx = WhisperContext::new(model_path.to_str().unwrap()).context("failed to load model")?;
+    let retries = MODEL_LOAD_RETRIES.load(Ordering::SeqCst) as u32;
+    let acceleration = Acceleration::for_build();
+    let ctx = load_with_retry(retries, MODEL_LOAD_RETRY_DELAY, || {
+        validate_model_file(&model_path)?;
+        load_context(&path, acceleration == Acceleration::Gpu)
+    })?;
     let mut guard = STATE.model_ctx.lock().unwrap();
     *guard = Some(ctx);
+    *LOADED_MODEL_PATH.lock().unwrap() = Some(path);
+    // A fresh load gets another try on the GPU
+    *ACCELERATION.lock().unwrap() = (acceleration, None);
     
     Ok(format!("Model loaded from {:?}", model_path))
 }
 
+fn load_context(path: &str, use_gpu: bool) -> Result<WhisperContext> {
+    let mut params = WhisperContextParameters::default();
+    params.use_gpu(use_gpu);
+    WhisperContext::new_with_params(path, params)
+        .with_context(|| format!("Model file {:?} is corrupt or not a ggml model", path))
+}
+
+/// How many more times model loading is attempted after a transient failure
+/// (antivirus holding the file, a download still finishing). 0 disables retries.
+pub fn set_model_load_retries(n: u32) -> Result<(), Fair9Error> {
+    MODEL_LOAD_RETRIES.store(n as usize, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Run `load`, retrying transient failures up to `retries` times with `delay` in between.
+/// A file that isn't a Whisper model fails straight away; waiting won't fix it.
+fn load_with_retry<T>(retries: u32, delay: std::time::Duration, mut load: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match load() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < retries && is_transient_load_error(&e) => {
+                attempt += 1;
+                eprintln!("model load failed (attempt {}), retrying: {:#}", attempt, e);
+                thread::sleep(delay);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Only a file that's locked (antivirus scanning it) or still being written is worth
+/// waiting for. Any other IO error, a bad header or a whisper.cpp init failure is
+/// permanent and reported straight away.
+fn is_transient_load_error(e: &anyhow::Error) -> bool {
+    e.chain().filter_map(|cause| cause.downcast_ref::<std::io::Error>()).any(|io| {
+        // ERROR_SHARING_VIOLATION / ERROR_LOCK_VIOLATION: another process has the file open
+        let locked = cfg!(windows) && matches!(io.raw_os_error(), Some(32) | Some(33));
+        locked || matches!(io.kind(), std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::UnexpectedEof)
+    })
+}
+
+/// Check the ggml magic so a truncated download or a stray file fails with a clear
+/// message instead of deep inside whisper.cpp
+fn validate_model_file(path: &std::path::Path) -> Result<()> {
+    use std::io::Read;
+    let mut magic = [0u8; 4];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .with_context(|| format!("Failed to read model header from {:?}", path))?;
+    if u32::from_le_bytes(magic) != GGML_MAGIC {
+        return Err(anyhow!("{:?} is not a ggml Whisper model", path));
+    }
+    Ok(())
+}
+
 pub fn calculate_rms(data: Vec<f32>) -> f32 {
     if data.is_empty() { return 0.0; }
     let sum_squares: f32 = data.iter().map(|&x| x * x).sum();
     (sum_squares / data.len() as f32).sqrt()
 }
 
+/// Min/max sample of one waveform bucket, for drawing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WaveformPeak {
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Raw copy of the captured audio (16kHz mono) for analysis/debugging
+pub fn get_audio_buffer_copy() -> Vec<f32> {
+    STATE.audio_buffer.lock().unwrap().clone()
+}
+
+/// Downsampled view of the current buffer: `points` min/max pairs.
+/// Buffers shorter than `points` yield one peak per sample.
+pub fn get_waveform(points: usize) -> Vec<WaveformPeak> {
+    let buffer = STATE.audio_buffer.lock().unwrap();
+    compute_waveform(&buffer, points)
+}
+
+fn compute_waveform(samples: &[f32], points: usize) -> Vec<WaveformPeak> {
+    let points = points.min(samples.len());
+    (0..points)
+        .map(|i| {
+            let start = i * samples.len() / points;
+            let end = (i + 1) * samples.len() / points;
+            let bucket = &samples[start..end];
+            WaveformPeak {
+                min: bucket.iter().cloned().fold(f32::INFINITY, f32::min),
+                max: bucket.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+            }
+        })
+        .collect()
+}
+
 use enigo::{Enigo, Key, KeyboardControllable};
 
 /// Inject text with adaptive delay between characters
 /// delay_ms: 10 for normal apps, 30 for legacy/slow apps
-pub fn inject_text(text: String, delay_ms: u64) -> Result<()> {
+pub fn inject_text(text: String, delay_ms: u64) -> Result<(), Fair9Error> {
+    injection_backend()?;
     let mut enigo = Enigo::new();
+    let gen = SHUTDOWN_GEN.load(Ordering::SeqCst);
     
-    for ch in text.chars() {
-        enigo.key_sequence(&ch.to_string());
+    // One keystroke per grapheme so "é" or a flag emoji isn't split mid-cluster
+    for grapheme in text.graphemes(true) {
+        if SHUTDOWN_GEN.load(Ordering::SeqCst) != gen {
+            return Err(Fair9Error::Cancelled("Injection".to_string()));
+        }
+        match grapheme {
+            "\n" | "\r\n" | "\r" => enigo.key_click(Key::Return),
+            "\t" => enigo.key_click(Key::Tab),
+            g => enigo.key_sequence(g),
+        }
         thread::sleep(std::time::Duration::from_millis(delay_ms));
     }
     Ok(())
 }
 
-/// AI Polish: Remove filler words from transcribed text
-pub fn clean_filler_words(text: String) -> String {
-    let fillers = [
-        " um ", " uh ", " hmm ", " uhh ", " umm ",
-        " basically ", " actually ", " sort of ", " kind of ",
-        " you know ", " I mean ",
-        " like ",
-    ];
+/// How text reaches the focused app
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum InjectionMode {
+    Keystroke, // Typed one grapheme at a time; works everywhere, slow for long text
+    Paste,     // Clipboard + paste shortcut; near-instant
+}
+
+impl InjectionMode {
+    fn parse(mode: &str) -> Result<Self> {
+        match mode.trim().to_lowercase().as_str() {
+            "keystroke" => Ok(InjectionMode::Keystroke),
+            "paste" => Ok(InjectionMode::Paste),
+            other => Err(anyhow!("Unknown injection mode '{}' (expected keystroke or paste)", other)),
+        }
+    }
+}
+
+/// How `inject` delivers text: "keystroke" (default) or "paste"
+pub fn set_injection_mode(mode: String) -> Result<(), Fair9Error> {
+    *INJECTION_MODE.lock().unwrap() = InjectionMode::parse(&mode)?;
+    Ok(())
+}
 
-    let mut result = format!(" {} ", text); 
+/// Deliver text using the selected injection mode. `delay_ms` only applies to keystrokes.
+pub fn inject(text: String, delay_ms: u64) -> Result<(), Fair9Error> {
+    let mode = *INJECTION_MODE.lock().unwrap();
+    match mode {
+        InjectionMode::Keystroke => inject_text(text, delay_ms),
+        InjectionMode::Paste => inject_via_clipboard(text),
+    }
+}
 
-    for filler in &fillers {
-        while result.contains(filler) {
-            result = result.replace(filler, " ");
+/// Deliver text as `inject` does, then walk the caret back to `cursor_offset`
+/// (a byte offset into `text`, e.g. a result's `cursor_offset`) with left-arrow presses
+pub fn inject_with_cursor(text: String, cursor_offset: Option<usize>, delay_ms: u64) -> Result<(), Fair9Error> {
+    let steps = match cursor_offset {
+        Some(offset) => caret_steps_back(&text, offset)?,
+        None => 0,
+    };
+    inject(text, delay_ms)?;
+    if steps > 0 {
+        let mut enigo = Enigo::new();
+        for _ in 0..steps {
+            enigo.key_click(Key::LeftArrow);
+            thread::sleep(std::time::Duration::from_millis(delay_ms));
         }
     }
+    Ok(())
+}
 
-    result.split_whitespace().collect::<Vec<_>>().join(" ")
+/// Left-arrow presses from the end of `text` back to byte `offset`. Editors move one
+/// grapheme per press, and a CRLF line break is a single step.
+fn caret_steps_back(text: &str, offset: usize) -> Result<usize> {
+    let tail = text.get(offset..).ok_or_else(|| anyhow!("Cursor offset {} is not a character boundary in the text", offset))?;
+    Ok(tail.graphemes(true).count())
 }
 
-// ── New AI Features (Restored) ──────────────────────────────────────
+/// Paste `text` through the clipboard, then put back what was there before, even
+/// if the paste fails. Only text contents can be restored; an image on the
+/// clipboard is lost.
+pub fn inject_via_clipboard(text: String) -> Result<(), Fair9Error> {
+    let mut clipboard = arboard::Clipboard::new().context("Failed to open clipboard")?;
+    let previous = clipboard.get_text().ok();
+    Ok(paste_preserving(
+        &text,
+        previous,
+        |t| clipboard.set_text(t.to_string()).context("Failed to set clipboard"),
+        press_paste_shortcut,
+    )?)
+}
 
-const AI_SYSTEM_PROMPT: &str = "You are a text editor. Execute the user's command on the following text. Return ONLY the modified text with no explanation, no markdown formatting, no quotes around it. Just the raw edited text, nothing else.";
+fn paste_preserving(
+    text: &str,
+    previous: Option<String>,
+    mut set_clipboard: impl FnMut(&str) -> Result<()>,
+    paste: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    set_clipboard(text)?;
+    let pasted = paste();
+    // The target app reads the clipboard asynchronously; don't swap it out from under the paste
+    thread::sleep(std::time::Duration::from_millis(PASTE_SETTLE_MS));
+    let restored = match previous {
+        Some(previous) => set_clipboard(&previous),
+        None => Ok(()),
+    };
+    pasted.and(restored)
+}
 
-#[derive(Serialize)]
-struct OllamaRequest {
-    model: String,
-    prompt: String,
-    system: String,
-    stream: bool,
+/// Which OS facility synthesizes our keystrokes (via enigo), or why none can
+pub fn get_injection_backend() -> Result<String, Fair9Error> {
+    Ok(injection_backend().map(str::to_string)?)
 }
 
-#[derive(Deserialize)]
-struct OllamaResponse {
-    response: String,
+#[cfg(target_os = "windows")]
+fn injection_backend() -> Result<&'static str> {
+    Ok("SendInput")
 }
 
-pub fn apply_semantic_correction(text: String) -> String {
-    if !SEMANTIC_CORRECTION.load(Ordering::SeqCst) {
-        return text;
-    }
+#[cfg(target_os = "macos")]
+fn injection_backend() -> Result<&'static str> {
+    // Posting CGEvents needs the Accessibility permission; without it they're silently dropped
+    Ok("CGEvent")
+}
 
-    // Skip short texts to avoid latency on simple commands
-    if text.split_whitespace().count() < 4 {
-        return text;
+#[cfg(target_os = "linux")]
+fn injection_backend() -> Result<&'static str> {
+    let display = std::env::var("DISPLAY").ok();
+    let wayland = std::env::var("WAYLAND_DISPLAY").ok();
+    linux_injection_backend(display.as_deref(), wayland.as_deref())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+fn injection_backend() -> Result<&'static str> {
+    Err(anyhow!("Text injection is not supported on this platform"))
+}
+
+/// X11 events via XTest. Wayland has no way for one client to type into another,
+/// but under XWayland (DISPLAY set too) the X11 path still reaches X11 apps.
+#[cfg(any(target_os = "linux", test))]
+fn linux_injection_backend(display: Option<&str>, wayland: Option<&str>) -> Result<&'static str> {
+    let set = |v: Option<&str>| v.map_or(false, |v| !v.is_empty());
+    if set(wayland) && !set(display) {
+        return Err(anyhow!("Text injection is not supported on Wayland; use an X11 session or copy the text instead"));
     }
+    Ok("XTest")
+}
 
-    let prompt = format!("Fix grammatical errors and remove hesitations (like 'no wait', 'I meant') from this text. Output ONLY the fixed text: \"{}\"", text);
-    
-    // Call Ollama (assuming lamma3 or similar is default)
-    // We use a short timeout because this is real-time-ish
-    let result = ureq::post("http://localhost:11434/api/generate")
-        .timeout(std::time::Duration::from_millis(1500)) 
-        .send_json(json!({
-            "model": "llama3",
-            "prompt": prompt,
-            "stream": false
-        }));
+fn press_paste_shortcut() -> Result<()> {
+    injection_backend()?;
+    let modifier = if cfg!(target_os = "macos") { Key::Meta } else { Key::Control };
+    let mut enigo = Enigo::new();
+    enigo.key_down(modifier);
+    enigo.key_click(Key::Layout('v'));
+    enigo.key_up(modifier);
+    Ok(())
+}
 
-    match result {
-        Ok(res) => {
-            if let Ok(json) = res.into_json::<OllamaResponse>() {
-                if !json.response.trim().is_empty() {
-                    return json.response.trim().to_string();
-                }
+/// How long `inject_text` will take for `text` at `delay_ms`: one step per grapheme,
+/// line breaks and tabs included. Lets the UI offer a paste instead of a long wait.
+pub fn estimate_injection_ms(text: String, delay_ms: u64) -> u64 {
+    text.graphemes(true).count() as u64 * delay_ms
+}
+
+/// Replace the filler list `clean_filler_words` removes. Entries may span several
+/// words ("you know"); an empty list turns filler removal off.
+pub fn set_filler_words(words: Vec<String>) -> Result<(), Fair9Error> {
+    let mut words: Vec<String> = words.iter()
+        .map(|w| w.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase())
+        .filter(|w| !w.is_empty())
+        .collect();
+    words.dedup();
+    *FILLER_WORDS.lock().unwrap() = Some(words);
+    Ok(())
+}
+
+/// Go back to the built-in filler list
+pub fn reset_filler_words() -> Result<(), Fair9Error> {
+    *FILLER_WORDS.lock().unwrap() = None;
+    Ok(())
+}
+
+/// AI Polish: Remove filler words from transcribed text
+pub fn clean_filler_words(text: &str) -> String {
+    let custom = FILLER_WORDS.lock().unwrap().clone();
+    match &custom {
+        Some(words) => remove_fillers(text, &words.iter().map(String::as_str).collect::<Vec<_>>()),
+        None => remove_fillers(text, DEFAULT_FILLER_WORDS),
+    }
+}
+
+/// Drop each of `fillers` (lowercase, single-spaced) from `text`, tidying the
+/// punctuation around it
+fn remove_fillers(text: &str, fillers: &[&str]) -> String {
+    let mut fillers = fillers.to_vec();
+    // Longest first, so "you know" wins over a bare "you"
+    fillers.sort_by_key(|f| std::cmp::Reverse(f.split(' ').count()));
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut out: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        let Some(len) = filler_len_at(&words[i..], &fillers) else {
+            out.push(words[i].to_string());
+            i += 1;
+            continue;
+        };
+        let last = words[i + len - 1];
+        let trailing = &last[last.trim_end_matches(|c: char| c.is_ascii_punctuation()).len()..];
+        if let Some(prev) = out.last_mut() {
+            // "went, um, to" → "went to"; "there uh." → "there."
+            if trailing.starts_with(',') {
+                prev.truncate(prev.trim_end_matches(',').len());
             }
+            prev.push_str(trailing.trim_start_matches(','));
         }
-        Err(_) => {
-            // Silently fail back to original text if AI is down/slow
-        }
+        i += len;
     }
-    
-    text
+    out.join(" ")
 }
 
-pub fn process_ai_command_with_config(
-    voice_command: String,
-    selected_text: String,
-    ollama_url: String,
-    model: String,
-) -> Result<String> {
-    if voice_command.trim().is_empty() {
-        return Err(anyhow!("No voice command provided"));
+/// Number of words the filler starting at `words[0]` spans, if it is one.
+/// Matches whole words, ignoring case and surrounding punctuation.
+fn filler_len_at(words: &[&str], fillers: &[&str]) -> Option<usize> {
+    fillers.iter().find_map(|filler| {
+        let parts: Vec<&str> = filler.split(' ').collect();
+        let candidate = words.get(..parts.len())?;
+        let matches = candidate.iter().zip(&parts).all(|(word, part)| {
+            word.trim_matches(|c: char| c.is_ascii_punctuation()).eq_ignore_ascii_case(part)
+        });
+        matches.then_some(parts.len())
+    })
+}
+
+/// Collapse Whisper's repetition loops ("the the the the …") to a single occurrence.
+/// Off by default.
+pub fn set_dedup_repeats(enabled: bool) -> Result<(), Fair9Error> {
+    DEDUP_REPEATS.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Immediate repeats of a word or short phrase allowed before it counts as a loop.
+/// The default of 3 keeps "very very good" and "no, no, no" as spoken.
+pub fn set_max_repeats(n: usize) -> Result<(), Fair9Error> {
+    if n == 0 {
+        return Err(Fair9Error::InvalidArgument("Max repeats must be at least 1".to_string()));
     }
-    if selected_text.trim().is_empty() {
-        return Err(anyhow!("No text selected"));
+    MAX_REPEATS.store(n, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Replace every run of more than `max_repeats` back-to-back copies of a phrase (up to
+/// MAX_LOOP_PHRASE_WORDS words, compared ignoring case and punctuation) with one copy.
+/// The last copy is kept so the sentence keeps its closing punctuation.
+fn collapse_repeats(text: &str, max_repeats: usize) -> String {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let keys: Vec<String> = words.iter()
+        .map(|w| w.trim_matches(|c: char| c.is_ascii_punctuation()).to_lowercase())
+        .collect();
+    let mut out: Vec<&str> = Vec::new();
+    let mut i = 0;
+    'outer: while i < words.len() {
+        for n in 1..=MAX_LOOP_PHRASE_WORDS {
+            let phrase = &keys[i..(i + n).min(keys.len())];
+            if phrase.len() < n {
+                break;
+            }
+            let mut copies = 1;
+            while keys.get(i + copies * n..i + (copies + 1) * n) == Some(phrase) {
+                copies += 1;
+            }
+            if copies > max_repeats {
+                let last = i + (copies - 1) * n;
+                out.extend_from_slice(&words[last..last + n]);
+                i += copies * n;
+                continue 'outer;
+            }
+        }
+        out.push(words[i]);
+        i += 1;
     }
+    out.join(" ")
+}
 
-    let prompt = format!("Command: {}\n\nText to edit:\n{}", voice_command, selected_text);
+// Sound cues Whisper puts in parentheses, e.g. "(wind blowing)", "(upbeat music)"
+const NONSPEECH_CUES: &[&str] = &[
+    "music", "laugh", "applause", "cough", "wind", "noise", "silence", "blowing",
+    "breath", "sigh", "inaudible", "static", "beep", "clap", "sniff", "footsteps",
+    "speaking in foreign language", "no audio", "blank",
+];
 
-    let res = ureq::post(&format!("{}/api/generate", ollama_url))
-        .timeout(std::time::Duration::from_secs(10))
-        .send_json(json!({
-            "model": model,
-            "prompt": prompt,
-            "system": AI_SYSTEM_PROMPT,
-            "stream": false
-        }))
-        .context("Failed to connect to Ollama")?;
+pub fn set_strip_nonspeech_tags(enabled: bool) -> Result<(), Fair9Error> {
+    STRIP_NONSPEECH_TAGS.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
 
-    let json: OllamaResponse = res.into_json().context("Failed to parse Ollama response")?;
-    
-    Ok(json.response.trim().to_string())
+fn is_nonspeech_annotation(inner: &str) -> bool {
+    let inner = inner.trim();
+    let has_letters = inner.chars().any(|c| c.is_alphabetic());
+    // "(MUSIC)", "(APPLAUSE)"
+    if has_letters && inner.chars().filter(|c| c.is_alphabetic()).all(|c| c.is_uppercase()) {
+        return true;
+    }
+    let lower = inner.to_lowercase();
+    let words: Vec<&str> = lower.split(|c: char| !c.is_alphabetic()).filter(|w| !w.is_empty()).collect();
+    NONSPEECH_CUES.iter().any(|cue| {
+        if cue.contains(' ') {
+            return lower.contains(cue);
+        }
+        // Whole-word match allowing simple inflections ("laughs", "coughing"), not "window"
+        words.iter().any(|w| {
+            w.strip_prefix(cue)
+                .map_or(false, |suffix| matches!(suffix, "" | "s" | "es" | "ed" | "ing" | "y"))
+        })
+    })
 }
 
-// ── Transcription Stream ─────────────────────────────────────────────
+/// Remove Whisper's non-speech annotations from raw model output.
+/// Square-bracket tags (`[BLANK_AUDIO]`, `[MUSIC]`) are always dropped; parentheses
+/// only when they look like a sound cue, so "(see appendix)" survives. Runs before
+/// any spoken punctuation is applied, so dictated parens are never seen here.
+fn strip_nonspeech_tags(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
 
-pub fn create_transcription_stream(sink: StreamSink<String>) -> Result<()> {
-    // Start listening thread
-    thread::spawn(move || {
-        let host = cpal::default_host();
-        let device = host.default_input_device().expect("No input device available");
-        let config = device.default_input_config().expect("Failed to get default input config");
-        
-        // We only support f32 for simplicity right now
-        let err_fn = move |err| {
-            eprintln!("an error occurred on stream: {}", err);
+    while let Some(start) = rest.find(|c| c == '[' || c == '(') {
+        let close = if rest[start..].starts_with('[') { ']' } else { ')' };
+        let Some(len) = rest[start + 1..].find(close) else {
+            break;
         };
+        let end = start + 1 + len;
+        out.push_str(&rest[..start]);
+        if close == ')' && !is_nonspeech_annotation(&rest[start + 1..end]) {
+            out.push_str(&rest[start..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
 
-        let stream = device.build_input_stream(
-            &config.into(),
-            move |data: &[f32], _: &_| {
-                if STATE.is_listening.load(Ordering::SeqCst) {
-                    let mut buffer = STATE.audio_buffer.lock().unwrap();
-                    buffer.extend_from_slice(data);
-                }
-            },
-            err_fn,
-            None // Timeout
-        ).expect("Failed to build input stream");
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
 
-        stream.play().expect("Failed to play stream");
+// ── Text Formatting ──────────────────────────────────────────────────
 
-        // Processing loop
-        loop {
-            thread::sleep(std::time::Duration::from_millis(500));
-            
-            if !STATE.is_listening.load(Ordering::SeqCst) {
-                // Clear buffer if not listening
-                let mut buffer = STATE.audio_buffer.lock().unwrap();
-                if !buffer.is_empty() {
-                    buffer.clear();
-                }
-                continue;
-            }
+/// Append a transcription to a growing document with spacing and casing that
+/// fit what came before: capitalized after a sentence end or paragraph break,
+/// lowercased (except "I", acronyms) when continuing mid-sentence.
+pub fn append_to_document(existing: String, new_text: String) -> String {
+    let new_text = new_text.trim();
+    if new_text.is_empty() {
+        return existing;
+    }
 
-            // Check buffer size (process every ~2 seconds of audio or on silence?)
-            // For real-time, we want frequent updates.
-            // Let's grab the buffer content
-            let samples = {
-                let mut buffer = STATE.audio_buffer.lock().unwrap();
-                if buffer.len() >= SAMPLE_RATE * 3 { // 3 seconds
-                    let chunk = buffer.clone();
-                    buffer.clear(); // overlap? for now simple clear
-                    chunk
-                } else {
-                    Vec::new() 
-                }
-            };
+    let tail = existing.trim_end_matches(|c: char| c == ' ' || c == '\t');
+    let sentence_start = tail.is_empty()
+        || tail.ends_with(|c| c == '.' || c == '?' || c == '!' || c == '\n');
+    let cased = if sentence_start {
+        capitalize_first(new_text)
+    } else {
+        decapitalize_first_word(new_text)
+    };
 
-            if !samples.is_empty() {
-                // Run Whisper
-                let guard = STATE.model_ctx.lock().unwrap();
-                if let Some(ctx) = guard.as_ref() {
-                    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
-                    params.set_print_special(false);
-                    params.set_print_progress(false);
-                    params.set_print_realtime(false);
-                    params.set_print_timestamps(false);
-                    
-                    // Whisper Mode hacks
-                    if WHISPER_MODE.load(Ordering::SeqCst) {
-                        params.set_no_speech_thold(0.1); // High sensitivity
-                        // params.set_temperature(0.0);
-                    }
+    // No separator at the very start, after a line break or opening bracket/quote,
+    // before leading punctuation, or when the document already ends in a space
+    let needs_space = !tail.is_empty()
+        && !existing.ends_with(|c: char| c.is_whitespace())
+        && !tail.ends_with(|c| c == '(' || c == '[' || c == '"' || c == '\n')
+        && !cased.starts_with(|c| matches!(c, ',' | '.' | '?' | '!' | ';' | ':' | ')'));
 
-                    // Run state
-                    let mut state = ctx.create_state().expect("failed to create state");
-                    state.full(params, &samples).expect("failed to run model");
-
-                    // Fetch results
-                    let num_segments = state.full_n_segments().expect("failed to get segments");
-                    let mut text = String::new();
-                    for i in 0..num_segments {
-                        if let Ok(segment) = state.full_get_segment_text(i) {
-                            text.push_str(&segment);
-                            text.push(' ');
-                        }
-                    }
+    let mut out = existing;
+    if needs_space {
+        out.push(' ');
+    }
+    out.push_str(&cased);
+    out
+}
 
-                    let clean_text = clean_filler_words(text.trim().to_string());
-                    let final_text = apply_semantic_correction(clean_text); // Semantic
+fn capitalize_first(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
 
-                    if !final_text.is_empty() {
-                        sink.add(final_text);
-                    }
-                }
-            }
+/// Wrap a transcription for pasting into a markdown editor.
+/// Styles: "blockquote", "codeblock", "bullet" (one item per line) and "plain".
+pub fn format_as(text: String, style: String) -> Result<String, Fair9Error> {
+    let text = text.trim_end();
+    let lines = text.lines().map(str::trim_end);
+    match style.trim().to_lowercase().as_str() {
+        "plain" => Ok(text.trim().to_string()),
+        "blockquote" => Ok(lines
+            .map(|line| if line.is_empty() { ">".to_string() } else { format!("> {}", line) })
+            .collect::<Vec<_>>()
+            .join("\n")),
+        "bullet" => Ok(lines
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| format!("- {}", line))
+            .collect::<Vec<_>>()
+            .join("\n")),
+        "codeblock" => {
+            // The fence must be longer than any backtick run inside, or dictated code could close it
+            let longest_run = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+            let fence = "`".repeat(longest_run.max(2) + 1);
+            Ok(format!("{}\n{}\n{}", fence, text, fence))
         }
-    });
-
-    Ok(())
+        other => Err(Fair9Error::InvalidArgument(format!("Unknown format style '{}' (expected blockquote, codeblock, bullet or plain)", other))),
+    }
 }
 
-fn check_for_updates() -> Result<String> {
-    Ok(APP_VERSION.to_string())
+/// Undo Whisper's per-chunk capitalization of the first word ("Then" → "then"),
+/// leaving "I", "I'm" and all-caps words like "API" alone
+fn decapitalize_first_word(text: &str) -> String {
+    let first_word = text.split_whitespace().next().unwrap_or("");
+    let letters: Vec<char> = first_word.chars().filter(|c| c.is_alphabetic()).collect();
+    let is_pronoun_i = first_word == "I" || first_word.starts_with("I'");
+    let is_acronym = letters.len() > 1 && letters.iter().all(|c| c.is_uppercase());
+    if is_pronoun_i || is_acronym {
+        return text.to_string();
+    }
+
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
 }
 
-// ── Tests ────────────────────────────────────────────────────────────
+// ── Spoken Punctuation ───────────────────────────────────────────────
 
-fn match_snippet(trigger: &str) -> Option<String> {
-    let store = SNIPPETS.lock().unwrap();
-    store.iter()
-        .find(|s| s.trigger.eq_ignore_ascii_case(trigger))
-        .map(|s| s.content.clone())
+/// Spoken phrase → mark, longest phrases first so "question mark" wins over a lone "mark"
+const SPOKEN_PUNCTUATION: &[(&str, char)] = &[
+    ("exclamation point", '!'),
+    ("exclamation mark", '!'),
+    ("question mark", '?'),
+    ("full stop", '.'),
+    ("semicolon", ';'),
+    ("period", '.'),
+    ("comma", ','),
+    ("colon", ':'),
+];
+
+/// Turn dictated "period", "comma", "question mark" etc. into the marks themselves
+pub fn set_spoken_punctuation(enabled: bool) -> Result<(), Fair9Error> {
+    SPOKEN_PUNCTUATION_ENABLED.store(enabled, Ordering::SeqCst);
+    Ok(())
 }
 
-fn extract_json_string(json: &str, key: &str) -> Option<String> {
-   // Simple manual parser for tests to avoid heavy deps in test/mock 
-   // But we have serde now, so let's use it if we want, or keep logic simple
-   if let Ok(val) =  serde_json::from_str::<serde_json::Value>(json) {
-       return val.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
-   }
-   None
+/// Capitalize the word after a dictated sentence end (and the first word)
+pub fn set_autocap_after_punct(enabled: bool) -> Result<(), Fair9Error> {
+    AUTOCAP_AFTER_PUNCT.store(enabled, Ordering::SeqCst);
+    Ok(())
 }
 
+fn apply_spoken_punctuation(text: &str, autocap: bool) -> String {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut out: Vec<String> = Vec::new();
+    let mut sentence_start = autocap;
+    let mut i = 0;
+    while i < words.len() {
+        let matched = SPOKEN_PUNCTUATION.iter().find_map(|(phrase, mark)| {
+            let len = phrase.split(' ').count();
+            let candidate = words.get(i..i + len)?;
+            let spoken = candidate.iter()
+                .map(|w| w.trim_matches(|c: char| c.is_ascii_punctuation()).to_lowercase())
+                .collect::<Vec<_>>()
+                .join(" ");
+            (spoken == *phrase).then_some((len, *mark))
+        });
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::Instant;
+        match (matched, out.last_mut()) {
+            (Some((len, mark)), Some(prev)) => {
+                // Whisper often punctuates on its own ("works. Period."), so the dictated mark replaces it
+                let trimmed = prev.trim_end_matches(|c| matches!(c, '.' | ',' | '?' | '!' | ';' | ':')).len();
+                prev.truncate(trimmed);
+                prev.push(mark);
+                sentence_start = autocap && matches!(mark, '.' | '?' | '!');
+                i += len;
+            }
+            _ => {
+                let word = words[i];
+                out.push(if sentence_start { capitalize_first(word) } else { word.to_string() });
+                sentence_start = false;
+                i += 1;
+            }
+        }
+    }
+    out.join(" ")
+}
+
+fn spoken_punctuation(text: String) -> String {
+    if !SPOKEN_PUNCTUATION_ENABLED.load(Ordering::SeqCst) {
+        return text;
+    }
+    apply_spoken_punctuation(&text, AUTOCAP_AFTER_PUNCT.load(Ordering::SeqCst))
+}
+
+// ── Text Transforms ──────────────────────────────────────────────────
+
+/// User-defined regex replacement, applied after filler removal and before snippets
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TextTransform {
+    pub name: String,
+    pub find: String,
+    pub replace: String,
+}
+
+/// Add a transform to the end of the chain, or update one with the same name in place.
+/// `replace` may use capture groups ("$1", "${name}").
+pub fn register_text_transform(name: String, find: String, replace: String) -> Result<(), Fair9Error> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(Fair9Error::InvalidArgument("Transform name cannot be empty".to_string()));
+    }
+    let regex = Regex::new(&find).with_context(|| format!("Invalid pattern for transform '{}'", name))?;
+    let transform = TextTransform { name, find, replace };
+    let mut chain = TEXT_TRANSFORMS.lock().unwrap().clone();
+    match chain.iter_mut().find(|(t, _)| t.name == transform.name) {
+        Some(slot) => *slot = (transform, regex),
+        None => chain.push((transform, regex)),
+    }
+    Ok(commit_text_transforms(chain)?)
+}
+
+pub fn remove_text_transform(name: String) -> Result<(), Fair9Error> {
+    let mut chain = TEXT_TRANSFORMS.lock().unwrap().clone();
+    let before = chain.len();
+    chain.retain(|(t, _)| t.name != name);
+    if chain.len() == before {
+        return Err(Fair9Error::InvalidArgument(format!("No transform named '{}'", name)));
+    }
+    Ok(commit_text_transforms(chain)?)
+}
+
+/// Persist an edited chain, then make it live. A failed write leaves the old chain
+/// in effect, so an error always means nothing changed.
+fn commit_text_transforms(chain: Vec<(TextTransform, Regex)>) -> Result<()> {
+    let settings = Settings {
+        text_transforms: chain.iter().map(|(t, _)| t.clone()).collect(),
+        ..Settings::current()
+    };
+    write_settings(&settings)?;
+    *TEXT_TRANSFORMS.lock().unwrap() = chain;
+    Ok(())
+}
+
+pub fn list_text_transforms() -> Vec<TextTransform> {
+    TEXT_TRANSFORMS.lock().unwrap().iter().map(|(t, _)| t.clone()).collect()
+}
+
+/// Replace the whole chain, skipping (and logging) patterns that no longer compile
+fn set_text_transforms(transforms: &[TextTransform]) {
+    let compiled = transforms.iter()
+        .filter_map(|t| match Regex::new(&t.find) {
+            Ok(regex) => Some((t.clone(), regex)),
+            Err(e) => {
+                eprintln!("skipping text transform '{}': {}", t.name, e);
+                None
+            }
+        })
+        .collect();
+    *TEXT_TRANSFORMS.lock().unwrap() = compiled;
+}
+
+fn apply_text_transforms(text: String) -> String {
+    let chain = TEXT_TRANSFORMS.lock().unwrap();
+    chain.iter().fold(text, |acc, (t, regex)| regex.replace_all(&acc, t.replace.as_str()).into_owned())
+}
+
+// ── Profanity Filter ─────────────────────────────────────────────────
+
+const PROFANITY_MASK: &str = "***";
+const DEFAULT_PROFANITY_LIST: &[&str] = &["fuck", "fucking", "shit", "bitch", "bastard", "ass", "asshole", "damn", "crap"];
+
+/// Clean mode for professional documents: mask listed words in every transcription
+pub fn set_profanity_filter(enabled: bool) -> Result<(), Fair9Error> {
+    PROFANITY_FILTER.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Replace the list of masked words. Matching is whole-word and case-insensitive,
+/// so "ass" doesn't touch "class".
+pub fn set_profanity_list(words: Vec<String>) -> Result<(), Fair9Error> {
+    *PROFANITY_LIST.lock().unwrap() = profanity_regex(&words)?;
+    Ok(())
+}
+
+fn profanity_regex<S: AsRef<str>>(words: &[S]) -> Result<(Vec<String>, Option<Regex>)> {
+    let words: Vec<String> = words.iter()
+        .map(|w| w.as_ref().trim().to_lowercase())
+        .filter(|w| !w.is_empty())
+        .collect();
+    if words.is_empty() {
+        return Ok((words, None));
+    }
+    let alternation = words.iter().map(|w| regex::escape(w)).collect::<Vec<_>>().join("|");
+    let regex = Regex::new(&format!(r"(?i)\b(?:{})\b", alternation)).context("Invalid profanity list")?;
+    Ok((words, Some(regex)))
+}
+
+fn filter_profanity(text: String) -> String {
+    if !PROFANITY_FILTER.load(Ordering::SeqCst) {
+        return text;
+    }
+    match &PROFANITY_LIST.lock().unwrap().1 {
+        Some(regex) => regex.replace_all(&text, PROFANITY_MASK).into_owned(),
+        None => text,
+    }
+}
+
+// ── Phonetic Output ──────────────────────────────────────────────────
+
+/// Grapheme-to-phoneme converter for one language
+trait GraphemeToPhoneme {
+    fn word_to_ipa(&self, word: &str) -> String;
+}
+
+/// Rule-based English G2P: a small lexicon of irregular words, then spelling rules.
+/// Approximate (General American), meant for learners rather than linguists.
+struct EnglishG2p;
+
+const ENGLISH_LEXICON: &[(&str, &str)] = &[
+    ("a", "ə"), ("the", "ðə"), ("of", "ʌv"), ("to", "tu"), ("do", "du"), ("two", "tu"),
+    ("you", "ju"), ("i", "aɪ"), ("is", "ɪz"), ("was", "wʌz"), ("are", "ɑɹ"), ("have", "hæv"),
+    ("one", "wʌn"), ("said", "sɛd"), ("hello", "həloʊ"), ("world", "wɝld"), ("what", "wʌt"),
+];
+
+// Checked before single letters, in order
+const ENGLISH_DIGRAPHS: &[(&str, &str)] = &[
+    ("th", "θ"), ("sh", "ʃ"), ("ch", "tʃ"), ("ph", "f"), ("wh", "w"), ("ck", "k"),
+    ("ng", "ŋ"), ("nk", "ŋk"), ("qu", "kw"), ("ee", "i"), ("ea", "i"), ("oo", "u"),
+    ("ai", "eɪ"), ("ay", "eɪ"), ("oa", "oʊ"), ("ow", "oʊ"), ("ou", "aʊ"), ("oi", "ɔɪ"),
+    ("oy", "ɔɪ"), ("ar", "ɑɹ"), ("er", "ɚ"), ("ir", "ɝ"), ("ur", "ɝ"), ("or", "ɔɹ"),
+];
+
+fn is_vowel(c: char) -> bool {
+    matches!(c, 'a' | 'e' | 'i' | 'o' | 'u')
+}
+
+impl GraphemeToPhoneme for EnglishG2p {
+    fn word_to_ipa(&self, word: &str) -> String {
+        let word: String = word.to_lowercase().chars().filter(|c| c.is_ascii_alphabetic()).collect();
+        if let Some((_, ipa)) = ENGLISH_LEXICON.iter().find(|(w, _)| *w == word) {
+            return ipa.to_string();
+        }
+
+        let chars: Vec<char> = word.chars().collect();
+        let n = chars.len();
+        // "make", "ride": a silent final e lengthens the vowel before a single consonant
+        let magic_e = n > 2
+            && chars[n - 1] == 'e'
+            && !is_vowel(chars[n - 2])
+            && is_vowel(chars[n - 3])
+            && (n < 4 || !is_vowel(chars[n - 4]));
+
+        let mut out = String::new();
+        let mut i = 0;
+        while i < n {
+            if magic_e && i == n - 1 {
+                break;
+            }
+            if magic_e && i == n - 3 {
+                out.push_str(match chars[i] {
+                    'a' => "eɪ",
+                    'e' => "i",
+                    'i' => "aɪ",
+                    'o' => "oʊ",
+                    _ => "ju",
+                });
+                i += 1;
+                continue;
+            }
+            let pair: String = chars[i..(i + 2).min(n)].iter().collect();
+            if let Some((_, ipa)) = ENGLISH_DIGRAPHS.iter().find(|(g, _)| *g == pair) {
+                out.push_str(ipa);
+                i += 2;
+                continue;
+            }
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if next == Some(c) && !is_vowel(c) {
+                i += 1; // "ll", "ss": doubled consonants sound once
+                continue;
+            }
+            match c {
+                'a' => out.push('æ'),
+                'e' => out.push('ɛ'),
+                'i' => out.push('ɪ'),
+                'o' => out.push('ɑ'),
+                'u' => out.push('ʌ'),
+                'y' => out.push(if i == 0 { 'j' } else { 'i' }),
+                'c' => out.push(if matches!(next, Some('e' | 'i' | 'y')) { 's' } else { 'k' }),
+                'g' => out.push('ɡ'),
+                'j' => out.push_str("dʒ"),
+                'q' => out.push('k'),
+                'x' => out.push_str("ks"),
+                'r' => out.push('ɹ'),
+                other => out.push(other),
+            }
+            i += 1;
+        }
+        out
+    }
+}
+
+fn g2p_for(language: &str) -> Result<Box<dyn GraphemeToPhoneme>> {
+    match language.trim().to_lowercase().as_str() {
+        "en" | "english" => Ok(Box::new(EnglishG2p)),
+        other => Err(anyhow!("Phonetic output is not supported for language '{}'", other)),
+    }
+}
+
+/// Approximate IPA for `text`, one transcription per word
+pub fn text_to_ipa(text: String, language: String) -> Result<String, Fair9Error> {
+    let g2p = g2p_for(&language)?;
+    Ok(text.split_whitespace()
+        .map(|w| g2p.word_to_ipa(w))
+        .filter(|ipa| !ipa.is_empty())
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Attach an IPA rendering to each stream emission, when the output language has a
+/// grapheme-to-phoneme converter (English only for now)
+pub fn set_output_ipa(enabled: bool) -> Result<(), Fair9Error> {
+    OUTPUT_IPA.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+// ── New AI Features (Restored) ──────────────────────────────────────
+
+const OLLAMA_URL: &str = "http://localhost:11434";
+const OLLAMA_MODEL: &str = "llama3";
+const AI_SYSTEM_PROMPT: &str = "You are a text editor. Execute the user's command on the following text. Return ONLY the modified text with no explanation, no markdown formatting, no quotes around it. Just the raw edited text, nothing else.";
+const CODE_SYSTEM_PROMPT: &str = "You are a code editor. Execute the user's command on the following source code. Preserve the language, indentation and formatting style. Return ONLY the modified code with no explanation and no markdown code fences. Just the raw code, nothing else.";
+
+#[derive(Serialize)]
+struct OllamaRequest {
+    model: String,
+    prompt: String,
+    system: String,
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    response: String,
+}
+
+/// One NDJSON line of a `"stream": true` reply
+#[derive(Deserialize)]
+struct OllamaStreamChunk {
+    #[serde(default)]
+    response: String,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+pub fn apply_semantic_correction(text: String) -> String {
+    if !SEMANTIC_CORRECTION.load(Ordering::SeqCst) {
+        return text;
+    }
+
+    // Skip short texts to avoid latency on simple commands
+    if text.split_whitespace().count() < 4 {
+        return text;
+    }
+
+    let prompt = format!("Fix grammatical errors and remove hesitations (like 'no wait', 'I meant') from this text. Output ONLY the fixed text: \"{}\"", text);
+    
+    // Call Ollama (assuming lamma3 or similar is default)
+    // We use a short timeout because this is real-time-ish
+    let result = ureq::post(&format!("{}/api/generate", OLLAMA_URL))
+        .timeout(std::time::Duration::from_millis(1500)) 
+        .send_json(json!({
+            "model": OLLAMA_MODEL,
+            "prompt": prompt,
+            "stream": false
+        }));
+
+    match result {
+        Ok(res) => {
+            if let Ok(json) = res.into_json::<OllamaResponse>() {
+                if !json.response.trim().is_empty() {
+                    return json.response.trim().to_string();
+                }
+            }
+        }
+        Err(_) => {
+            // Silently fail back to original text if AI is down/slow
+        }
+    }
+    
+    text
+}
+
+/// Run every final transcription through an AI command (e.g. "fix punctuation and
+/// capitalization") before it's emitted. Interim results are never sent.
+pub fn set_auto_ai_cleanup(enabled: bool, command: String) -> Result<(), Fair9Error> {
+    if enabled && command.trim().is_empty() {
+        return Err(Fair9Error::InvalidArgument("No voice command provided".to_string()));
+    }
+    *AUTO_AI_CLEANUP.lock().unwrap() = enabled.then(|| command.trim().to_string());
+    Ok(())
+}
+
+fn apply_auto_ai_cleanup(text: String) -> String {
+    let Some(command) = AUTO_AI_CLEANUP.lock().unwrap().clone() else {
+        return text;
+    };
+    auto_ai_cleanup_with(text, &command, |prompt, system| {
+        ollama_generate(OLLAMA_URL, OLLAMA_MODEL, prompt, system.to_string())
+    })
+}
+
+/// Falls back to the original text when Ollama is offline or answers with nothing,
+/// so dictation keeps working without it
+fn auto_ai_cleanup_with(text: String, command: &str, generate: impl FnOnce(String, &str) -> Result<String>) -> String {
+    if !worth_sending_to_ai(&text, MIN_WORDS_FOR_AI.load(Ordering::SeqCst))
+        || check_ai_input_size(&text, MAX_AI_INPUT_CHARS.load(Ordering::SeqCst)).is_err()
+    {
+        return text;
+    }
+    let prompt = format!("Command: {}\n\nText to edit:\n{}", command, text);
+    match generate(prompt, AI_SYSTEM_PROMPT) {
+        Ok(cleaned) if !cleaned.trim().is_empty() => cleaned,
+        Ok(_) => text,
+        Err(e) => {
+            eprintln!("auto AI cleanup skipped: {:#}", e);
+            text
+        }
+    }
+}
+
+/// Transcriptions with fewer words than this skip the automatic AI step, since
+/// silence or a stray "okay" isn't worth an Ollama round-trip
+pub fn set_min_words_for_ai(n: usize) -> Result<(), Fair9Error> {
+    MIN_WORDS_FOR_AI.store(n, Ordering::SeqCst);
+    Ok(())
+}
+
+fn worth_sending_to_ai(text: &str, min_words: usize) -> bool {
+    let words = strip_nonspeech_tags(text).split_whitespace()
+        .filter(|w| w.chars().any(char::is_alphanumeric))
+        .count();
+    words > 0 && words >= min_words
+}
+
+fn validate_ai_command(voice_command: &str, selected_text: &str) -> Result<()> {
+    if voice_command.trim().is_empty() {
+        return Err(anyhow!("No voice command provided"));
+    }
+    if selected_text.trim().is_empty() {
+        return Err(anyhow!("No text selected"));
+    }
+    check_ai_input_size(selected_text, MAX_AI_INPUT_CHARS.load(Ordering::SeqCst))
+}
+
+pub fn process_ai_command_with_config(
+    voice_command: String,
+    selected_text: String,
+    ollama_url: String,
+    model: String,
+) -> Result<String, Fair9Error> {
+    validate_ai_command(&voice_command, &selected_text)?;
+
+    let prose_prompt = ai_system_prompt();
+    let system = select_system_prompt(&selected_text, AUTO_CODE_PROMPT.load(Ordering::SeqCst), &prose_prompt);
+
+    let provider = *AI_PROVIDER.lock().unwrap();
+    let edited = edit_in_chunks(&selected_text, AI_CHUNK_CHARS.load(Ordering::SeqCst), |chunk| {
+        let prompt = format!("Command: {}\n\nText to edit:\n{}", voice_command, chunk);
+        match provider {
+            AiProvider::Ollama => ollama_generate(&ollama_url, &model, prompt, system.to_string()),
+            AiProvider::OpenAiCompatible => chat_completion(&ollama_url, &model, prompt, system.to_string()),
+        }
+    })?;
+    record_ai_command(&voice_command);
+    Ok(edited)
+}
+
+/// Send AI commands in pieces of at most `n` characters, split between sentences,
+/// so small local models don't lose the thread on long selections. 0 (default)
+/// sends the selection in one request.
+pub fn set_ai_chunk_chars(n: usize) -> Result<(), Fair9Error> {
+    AI_CHUNK_CHARS.store(n, Ordering::SeqCst);
+    Ok(())
+}
+
+/// The pieces an AI command on `text` would be sent in with chunks of `max_chars`,
+/// so the UI can say "this will be sent in 4 requests" up front
+pub fn preview_ai_chunks(text: String, max_chars: usize) -> Vec<String> {
+    if max_chars == 0 {
+        return vec![text.trim().to_string()];
+    }
+    chunk_spans(&text, max_chars).into_iter().map(|span| text[span].to_string()).collect()
+}
+
+/// Run `generate` over each chunk and stitch the results back together with the
+/// whitespace that separated the chunks (so paragraph breaks survive)
+fn edit_in_chunks(text: &str, max_chars: usize, generate: impl FnMut(&str) -> Result<String>) -> Result<String> {
+    edit_in_chunks_with(text, max_chars, |_| {}, generate)
+}
+
+/// `edit_in_chunks`, also handing each between-chunk separator to `separator` as
+/// it's reached, for callers that stream the edit out piece by piece
+fn edit_in_chunks_with(
+    text: &str,
+    max_chars: usize,
+    mut separator: impl FnMut(&str),
+    mut generate: impl FnMut(&str) -> Result<String>,
+) -> Result<String> {
+    if max_chars == 0 {
+        return generate(text);
+    }
+    let mut edited = String::new();
+    let mut prev_end = None;
+    for span in chunk_spans(text, max_chars) {
+        if let Some(prev_end) = prev_end {
+            separator(&text[prev_end..span.start]);
+            edited.push_str(&text[prev_end..span.start]);
+        }
+        prev_end = Some(span.end);
+        edited.push_str(generate(&text[span])?.trim());
+    }
+    Ok(edited)
+}
+
+/// Byte ranges of `text` packing whole sentences into chunks of at most `max_chars`
+/// characters. A sentence that is too long on its own is split between words, and
+/// a word that is too long between characters.
+fn chunk_spans(text: &str, max_chars: usize) -> Vec<std::ops::Range<usize>> {
+    let max_chars = max_chars.max(1);
+    let pieces: Vec<std::ops::Range<usize>> = sentence_spans(text)
+        .into_iter()
+        .flat_map(|sentence| split_oversized(text, sentence, max_chars))
+        .collect();
+
+    let mut chunks: Vec<std::ops::Range<usize>> = Vec::new();
+    for piece in pieces {
+        match chunks.last_mut() {
+            Some(chunk) if text[chunk.start..piece.end].chars().count() <= max_chars => chunk.end = piece.end,
+            _ => chunks.push(piece),
+        }
+    }
+    chunks
+}
+
+/// Sentences end at `.`, `?` or `!` followed by whitespace, or at a line break
+fn sentence_spans(text: &str) -> Vec<std::ops::Range<usize>> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c.is_whitespace() && start.is_none() {
+            continue;
+        }
+        let begin = *start.get_or_insert(i);
+        let next_is_space = chars.peek().map_or(true, |(_, n)| n.is_whitespace());
+        if c == '\n' {
+            spans.push(begin..text[..i].trim_end().len());
+            start = None;
+        } else if matches!(c, '.' | '?' | '!') && next_is_space {
+            spans.push(begin..i + c.len_utf8());
+            start = None;
+        }
+    }
+    if let Some(begin) = start {
+        spans.push(begin..text.trim_end().len());
+    }
+    spans.retain(|span| !span.is_empty());
+    spans
+}
+
+fn split_oversized(text: &str, span: std::ops::Range<usize>, max_chars: usize) -> Vec<std::ops::Range<usize>> {
+    if text[span.clone()].chars().count() <= max_chars {
+        return vec![span];
+    }
+    let mut pieces = Vec::new();
+    let mut offset = span.start;
+    for word in text[span.clone()].split_whitespace() {
+        let word_start = offset + text[offset..].find(word).unwrap_or(0);
+        offset = word_start + word.len();
+        let mut piece_start = word_start;
+        let mut count = 0;
+        for (i, _) in word.char_indices() {
+            if count == max_chars {
+                pieces.push(piece_start..word_start + i);
+                piece_start = word_start + i;
+                count = 0;
+            }
+            count += 1;
+        }
+        pieces.push(piece_start..offset);
+    }
+    pieces
+}
+
+/// Largest selection (in characters) sent to the model; bigger inputs are refused
+/// with guidance rather than silently truncated or left to time out
+pub fn set_max_ai_input_chars(n: usize) -> Result<(), Fair9Error> {
+    if n == 0 {
+        return Err(Fair9Error::InvalidArgument("Max AI input size must be greater than zero".to_string()));
+    }
+    MAX_AI_INPUT_CHARS.store(n, Ordering::SeqCst);
+    Ok(())
+}
+
+fn check_ai_input_size(text: &str, max_chars: usize) -> Result<()> {
+    let len = text.chars().count();
+    if len > max_chars {
+        return Err(anyhow!(
+            "Selected text is {} characters, more than the {} an AI command can handle. \
+             Select a smaller portion and try again.",
+            len, max_chars
+        ));
+    }
+    Ok(())
+}
+
+/// A past AI command. Only the command is kept, never the edited content.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CommandHistoryEntry {
+    command: String,
+    timestamp: u64, // Unix seconds
+}
+
+fn record_ai_command(command: &str) {
+    let command = command.trim();
+    if command.is_empty() {
+        return;
+    }
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut history = COMMAND_HISTORY.lock().unwrap();
+    history.push(CommandHistoryEntry { command: command.to_string(), timestamp });
+    if history.len() > MAX_COMMAND_HISTORY {
+        let excess = history.len() - MAX_COMMAND_HISTORY;
+        history.drain(..excess);
+    }
+    HISTORY_DIRTY.store(true, Ordering::SeqCst);
+}
+
+/// Most recent distinct AI commands (case-insensitive), newest first
+pub fn get_recent_commands(limit: usize) -> Vec<String> {
+    let history = COMMAND_HISTORY.lock().unwrap();
+    let mut recent: Vec<String> = Vec::new();
+    for entry in history.iter().rev() {
+        if recent.len() >= limit {
+            break;
+        }
+        if !recent.iter().any(|c| c.eq_ignore_ascii_case(&entry.command)) {
+            recent.push(entry.command.clone());
+        }
+    }
+    recent
+}
+
+fn get_history_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("command_history.json"))
+}
+
+pub fn load_command_history() -> Result<usize, Fair9Error> {
+    let path = get_history_path()?;
+    let entries: Vec<CommandHistoryEntry> = if path.exists() {
+        serde_json::from_str(&fs::read_to_string(&path)?).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let count = entries.len();
+    *COMMAND_HISTORY.lock().unwrap() = entries;
+    HISTORY_DIRTY.store(false, Ordering::SeqCst);
+    Ok(count)
+}
+
+fn save_command_history() -> Result<()> {
+    let path = get_history_path()?;
+    let data = serde_json::to_string_pretty(&*COMMAND_HISTORY.lock().unwrap())?;
+    write_data_file(&path, data, "command history")?;
+    HISTORY_DIRTY.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Same as `process_ai_command_with_config`, but ticks `progress` with the elapsed
+/// seconds every second while Ollama is working, so the UI can animate.
+/// Ticking stops as soon as the request succeeds or fails.
+pub fn process_ai_command_with_progress(
+    voice_command: String,
+    selected_text: String,
+    ollama_url: String,
+    model: String,
+    progress: StreamSink<u32>,
+) -> Result<String, Fair9Error> {
+    let heartbeat = Heartbeat::start(std::time::Duration::from_secs(1), move |elapsed| {
+        progress.add(elapsed);
+    });
+    let result = process_ai_command_with_config(voice_command, selected_text, ollama_url, model);
+    heartbeat.stop();
+    result
+}
+
+/// Same as `process_ai_command_with_config`, but asks Ollama to stream and forwards
+/// each piece of the edit to `sink` as it arrives. Leading whitespace of each reply is
+/// dropped; the returned string is the complete, trimmed edit.
+pub fn process_ai_command_stream(
+    voice_command: String,
+    selected_text: String,
+    ollama_url: String,
+    model: String,
+    sink: StreamSink<String>,
+) -> Result<String, Fair9Error> {
+    validate_ai_command(&voice_command, &selected_text)?;
+
+    let prose_prompt = ai_system_prompt();
+    let system = select_system_prompt(&selected_text, AUTO_CODE_PROMPT.load(Ordering::SeqCst), &prose_prompt);
+    let provider = *AI_PROVIDER.lock().unwrap();
+    // Separators between chunks wait until the next chunk has produced something
+    let pending_separator = std::cell::RefCell::new(String::new());
+    let edited = edit_in_chunks_with(
+        &selected_text,
+        AI_CHUNK_CHARS.load(Ordering::SeqCst),
+        |sep| pending_separator.borrow_mut().push_str(sep),
+        |chunk| {
+            let prompt = format!("Command: {}\n\nText to edit:\n{}", voice_command, chunk);
+            let mut started = false;
+            let mut forward = |piece: &str| {
+                let piece = if started { piece } else { piece.trim_start() };
+                if piece.is_empty() {
+                    return;
+                }
+                started = true;
+                let separator = std::mem::take(&mut *pending_separator.borrow_mut());
+                sink.add(separator + piece);
+            };
+            match provider {
+                AiProvider::Ollama => ollama_generate_stream(&ollama_url, &model, prompt, system.to_string(), forward),
+                // Chat endpoints stream as server-sent events; send the reply as one piece instead
+                AiProvider::OpenAiCompatible => {
+                    let edited = chat_completion(&ollama_url, &model, prompt, system.to_string())?;
+                    forward(&edited);
+                    Ok(edited)
+                }
+            }
+        },
+    )?;
+    record_ai_command(&voice_command);
+    Ok(edited)
+}
+
+/// Background ticker that runs until stopped (or dropped)
+struct Heartbeat {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    fn start(interval: std::time::Duration, tick: impl Fn(u32) + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let handle = thread::spawn(move || {
+            // Poll in small steps so stop() returns promptly
+            let poll = std::time::Duration::from_millis(20).min(interval);
+            let started = std::time::Instant::now();
+            let mut next_tick = interval;
+            while !stop_flag.load(Ordering::SeqCst) {
+                thread::sleep(poll);
+                if started.elapsed() >= next_tick && !stop_flag.load(Ordering::SeqCst) {
+                    next_tick += interval;
+                    tick(started.elapsed().as_secs() as u32);
+                }
+            }
+        });
+        Heartbeat { stop, handle: Some(handle) }
+    }
+
+    fn stop(mut self) {
+        self.halt();
+    }
+
+    fn halt(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.halt();
+    }
+}
+
+/// Pick the code-editing prompt for code selections (when enabled), else `prose_prompt`
+fn select_system_prompt<'a>(selected_text: &str, auto_code_prompt: bool, prose_prompt: &'a str) -> &'a str {
+    if auto_code_prompt && looks_like_code(selected_text) {
+        CODE_SYSTEM_PROMPT
+    } else {
+        prose_prompt
+    }
+}
+
+/// Replace the system prompt AI commands run under, e.g. a "summarize" or "make it
+/// formal" persona. Code selections still get the code prompt while
+/// `set_auto_code_prompt` is on. Automatic cleanup keeps the default editor prompt.
+pub fn set_ai_system_prompt(prompt: String) -> Result<(), Fair9Error> {
+    if prompt.trim().is_empty() {
+        return Err(Fair9Error::InvalidArgument("System prompt cannot be empty; use reset_ai_system_prompt to restore the default".to_string()));
+    }
+    *CUSTOM_AI_SYSTEM_PROMPT.lock().unwrap() = Some(prompt.trim().to_string());
+    Ok(())
+}
+
+/// Go back to the built-in text editor prompt
+pub fn reset_ai_system_prompt() -> Result<(), Fair9Error> {
+    *CUSTOM_AI_SYSTEM_PROMPT.lock().unwrap() = None;
+    Ok(())
+}
+
+/// The system prompt AI commands currently use for prose
+pub fn ai_system_prompt() -> String {
+    CUSTOM_AI_SYSTEM_PROMPT.lock().unwrap().clone().unwrap_or_else(|| AI_SYSTEM_PROMPT.to_string())
+}
+
+/// Give AI commands on code selections a code-editing system prompt. Off by default,
+/// so existing commands keep their prompt until this is opted into.
+pub fn set_auto_code_prompt(enabled: bool) -> Result<(), Fair9Error> {
+    AUTO_CODE_PROMPT.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+const CODE_KEYWORDS: &[&str] = &[
+    "fn ", "let ", "const ", "def ", "class ", "return ", "import ", "function ",
+    "var ", "public ", "private ", "#include", "=>", "::", "->", "if (", "for (", "while (",
+];
+
+/// Whether `text` reads as source code rather than prose, the same check AI commands
+/// use to pick the code prompt. Lets the UI label or route a selection up front.
+pub fn is_code_selection(text: String) -> bool {
+    looks_like_code(&text)
+}
+
+/// Heuristic: symbol density, code-style line endings/indentation, and keywords.
+/// Two or more signals means the selection is treated as code.
+fn looks_like_code(text: &str) -> bool {
+    let non_ws: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if non_ws.is_empty() {
+        return false;
+    }
+    let mut signals = 0;
+
+    let symbols = non_ws.iter().filter(|c| "{}()[];=<>".contains(**c)).count();
+    if symbols as f32 / non_ws.len() as f32 > 0.08 {
+        signals += 1;
+    }
+
+    let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+    let code_endings = lines
+        .iter()
+        .filter(|l| l.trim_end().ends_with(|c| c == ';' || c == '{' || c == '}'))
+        .count();
+    if code_endings * 3 >= lines.len() {
+        signals += 1;
+    }
+
+    let indented = lines.iter().filter(|l| l.starts_with("    ") || l.starts_with('\t')).count();
+    if lines.len() > 1 && indented > 0 {
+        signals += 1;
+    }
+
+    if CODE_KEYWORDS.iter().filter(|k| text.contains(*k)).count() >= 2 {
+        signals += 1;
+    }
+
+    signals >= 2
+}
+
+fn ollama_generate(ollama_url: &str, model: &str, prompt: String, system: String) -> Result<String> {
+    let gen = SHUTDOWN_GEN.load(Ordering::SeqCst);
+    let json: OllamaResponse = with_ollama_retries(ollama_url, OLLAMA_RETRY_BASE_DELAY, || true, |timeout| {
+        let res = ureq::post(&format!("{}/api/generate", ollama_url))
+            .timeout(timeout)
+            .send_json(OllamaRequest {
+                model: model.to_string(),
+                prompt: prompt.clone(),
+                system: system.clone(),
+                stream: false,
+            })
+            .context("Failed to connect to Ollama")?;
+        res.into_json().context("Failed to parse Ollama response")
+    })?;
+    // The request itself can't be interrupted, but don't hand back a result after shutdown
+    if SHUTDOWN_GEN.load(Ordering::SeqCst) != gen {
+        return Err(Fair9Error::Cancelled("AI command".to_string()).into());
+    }
+    
+    Ok(json.response.trim().to_string())
+}
+
+/// `ollama_generate` with `"stream": true`, calling `on_piece` with each token as it
+/// arrives. Generation can run long, so only the connect and gaps between tokens
+/// are time-limited, not the whole reply.
+fn ollama_generate_stream(
+    ollama_url: &str,
+    model: &str,
+    prompt: String,
+    system: String,
+    mut on_piece: impl FnMut(&str),
+) -> Result<String> {
+    // Once part of the reply has gone out, a retry would repeat it
+    let streamed = std::cell::Cell::new(false);
+    let response = with_ollama_retries(ollama_url, OLLAMA_RETRY_BASE_DELAY, || !streamed.get(), |timeout| {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(timeout)
+            .timeout_read(timeout)
+            .build();
+        let res = agent.post(&format!("{}/api/generate", ollama_url))
+            .send_json(OllamaRequest {
+                model: model.to_string(),
+                prompt: prompt.clone(),
+                system: system.clone(),
+                stream: true,
+            })
+            .context("Failed to connect to Ollama")?;
+        read_ollama_stream(std::io::BufReader::new(res.into_reader()), |piece| {
+            streamed.set(true);
+            on_piece(piece);
+        })
+    })?;
+    Ok(response.trim().to_string())
+}
+
+/// How long one Ollama request may wait to connect or for the next piece of the
+/// reply. Raise it for big models that take a while to load.
+pub fn set_ollama_timeout_ms(ms: u64) -> Result<(), Fair9Error> {
+    if ms == 0 {
+        return Err(Fair9Error::InvalidArgument("Ollama timeout must be greater than zero".to_string()));
+    }
+    OLLAMA_TIMEOUT_MS.store(ms, Ordering::SeqCst);
+    Ok(())
+}
+
+/// How many more times an Ollama request is tried after it's refused or times out,
+/// with a doubling delay in between. 0 disables retries.
+pub fn set_ollama_retries(n: u32) -> Result<(), Fair9Error> {
+    OLLAMA_RETRIES.store(n as usize, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Why an Ollama request didn't get through
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OllamaFailure {
+    Refused,  // Nothing listening: Ollama isn't running
+    TimedOut, // Connected (or connecting) but no answer in time: usually a cold model load
+    Other,
+}
+
+fn classify_ollama_error(e: &anyhow::Error) -> OllamaFailure {
+    let mut connect_failed = false;
+    for cause in e.chain() {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            match io.kind() {
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => return OllamaFailure::TimedOut,
+                std::io::ErrorKind::ConnectionRefused => return OllamaFailure::Refused,
+                _ => {}
+            }
+        }
+        if let Some(ureq::Error::Transport(t)) = cause.downcast_ref::<ureq::Error>() {
+            connect_failed |= t.kind() == ureq::ErrorKind::ConnectionFailed;
+        }
+    }
+    if connect_failed { OllamaFailure::Refused } else { OllamaFailure::Other }
+}
+
+fn with_ollama_retries<T>(
+    ollama_url: &str,
+    base_delay: std::time::Duration,
+    can_retry: impl Fn() -> bool,
+    send: impl FnMut(std::time::Duration) -> Result<T>,
+) -> Result<T> {
+    with_ai_retries(AiProvider::Ollama, ollama_url, base_delay, can_retry, send)
+}
+
+/// Run `send` with the configured timeout, retrying refusals and timeouts (while
+/// `can_retry` allows) with a doubling delay. The final error says which it was.
+fn with_ai_retries<T>(
+    provider: AiProvider,
+    url: &str,
+    base_delay: std::time::Duration,
+    can_retry: impl Fn() -> bool,
+    mut send: impl FnMut(std::time::Duration) -> Result<T>,
+) -> Result<T> {
+    let timeout = std::time::Duration::from_millis(OLLAMA_TIMEOUT_MS.load(Ordering::SeqCst));
+    let retries = OLLAMA_RETRIES.load(Ordering::SeqCst) as u32;
+    let gen = SHUTDOWN_GEN.load(Ordering::SeqCst);
+    let mut attempt = 0;
+    loop {
+        let e = match send(timeout) {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let failure = classify_ollama_error(&e);
+        let retryable = failure != OllamaFailure::Other && can_retry() && SHUTDOWN_GEN.load(Ordering::SeqCst) == gen;
+        if retryable && attempt < retries {
+            let delay = base_delay * 2u32.pow(attempt);
+            attempt += 1;
+            eprintln!("AI request failed (attempt {}), retrying in {:?}: {:#}", attempt, delay, e);
+            thread::sleep(delay);
+            continue;
+        }
+        let seconds = timeout.as_secs_f32();
+        return Err(match (failure, provider) {
+            (OllamaFailure::Refused, AiProvider::Ollama) => e.context(Fair9Error::OllamaOffline(url.to_string())),
+            (OllamaFailure::TimedOut, AiProvider::Ollama) => e.context(Fair9Error::AiTimedOut(format!(
+                "Ollama timed out after {:.1}s; the model is probably still loading. Try again in a moment.",
+                seconds
+            ))),
+            (OllamaFailure::Refused, AiProvider::OpenAiCompatible) => e.context(Fair9Error::AiEndpointOffline(url.to_string())),
+            (OllamaFailure::TimedOut, AiProvider::OpenAiCompatible) => e.context(Fair9Error::AiTimedOut(format!(
+                "The AI endpoint timed out after {:.1}s. Try again, or raise the timeout.",
+                seconds
+            ))),
+            (OllamaFailure::Other, _) => e,
+        });
+    }
+}
+
+/// Read Ollama's NDJSON reply line by line until `done`. A line split across reads
+/// is only parsed once the reader has all of it.
+fn read_ollama_stream(mut reader: impl std::io::BufRead, mut on_piece: impl FnMut(&str)) -> Result<String> {
+    let gen = SHUTDOWN_GEN.load(Ordering::SeqCst);
+    let mut response = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).context("Failed to read Ollama response")? == 0 {
+            return Err(anyhow!("Ollama closed the stream before finishing"));
+        }
+        if SHUTDOWN_GEN.load(Ordering::SeqCst) != gen {
+            return Err(Fair9Error::Cancelled("AI command".to_string()).into());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chunk: OllamaStreamChunk = serde_json::from_str(&line).context("Failed to parse Ollama response")?;
+        if let Some(error) = chunk.error {
+            return Err(anyhow!("Ollama error: {}", error));
+        }
+        if !chunk.response.is_empty() {
+            on_piece(&chunk.response);
+            response.push_str(&chunk.response);
+        }
+        if chunk.done {
+            return Ok(response);
+        }
+    }
+}
+
+/// Which API shape AI commands speak. The URL and model passed to
+/// `process_ai_command_with_config` are interpreted accordingly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AiProvider {
+    Ollama,           // POST {url}/api/generate
+    OpenAiCompatible, // POST {url}/v1/chat/completions with a bearer key
+}
+
+impl AiProvider {
+    fn parse(name: &str) -> Result<Self> {
+        match name.trim().to_lowercase().replace(['-', ' '], "_").as_str() {
+            "ollama" => Ok(AiProvider::Ollama),
+            "openai" | "openai_compatible" => Ok(AiProvider::OpenAiCompatible),
+            other => Err(anyhow!("Unknown AI provider '{}' (expected ollama or openai_compatible)", other)),
+        }
+    }
+}
+
+/// Send AI commands to "ollama" (default) or an "openai_compatible" chat endpoint
+pub fn set_ai_provider(provider: String) -> Result<(), Fair9Error> {
+    *AI_PROVIDER.lock().unwrap() = AiProvider::parse(&provider)?;
+    Ok(())
+}
+
+/// Bearer key for OpenAI-compatible endpoints. Kept in memory only; the app is
+/// expected to hold it in the OS keychain and set it on launch. None or empty clears it.
+pub fn set_ai_api_key(key: Option<String>) -> Result<(), Fair9Error> {
+    *AI_API_KEY.lock().unwrap() = key.map(|k| k.trim().to_string()).filter(|k| !k.is_empty());
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// "https://host" and "https://host/v1" both mean ".../v1/chat/completions"
+fn chat_completions_url(base: &str) -> String {
+    let base = base.trim().trim_end_matches('/');
+    if base.ends_with("/chat/completions") {
+        base.to_string()
+    } else if base.ends_with("/v1") {
+        format!("{}/chat/completions", base)
+    } else {
+        format!("{}/v1/chat/completions", base)
+    }
+}
+
+fn chat_request_body(model: &str, system: &str, prompt: &str) -> serde_json::Value {
+    json!({
+        "model": model,
+        "messages": [
+            { "role": "system", "content": system },
+            { "role": "user", "content": prompt },
+        ],
+        "stream": false,
+    })
+}
+
+fn parse_chat_response(body: &str) -> Result<String> {
+    let response: ChatResponse = serde_json::from_str(body).context("Failed to parse chat completion response")?;
+    let content = response.choices.into_iter().next()
+        .and_then(|choice| choice.message.content)
+        .ok_or_else(|| anyhow!("Chat completion response had no message content"))?;
+    Ok(content.trim().to_string())
+}
+
+/// One chat completion against an OpenAI-compatible endpoint, with the same
+/// timeout and retries as Ollama requests
+fn chat_completion(base_url: &str, model: &str, prompt: String, system: String) -> Result<String> {
+    let gen = SHUTDOWN_GEN.load(Ordering::SeqCst);
+    let url = chat_completions_url(base_url);
+    let api_key = AI_API_KEY.lock().unwrap().clone();
+    let body = chat_request_body(model, &system, &prompt);
+    let reply = with_ai_retries(AiProvider::OpenAiCompatible, &url, OLLAMA_RETRY_BASE_DELAY, || true, |timeout| {
+        let mut request = ureq::post(&url).timeout(timeout);
+        if let Some(key) = &api_key {
+            request = request.set("Authorization", &format!("Bearer {}", key));
+        }
+        match request.send_json(body.clone()) {
+            Ok(res) => res.into_string().context("Failed to read chat completion response"),
+            Err(ureq::Error::Status(code, res)) => {
+                // These APIs explain themselves in {"error": {"message": ...}}
+                let detail = res.into_string().ok()
+                    .and_then(|b| serde_json::from_str::<serde_json::Value>(&b).ok())
+                    .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+                    .unwrap_or_default();
+                let hint = if code == 401 || code == 403 { " (check the API key)" } else { "" };
+                Err(anyhow!("AI endpoint returned HTTP {}{}: {}", code, hint, detail))
+            }
+            Err(e) => Err(anyhow::Error::new(e).context("Failed to connect to the AI endpoint")),
+        }
+    })?;
+    if SHUTDOWN_GEN.load(Ordering::SeqCst) != gen {
+        return Err(Fair9Error::Cancelled("AI command".to_string()).into());
+    }
+    parse_chat_response(&reply)
+}
+
+// ── Translation (Ollama) ─────────────────────────────────────────────
+// Whisper's own translate task only targets English; this handles any
+// target language as a second step on the transcribed text.
+
+fn translation_system_prompt(target_lang: &str) -> String {
+    format!(
+        "You are a translator. Translate the user's text into {}. Return ONLY the translated text with no explanation, no markdown formatting, no quotes around it.",
+        target_lang.trim()
+    )
+}
+
+pub fn translate_text(text: String, target_lang: String) -> Result<String, Fair9Error> {
+    if text.trim().is_empty() {
+        return Err(Fair9Error::InvalidArgument("No text to translate".to_string()));
+    }
+    if target_lang.trim().is_empty() {
+        return Err(Fair9Error::InvalidArgument("No target language provided".to_string()));
+    }
+
+    Ok(ollama_generate(OLLAMA_URL, OLLAMA_MODEL, text, translation_system_prompt(&target_lang))?)
+}
+
+// ── Transcription Stream ─────────────────────────────────────────────
+
+/// Withhold interim emissions until the transcript has at least `n` words.
+/// Finals are always emitted. 0 disables the gate.
+pub fn set_min_interim_words(n: usize) -> Result<(), Fair9Error> {
+    MIN_INTERIM_WORDS.store(n, Ordering::SeqCst);
+    Ok(())
+}
+
+fn should_emit(text: &str, is_final: bool, min_interim_words: usize) -> bool {
+    if text.is_empty() {
+        return false;
+    }
+    is_final || text.split_whitespace().count() >= min_interim_words
+}
+
+pub fn start_listening() -> Result<(), Fair9Error> {
+    COMMITTED_TEXT.lock().unwrap().clear();
+    *LISTENING_STARTED.lock().unwrap() = Some(std::time::Instant::now());
+    LISTENING_PAUSED.store(false, Ordering::SeqCst);
+    STATE.is_listening.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Stop capturing without tearing the session down: the mic stream, buffered audio
+/// and committed transcript are kept, and nothing is emitted until `resume_listening`.
+/// Pausing an already paused session does nothing.
+pub fn pause_listening() -> Result<(), Fair9Error> {
+    if !STATE.is_listening.load(Ordering::SeqCst) {
+        return Err(Fair9Error::NotListening);
+    }
+    LISTENING_PAUSED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Pick up capture where `pause_listening` left it. A no-op when not paused.
+pub fn resume_listening() -> Result<(), Fair9Error> {
+    LISTENING_PAUSED.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+fn is_paused() -> bool {
+    LISTENING_PAUSED.load(Ordering::SeqCst)
+}
+
+/// Transcript confirmed by finals since listening started
+pub fn get_committed_prefix() -> String {
+    COMMITTED_TEXT.lock().unwrap().clone()
+}
+
+/// Replace the confirmed transcript, e.g. after the user corrects it while still
+/// dictating. Later emissions append to the edited text.
+pub fn set_committed_prefix(text: String) -> Result<(), Fair9Error> {
+    *COMMITTED_TEXT.lock().unwrap() = text;
+    Ok(())
+}
+
+fn append_to_transcript(prefix: &str, text: &str) -> String {
+    match (prefix.trim_end(), text.trim()) {
+        ("", text) => text.to_string(),
+        (prefix, "") => prefix.to_string(),
+        (prefix, text) => format!("{} {}", prefix, text),
+    }
+}
+
+pub fn stop_listening() -> Result<(), Fair9Error> {
+    STATE.is_listening.store(false, Ordering::SeqCst);
+    LISTENING_PAUSED.store(false, Ordering::SeqCst);
+    let mut buffer = STATE.audio_buffer.lock().unwrap();
+    let samples = reset_buffer(&mut buffer);
+    // Nothing has been transcribed yet; hand the audio to the stream for one final pass
+    if !LIVE_PROCESSING.load(Ordering::SeqCst) && !samples.is_empty() {
+        *DEFERRED_AUDIO.lock().unwrap() = Some(samples);
+    }
+    Ok(())
+}
+
+/// Drop all buffered audio. The stream's cursor notices and starts over on
+/// whatever is captured next instead of mis-measuring against the old buffer.
+pub fn clear_audio_buffer() -> Result<(), Fair9Error> {
+    let mut buffer = STATE.audio_buffer.lock().unwrap();
+    reset_buffer(&mut buffer);
+    Ok(())
+}
+
+/// Empty the buffer and start a new cursor generation; call with the buffer lock held
+fn reset_buffer(buffer: &mut Vec<f32>) -> Vec<f32> {
+    BUFFER_POS.lock().unwrap().reset();
+    RECORDING_LIMIT_REPORTED.store(false, Ordering::SeqCst);
+    CAPTURE_BACKLOG.lock().unwrap().clear();
+    std::mem::take(buffer)
+}
+
+/// Suppress blank output at the start of decoding (whisper.cpp `suppress_blank`)
+pub fn set_suppress_blank(enabled: bool) -> Result<(), Fair9Error> {
+    SUPPRESS_BLANK.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Suppress non-speech tokens like `[MUSIC]` while decoding, at the source
+pub fn set_suppress_non_speech(enabled: bool) -> Result<(), Fair9Error> {
+    SUPPRESS_NON_SPEECH.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Language codes whisper.cpp knows. Kept as statics because FullParams borrows the code.
+const WHISPER_LANGUAGES: &[&str] = &[
+    "en", "zh", "de", "es", "ru", "ko", "fr", "ja", "pt", "tr", "pl", "ca", "nl", "ar", "sv", "it",
+    "id", "hi", "fi", "vi", "he", "uk", "el", "ms", "cs", "ro", "da", "hu", "ta", "no", "th", "ur",
+    "hr", "bg", "lt", "la", "mi", "ml", "cy", "sk", "te", "fa", "lv", "bn", "sr", "az", "sl", "kn",
+    "et", "mk", "br", "eu", "is", "hy", "ne", "mn", "bs", "kk", "sq", "sw", "gl", "mr", "pa", "si",
+    "km", "sn", "yo", "so", "af", "oc", "ka", "be", "tg", "sd", "gu", "am", "yi", "lo", "uz", "fo",
+    "ht", "ps", "tk", "nn", "mt", "sa", "lb", "my", "bo", "tl", "mg", "as", "tt", "haw", "ln",
+    "ha", "ba", "jw", "su", "yue",
+];
+const DEFAULT_LANGUAGE: &str = "en";
+
+/// Transcription language as a whisper code ("de", "es"), or "auto" to detect it per pass
+pub fn set_language(lang: String) -> Result<(), Fair9Error> {
+    *LANGUAGE.lock().unwrap() = parse_language(&lang)?;
+    Ok(())
+}
+
+/// "auto" → None (detect); otherwise the matching static code
+fn parse_language(lang: &str) -> Result<Option<&'static str>> {
+    let lang = lang.trim().to_lowercase();
+    if lang == "auto" {
+        return Ok(None);
+    }
+    WHISPER_LANGUAGES.iter()
+        .find(|code| **code == lang)
+        .map(|code| Some(*code))
+        .ok_or_else(|| anyhow!("Unsupported language '{}' (use a whisper language code or \"auto\")", lang))
+}
+
+/// Output English text whatever the spoken language, using whisper's translate task.
+/// The source language still comes from `set_language` ("auto" detects it).
+pub fn set_translate_mode(enabled: bool) -> Result<(), Fair9Error> {
+    TRANSLATE_MODE.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+const KEYWORD_BOOST_MAX_REPEATS: usize = 3;
+const KEYWORD_PROMPT_MAX_CHARS: usize = 600; // whisper.cpp keeps roughly the last 224 prompt tokens
+
+/// Bias recognition toward `words` (product names, commands), with `weight` from 0.0
+/// to 1.0. Best-effort: whisper.cpp can't force a vocabulary, so this only primes the
+/// decoder with an initial prompt listing the words, repeated more at higher weights.
+/// whisper-rs has no safe hook for suppressing competing tokens, so nothing is
+/// suppressed. An empty list turns the boost off.
+pub fn set_keyword_boost(words: Vec<String>, weight: f32) -> Result<(), Fair9Error> {
+    if !weight.is_finite() || !(0.0..=1.0).contains(&weight) {
+        return Err(Fair9Error::InvalidArgument("Keyword boost weight must be between 0.0 and 1.0".to_string()));
+    }
+    let mut unique: Vec<String> = Vec::new();
+    for word in words.iter().map(|w| w.trim()).filter(|w| !w.is_empty()) {
+        if !unique.iter().any(|u| u.eq_ignore_ascii_case(word)) {
+            unique.push(word.to_string());
+        }
+    }
+    let prompt = keyword_prompt(&unique, weight);
+    *KEYWORD_BOOST.lock().unwrap() = (unique, weight, prompt);
+    Ok(())
+}
+
+/// "Glossary: Fair9, Grafana." once at weight 0, up to KEYWORD_BOOST_MAX_REPEATS
+/// times at weight 1, dropping repeats that would overflow the prompt window
+fn keyword_prompt(words: &[String], weight: f32) -> Option<String> {
+    if words.is_empty() {
+        return None;
+    }
+    let listing = format!("Glossary: {}.", words.join(", "));
+    let wanted = 1 + (weight * (KEYWORD_BOOST_MAX_REPEATS - 1) as f32).round() as usize;
+    let fits = (KEYWORD_PROMPT_MAX_CHARS + 1) / (listing.chars().count() + 1);
+    let repeats = wanted.min(fits).max(1);
+    Some(vec![listing; repeats].join(" "))
+}
+
+/// Snapshot of the user-tunable decoding settings, applied to every FullParams.
+/// FullParams can't be read back, so this is what tests inspect.
+#[derive(Clone, Debug, PartialEq)]
+struct DecodeOptions {
+    whisper_mode: bool,
+    suppress_blank: bool,
+    suppress_non_speech: bool,
+    thread_count: usize, // 0 = whisper.cpp default
+    max_segment_chars: i32, // 0 = no limit
+    split_on_word: bool,
+    language: Option<&'static str>, // None = auto-detect
+    translate: bool,
+    initial_prompt: Option<String>, // From set_keyword_boost
+}
+
+impl DecodeOptions {
+    fn current() -> Self {
+        DecodeOptions {
+            whisper_mode: WHISPER_MODE.load(Ordering::SeqCst),
+            suppress_blank: SUPPRESS_BLANK.load(Ordering::SeqCst),
+            suppress_non_speech: SUPPRESS_NON_SPEECH.load(Ordering::SeqCst),
+            thread_count: THREAD_COUNT.load(Ordering::SeqCst),
+            max_segment_chars: MAX_SEGMENT_CHARS.load(Ordering::SeqCst) as i32,
+            split_on_word: SPLIT_ON_WORD.load(Ordering::SeqCst),
+            language: *LANGUAGE.lock().unwrap(),
+            translate: TRANSLATE_MODE.load(Ordering::SeqCst),
+            initial_prompt: KEYWORD_BOOST.lock().unwrap().2.clone(),
+        }
+    }
+
+    fn apply(&self, params: &mut FullParams) {
+        // Whisper Mode hacks
+        if self.whisper_mode {
+            params.set_no_speech_thold(0.1); // High sensitivity
+            // params.set_temperature(0.0);
+        }
+        params.set_suppress_blank(self.suppress_blank);
+        params.set_suppress_non_speech_tokens(self.suppress_non_speech);
+        if self.thread_count > 0 {
+            params.set_n_threads(self.thread_count as i32);
+        }
+        if self.max_segment_chars > 0 {
+            // whisper.cpp only honours max_len with token-level timestamps
+            params.set_token_timestamps(true);
+            params.set_max_len(self.max_segment_chars);
+        }
+        if self.split_on_word {
+            params.set_split_on_word(true);
+        }
+        params.set_language(self.language);
+        params.set_translate(self.translate);
+        if let Some(prompt) = &self.initial_prompt {
+            params.set_initial_prompt(prompt);
+        }
+    }
+}
+
+/// Decoder search used for a pass. Beam search is slower but more accurate.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecodeStrategy {
+    Greedy,
+    Beam,
+}
+
+impl DecodeStrategy {
+    fn parse(name: &str) -> Result<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "greedy" => Ok(DecodeStrategy::Greedy),
+            "beam" => Ok(DecodeStrategy::Beam),
+            other => Err(anyhow!("Unknown decode strategy '{}' (expected greedy or beam)", other)),
+        }
+    }
+
+    fn sampling(self) -> SamplingStrategy {
+        match self {
+            DecodeStrategy::Greedy => SamplingStrategy::Greedy { best_of: 1 },
+            DecodeStrategy::Beam => SamplingStrategy::BeamSearch { beam_size: BEAM_SIZE, patience: -1.0 },
+        }
+    }
+}
+
+/// Strategy for interim re-transcriptions: "greedy" (default) keeps live captions snappy
+pub fn set_interim_strategy(strategy: String) -> Result<(), Fair9Error> {
+    *INTERIM_STRATEGY.lock().unwrap() = DecodeStrategy::parse(&strategy)?;
+    Ok(())
+}
+
+/// Strategy for final and batch transcriptions: "beam" (default) runs once per utterance,
+/// so it can spend the latency on accuracy; "greedy" suits machines where it's too slow
+pub fn set_final_strategy(strategy: String) -> Result<(), Fair9Error> {
+    *FINAL_STRATEGY.lock().unwrap() = DecodeStrategy::parse(&strategy)?;
+    Ok(())
+}
+
+fn strategy_for(is_final: bool) -> DecodeStrategy {
+    if is_final {
+        *FINAL_STRATEGY.lock().unwrap()
+    } else {
+        *INTERIM_STRATEGY.lock().unwrap()
+    }
+}
+
+/// Parameters for final-quality decoding (finals, batch and file transcription)
+fn build_full_params<'a, 'b>() -> FullParams<'a, 'b> {
+    build_params_for(true)
+}
+
+fn build_params_for<'a, 'b>(is_final: bool) -> FullParams<'a, 'b> {
+    let mut params = FullParams::new(strategy_for(is_final).sampling());
+    params.set_print_special(false);
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_timestamps(false);
+    DecodeOptions::current().apply(&mut params);
+    params
+}
+
+/// Whisper-mode preprocessing: one-pole high-pass, then +15dB gain clamped to full scale
+fn whisper_mode_dsp(samples: &[f32]) -> Vec<f32> {
+    let rc = 1.0 / (2.0 * std::f32::consts::PI * WHISPER_MODE_HIGHPASS_HZ);
+    let dt = 1.0 / SAMPLE_RATE as f32;
+    let alpha = rc / (rc + dt);
+    let gain = 10f32.powf(WHISPER_MODE_GAIN_DB / 20.0);
+
+    let mut out = Vec::with_capacity(samples.len());
+    let (mut prev_in, mut prev_out) = (0.0f32, 0.0f32);
+    for (i, &x) in samples.iter().enumerate() {
+        // Seed with the first sample so a DC offset doesn't start as a step
+        if i == 0 {
+            prev_in = x;
+        }
+        let y = alpha * (prev_out + x - prev_in);
+        prev_in = x;
+        prev_out = y;
+        out.push((y * gain).clamp(-1.0, 1.0));
+    }
+    out
+}
+
+/// Raw segment text of one model run and how sure the model was about it
+struct Decoded {
+    text: String,
+    confidence: Option<f32>, // Mean token probability; None when nothing was decoded
+    segments: Vec<Segment>,
+    tokens: Vec<(String, f32)>, // Every token with its probability, special ones included
+}
+
+/// Run the model over 16kHz mono samples and return the raw segment text.
+/// Not counted in the metrics; see `counted`.
+fn run_whisper(ctx: &WhisperContext, samples: &[f32], is_final: bool) -> Result<Decoded> {
+    if WHISPER_MODE_DSP.load(Ordering::SeqCst) {
+        return decode_segments(ctx, build_params_for(is_final), &whisper_mode_dsp(samples));
+    }
+    decode_segments(ctx, build_params_for(is_final), samples)
+}
+
+/// Count a finished transcription (a final, or a batch run) in the metrics.
+/// Interims, peeks and benchmarks stay out, so each utterance is one outcome.
+fn counted(result: Result<Decoded>) -> Result<Decoded> {
+    record_transcription_outcome(result.as_ref().ok().map(|d| d.text.as_str()));
+    result
+}
+
+fn decode_segments(ctx: &WhisperContext, params: FullParams, samples: &[f32]) -> Result<Decoded> {
+    let mut state = ctx.create_state().context("failed to create state")?;
+    run_full(&mut state, params, samples)?;
+
+    let num_segments = state.full_n_segments().context("failed to get segments")?;
+    let mut text = String::new();
+    let mut tokens = Vec::new();
+    let mut segments = Vec::new();
+    for i in 0..num_segments {
+        if let Ok(segment) = state.full_get_segment_text(i) {
+            text.push_str(&segment);
+            text.push(' ');
+            if let (Ok(t0), Ok(t1)) = (state.full_get_segment_t0(i), state.full_get_segment_t1(i)) {
+                // whisper.cpp timestamps are in 10ms units
+                segments.push(Segment { text: segment, start_ms: t0 * 10, end_ms: t1 * 10 });
+            }
+        }
+        for tok in 0..state.full_n_tokens(i).unwrap_or(0) {
+            if let (Ok(token), Ok(p)) = (state.full_get_token_text(i, tok), state.full_get_token_prob(i, tok)) {
+                tokens.push((token, p));
+            }
+        }
+    }
+    Ok(Decoded { text, confidence: mean_token_confidence(&tokens), segments, tokens })
+}
+
+/// `state.full` with its wall time counted toward the inference duty cycle
+fn run_full(state: &mut WhisperState, params: FullParams, samples: &[f32]) -> Result<()> {
+    let start = std::time::Instant::now();
+    let result = state.full(params, samples);
+    INFERENCE_DUTY.lock().unwrap().record(start, start.elapsed());
+    result.context("failed to run model")?;
+    Ok(())
+}
+
+/// Share of the last minute (or of the time since the first run, if shorter)
+/// spent inside the model, 0–1. A power-aware UI can suggest batch mode or a
+/// smaller model when this stays high on battery.
+pub fn get_inference_duty_cycle() -> f32 {
+    INFERENCE_DUTY.lock().unwrap().ratio(std::time::Instant::now())
+}
+
+/// Model runs within the trailing DUTY_CYCLE_WINDOW
+#[derive(Default)]
+struct DutyCycle {
+    first_run: Option<std::time::Instant>,
+    runs: std::collections::VecDeque<(std::time::Instant, std::time::Duration)>,
+}
+
+impl DutyCycle {
+    fn record(&mut self, start: std::time::Instant, took: std::time::Duration) {
+        self.first_run.get_or_insert(start);
+        self.runs.push_back((start, took));
+        while self.runs.front().map_or(false, |(s, d)| start.saturating_duration_since(*s + *d) > DUTY_CYCLE_WINDOW) {
+            self.runs.pop_front();
+        }
+    }
+
+    fn ratio(&self, now: std::time::Instant) -> f32 {
+        let Some(first_run) = self.first_run else {
+            return 0.0;
+        };
+        let window_start = now.checked_sub(DUTY_CYCLE_WINDOW).map_or(first_run, |w| w.max(first_run));
+        let wall = now.saturating_duration_since(window_start);
+        if wall.is_zero() {
+            return 0.0;
+        }
+        // Only the part of each run that falls inside the window counts
+        let busy: std::time::Duration = self.runs.iter()
+            .map(|(start, took)| (*start + *took).min(now).saturating_duration_since((*start).max(window_start)))
+            .sum();
+        (busy.as_secs_f32() / wall.as_secs_f32()).min(1.0)
+    }
+}
+
+/// Average probability of the spoken tokens, ignoring special/timestamp tokens
+fn mean_token_confidence(tokens: &[(String, f32)]) -> Option<f32> {
+    let probs: Vec<f32> = tokens.iter()
+        .filter(|(text, _)| !is_special_token(text))
+        .map(|(_, p)| *p)
+        .collect();
+    if probs.is_empty() {
+        return None;
+    }
+    Some(probs.iter().sum::<f32>() / probs.len() as f32)
+}
+
+fn is_special_token(text: &str) -> bool {
+    text.starts_with("[_") || text.starts_with("<|") || text.trim().is_empty()
+}
+
+/// Outcome counts for finished transcriptions, for spotting systemic failures on a machine
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TranscriptionMetrics {
+    pub successes: u64,
+    pub failures: u64,
+    pub no_speech: u64, // Ran fine but produced nothing besides non-speech tags
+}
+
+/// `text` is the decoded output, or None when the run failed
+fn record_transcription_outcome(text: Option<&str>) {
+    let mut metrics = TRANSCRIPTION_METRICS.lock().unwrap();
+    match text {
+        Some(text) if strip_nonspeech_tags(text).trim().is_empty() => metrics.no_speech += 1,
+        Some(_) => metrics.successes += 1,
+        None => metrics.failures += 1,
+    }
+    METRICS_DIRTY.store(true, Ordering::SeqCst);
+}
+
+pub fn get_transcription_metrics() -> TranscriptionMetrics {
+    TRANSCRIPTION_METRICS.lock().unwrap().clone()
+}
+
+pub fn reset_transcription_metrics() -> Result<(), Fair9Error> {
+    *TRANSCRIPTION_METRICS.lock().unwrap() = TranscriptionMetrics::default();
+    METRICS_DIRTY.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Keep metrics across sessions in metrics.json (written with other pending writes)
+pub fn set_persist_metrics(enabled: bool) -> Result<(), Fair9Error> {
+    PERSIST_METRICS.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+fn get_metrics_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("metrics.json"))
+}
+
+/// Restore persisted metrics; a missing or unreadable file starts from zero
+pub fn load_transcription_metrics() -> Result<TranscriptionMetrics, Fair9Error> {
+    let path = get_metrics_path()?;
+    let metrics: TranscriptionMetrics = if path.exists() {
+        serde_json::from_str(&fs::read_to_string(&path)?).unwrap_or_default()
+    } else {
+        TranscriptionMetrics::default()
+    };
+    *TRANSCRIPTION_METRICS.lock().unwrap() = metrics.clone();
+    METRICS_DIRTY.store(false, Ordering::SeqCst);
+    Ok(metrics)
+}
+
+fn save_transcription_metrics() -> Result<()> {
+    let path = get_metrics_path()?;
+    let data = serde_json::to_string_pretty(&*TRANSCRIPTION_METRICS.lock().unwrap())?;
+    write_data_file(&path, data, "metrics")?;
+    METRICS_DIRTY.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Split segments longer than `n` characters (readable captions/timestamps). 0 disables.
+pub fn set_max_segment_chars(n: i32) -> Result<(), Fair9Error> {
+    if n < 0 {
+        return Err(Fair9Error::InvalidArgument("Max segment length cannot be negative".to_string()));
+    }
+    MAX_SEGMENT_CHARS.store(n as usize, Ordering::SeqCst);
+    Ok(())
+}
+
+/// When splitting long segments, break on word boundaries instead of tokens
+pub fn set_split_on_word(enabled: bool) -> Result<(), Fair9Error> {
+    SPLIT_ON_WORD.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Whisper inference threads; 0 restores the whisper.cpp default
+pub fn set_thread_count(n: u32) -> Result<(), Fair9Error> {
+    THREAD_COUNT.store(n as usize, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Time a short transcription at a few thread counts and return the fastest.
+/// The result is cached for the process lifetime; it does not change the setting,
+/// pass it to `set_thread_count` to apply it.
+pub fn recommend_thread_count() -> Result<u32, Fair9Error> {
+    if let Some(cached) = *RECOMMENDED_THREADS.lock().unwrap() {
+        return Ok(cached);
+    }
+
+    let guard = lock_model(true)?;
+    let ctx = guard.as_ref().ok_or(Fair9Error::ModelNotLoaded)?;
+
+    // 2s of a quiet tone: enough to exercise the encoder without real speech
+    let samples: Vec<f32> = (0..SAMPLE_RATE * 2)
+        .map(|i| (i as f32 * 440.0 * 2.0 * std::f32::consts::PI / SAMPLE_RATE as f32).sin() * 0.1)
+        .collect();
+
+    let mut timings = Vec::new();
+    for threads in thread_count_candidates() {
+        let mut params = build_full_params();
+        params.set_n_threads(threads as i32);
+        let start = std::time::Instant::now();
+        decode_segments(ctx, params, &samples)?;
+        timings.push((threads, start.elapsed()));
+    }
+
+    let best = pick_fastest(&timings).ok_or_else(|| anyhow!("No thread counts to benchmark"))?;
+    *RECOMMENDED_THREADS.lock().unwrap() = Some(best);
+    Ok(best)
+}
+
+fn thread_count_candidates() -> Vec<u32> {
+    let cores = thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(4);
+    let mut candidates = vec![2, 4, cores / 2, cores];
+    candidates.retain(|&n| n >= 1 && n <= cores);
+    candidates.sort_unstable();
+    candidates.dedup();
+    candidates
+}
+
+/// Fastest timing wins; ties go to the smaller thread count
+fn pick_fastest(timings: &[(u32, std::time::Duration)]) -> Option<u32> {
+    timings
+        .iter()
+        .min_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)))
+        .map(|(threads, _)| *threads)
+}
+
+/// One recognised word with its timing, for read-along highlighting
+#[derive(Clone, Debug, PartialEq)]
+pub struct Word {
+    pub text: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+}
+
+/// One decoded segment with its timing
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub text: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+}
+
+/// Words per minute over the spoken stretch: from the first segment with words to
+/// the last, so silence (or `[BLANK_AUDIO]`) before and after doesn't drag it down
+fn speaking_rate_wpm(segments: &[Segment]) -> f32 {
+    let spoken: Vec<(&Segment, usize)> = segments.iter()
+        .map(|seg| (seg, strip_nonspeech_tags(&seg.text).split_whitespace().count()))
+        .filter(|(_, words)| *words > 0)
+        .collect();
+    let (Some((first, _)), Some((last, _))) = (spoken.first(), spoken.last()) else {
+        return 0.0;
+    };
+    let duration_ms = last.end_ms - first.start_ms;
+    if duration_ms <= 0 {
+        return 0.0;
+    }
+    let words: usize = spoken.iter().map(|(_, words)| words).sum();
+    words as f32 * 60_000.0 / duration_ms as f32
+}
+
+/// Speaking rate of the most recent batch transcription, for speaking-practice feedback
+pub fn last_speaking_rate() -> Option<f32> {
+    *LAST_SPEAKING_RATE.lock().unwrap()
+}
+
+/// Transcribe 16kHz mono samples with per-word timings
+pub fn transcribe_word_timestamps(samples: Vec<f32>) -> Result<Vec<Word>, Fair9Error> {
+    let guard = lock_model(true)?;
+    let ctx = guard.as_ref().ok_or(Fair9Error::ModelNotLoaded)?;
+
+    let words = decode_word_timestamps(ctx, &samples);
+    let text = words.as_ref().ok().map(|words| words.iter().map(|w| w.text.as_str()).collect::<Vec<_>>().join(" "));
+    record_transcription_outcome(text.as_deref());
+    Ok(words?)
+}
+
+fn decode_word_timestamps(ctx: &WhisperContext, samples: &[f32]) -> Result<Vec<Word>> {
+    let mut params = build_full_params();
+    params.set_token_timestamps(true);
+    let mut state = ctx.create_state().context("failed to create state")?;
+    run_full(&mut state, params, samples)?;
+
+    let mut tokens = Vec::new();
+    let num_segments = state.full_n_segments().context("failed to get segments")?;
+    for seg in 0..num_segments {
+        let num_tokens = state.full_n_tokens(seg).context("failed to get tokens")?;
+        for tok in 0..num_tokens {
+            let (Ok(text), Ok(data)) = (state.full_get_token_text(seg, tok), state.full_get_token_data(seg, tok)) else {
+                continue;
+            };
+            // whisper.cpp timestamps are in 10ms units
+            tokens.push((text, (data.t0 * 10, data.t1 * 10)));
+        }
+    }
+    Ok(merge_tokens_into_words(&tokens))
+}
+
+/// Tokens are sub-word pieces: a leading space starts a new word, anything else
+/// continues the previous one. Special tokens (`[_BEG_]`, `<|endoftext|>`) are skipped.
+/// Each word comes with the data of the tokens it was built from.
+fn group_tokens_into_words<T: Copy>(tokens: &[(String, T)]) -> Vec<(String, Vec<T>)> {
+    let mut words: Vec<(String, Vec<T>)> = Vec::new();
+    for (text, data) in tokens {
+        if is_special_token(text) {
+            continue;
+        }
+        match words.last_mut() {
+            Some((word, pieces)) if !text.starts_with(' ') => {
+                word.push_str(text);
+                pieces.push(*data);
+            }
+            _ => words.push((text.trim().to_string(), vec![*data])),
+        }
+    }
+    words
+}
+
+/// Words from `(t0, t1)`-timed tokens, with starts clamped so words never overlap
+fn merge_tokens_into_words(tokens: &[(String, (i64, i64))]) -> Vec<Word> {
+    let mut words: Vec<Word> = Vec::new();
+    for (text, pieces) in group_tokens_into_words(tokens) {
+        let prev_end = words.last().map_or(0, |w| w.end_ms);
+        let start_ms = pieces[0].0.max(prev_end);
+        let end_ms = pieces.iter().map(|(_, t1)| *t1).fold(start_ms, i64::max);
+        words.push(Word { text, start_ms, end_ms });
+    }
+    words
+}
+
+/// Transcribe 16kHz mono samples with the model's probability for each word, as a
+/// JSON array of `{"word", "probability"}`. Raw model output: no filler removal or
+/// snippets, and what counts as low confidence is up to the caller.
+pub fn transcribe_with_confidence(samples: Vec<f32>) -> Result<String, Fair9Error> {
+    let mut guard = lock_model(true)?;
+    let decoded = run_whisper_or_fall_back(&mut guard, &samples, true)?;
+    let words: Vec<serde_json::Value> = word_probabilities(&decoded.tokens)
+        .into_iter()
+        .map(|(word, probability)| json!({ "word": word, "probability": probability }))
+        .collect();
+    Ok(serde_json::to_string(&words)?)
+}
+
+/// A word is only as certain as its least likely piece, so it takes the lowest
+/// token probability
+fn word_probabilities(tokens: &[(String, f32)]) -> Vec<(String, f32)> {
+    group_tokens_into_words(tokens)
+        .into_iter()
+        .map(|(word, probs)| (word, probs.into_iter().fold(f32::INFINITY, f32::min)))
+        .collect()
+}
+
+/// Post-processing shared by every transcription path (tags, fillers, user transforms, profanity)
+fn clean_transcript(raw: &str) -> String {
+    let text = if STRIP_NONSPEECH_TAGS.load(Ordering::SeqCst) {
+        strip_nonspeech_tags(raw)
+    } else {
+        raw.to_string()
+    };
+    let text = if DEDUP_REPEATS.load(Ordering::SeqCst) {
+        collapse_repeats(&text, MAX_REPEATS.load(Ordering::SeqCst))
+    } else {
+        text
+    };
+    let text = clean_filler_words(text.trim());
+    let text = if STRIP_TRAILING_PUNCT.load(Ordering::SeqCst) {
+        strip_trailing_punct(&text).to_string()
+    } else {
+        text
+    };
+    filter_profanity(apply_text_transforms(spoken_punctuation(text)))
+}
+
+/// Drop the `.`, `?` or `!` Whisper ends a transcript with, for dictating into code
+/// or search boxes. Punctuation inside the text, and a spoken "period", stay.
+pub fn set_strip_trailing_punct(enabled: bool) -> Result<(), Fair9Error> {
+    STRIP_TRAILING_PUNCT.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+fn strip_trailing_punct(text: &str) -> &str {
+    let Some(stripped) = text.strip_suffix(['.', '?', '!']) else {
+        return text;
+    };
+    // "Wait..." is an ellipsis, not a sentence end
+    if stripped.ends_with('.') {
+        return text;
+    }
+    stripped.trim_end()
+}
+
+/// Register the sink for `EngineEvent`s. Replaces any previous sink.
+pub fn create_event_stream(sink: StreamSink<EngineEvent>) -> Result<(), Fair9Error> {
+    *EVENT_SINK.lock().unwrap() = Some(sink);
+    Ok(())
+}
+
+fn emit_event(event: EngineEvent) {
+    if let Some(sink) = EVENT_SINK.lock().unwrap().as_ref() {
+        sink.add(event);
+    }
+}
+
+/// Clipped-sample ratio (0.0-1.0) above which `ClippingDetected` is emitted
+pub fn set_clip_warn_ratio(r: f32) -> Result<(), Fair9Error> {
+    if !r.is_finite() || !(0.0..=1.0).contains(&r) {
+        return Err(Fair9Error::InvalidArgument("Clip warning ratio must be between 0.0 and 1.0".to_string()));
+    }
+    CLIP_WARN_RATIO.store(r.to_bits(), Ordering::SeqCst);
+    Ok(())
+}
+
+/// Counts near-full-scale samples over fixed windows of captured audio
+#[derive(Default)]
+struct ClipMonitor {
+    seen: usize,
+    clipped: usize,
+}
+
+impl ClipMonitor {
+    /// Feed captured samples; returns the clip ratio when a completed window exceeds `warn_ratio`
+    fn observe(&mut self, data: &[f32], warn_ratio: f32) -> Option<f32> {
+        self.seen += data.len();
+        self.clipped += data.iter().filter(|s| s.abs() >= CLIP_LEVEL).count();
+        if self.seen < CLIP_WINDOW_SAMPLES {
+            return None;
+        }
+
+        let ratio = self.clipped as f32 / self.seen as f32;
+        self.seen = 0;
+        self.clipped = 0;
+        (ratio > warn_ratio).then_some(ratio)
+    }
+}
+
+/// Hard cap on buffered audio in case inference falls behind (or no model is loaded).
+/// The oldest samples are dropped first. Batch recordings aren't drained as they go,
+/// so they have their own 30 minute limit instead.
+pub fn set_max_buffer_ms(ms: u64) -> Result<(), Fair9Error> {
+    let samples = (ms as usize * SAMPLE_RATE) / 1000;
+    if samples < FINAL_WINDOW_SAMPLES {
+        return Err(Fair9Error::InvalidArgument(format!("Buffer cap must be at least {} ms", FINAL_WINDOW_SAMPLES * 1000 / SAMPLE_RATE)));
+    }
+    MAX_BUFFER_SAMPLES.store(samples, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Returns how many of the oldest samples were dropped to stay under `cap`
+fn push_capped(buffer: &mut Vec<f32>, data: &[f32], cap: usize) -> usize {
+    buffer.extend_from_slice(data);
+    if buffer.len() > cap {
+        let excess = buffer.len() - cap;
+        buffer.drain(..excess);
+        return excess;
+    }
+    0
+}
+
+/// Where the shared buffer sits in the session's audio: `start` is the absolute
+/// sample index of `buffer[0]`, and `generation` changes whenever the buffer is
+/// emptied outright, so cursors can tell a trim from a fresh start.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct BufferPos {
+    generation: usize,
+    start: usize,
+}
+
+impl BufferPos {
+    /// `n` samples were removed from the front
+    fn advance(&mut self, n: usize) {
+        self.start += n;
+    }
+
+    fn reset(&mut self) {
+        self.generation += 1;
+        self.start = 0;
+    }
+}
+
+/// Sliding-window bookkeeping for the streaming loop. Interim passes re-transcribe
+/// the growing window; once it reaches FINAL_WINDOW_SAMPLES the window is committed
+/// as a final and exactly those samples are drained, so memory stays bounded to
+/// roughly one window regardless of session length.
+#[derive(Default)]
+struct StreamWindow {
+    generation: usize,
+    // Absolute end of the audio covered by the last pass, so unchanged audio isn't re-run
+    processed_to: usize,
+}
+
+impl StreamWindow {
+    fn next_pass(&mut self, buffer: &mut Vec<f32>, pos: &mut BufferPos) -> Option<(Vec<f32>, bool)> {
+        // Cleared, or trimmed past everything we'd seen: pick up from the current buffer
+        if pos.generation != self.generation || self.processed_to < pos.start {
+            self.generation = pos.generation;
+            self.processed_to = pos.start;
+        }
+
+        if buffer.len() >= FINAL_WINDOW_SAMPLES {
+            let committed: Vec<f32> = buffer.drain(..).collect();
+            pos.advance(committed.len());
+            self.processed_to = pos.start;
+            Some((committed, true))
+        } else if buffer.len() >= MIN_INTERIM_SAMPLES && pos.start + buffer.len() > self.processed_to {
+            self.processed_to = pos.start + buffer.len();
+            Some((buffer.clone(), false))
+        } else {
+            None
+        }
+    }
+}
+
+/// Audio backends compiled in and usable on this machine (e.g. "WASAPI", "ASIO")
+pub fn list_audio_hosts() -> Vec<String> {
+    cpal::available_hosts().iter().map(|id| id.name().to_string()).collect()
+}
+
+/// Record through a specific audio host instead of the platform default.
+/// Takes effect the next time capture starts; empty restores the default.
+pub fn set_audio_host(name: String) -> Result<(), Fair9Error> {
+    let name = name.trim();
+    *AUDIO_HOST.lock().unwrap() = (!name.is_empty()).then(|| name.to_string());
+    Ok(())
+}
+
+/// The chosen host, or the default when none is chosen or it can't be opened
+fn selected_host() -> cpal::Host {
+    let Some(name) = AUDIO_HOST.lock().unwrap().clone() else {
+        return cpal::default_host();
+    };
+    let Some(id) = cpal::available_hosts().into_iter().find(|id| id.name().eq_ignore_ascii_case(&name)) else {
+        eprintln!("audio host '{}' is not available, using the default", name);
+        return cpal::default_host();
+    };
+    cpal::host_from_id(id).unwrap_or_else(|e| {
+        eprintln!("failed to open audio host '{}', using the default: {}", name, e);
+        cpal::default_host()
+    })
+}
+
+/// Names of the microphones on the selected audio host
+pub fn list_input_devices() -> Vec<String> {
+    input_device_names(&selected_host())
+}
+
+fn input_device_names(host: &cpal::Host) -> Vec<String> {
+    match host.input_devices() {
+        Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+        Err(e) => {
+            eprintln!("failed to list input devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Record from a specific microphone instead of the system default. Takes effect
+/// the next time capture starts; empty restores the default.
+pub fn set_input_device(name: String) -> Result<(), Fair9Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        *INPUT_DEVICE.lock().unwrap() = None;
+        return Ok(());
+    }
+    let available = list_input_devices();
+    if !available.iter().any(|d| d.eq_ignore_ascii_case(name)) {
+        return Err(Fair9Error::InputDeviceNotFound { name: name.to_string(), available: available.join(", ") });
+    }
+    *INPUT_DEVICE.lock().unwrap() = Some(name.to_string());
+    Ok(())
+}
+
+/// The chosen microphone, or the default when none is chosen or it's been unplugged
+fn selected_input_device(host: &cpal::Host) -> Result<cpal::Device> {
+    if let Some(name) = INPUT_DEVICE.lock().unwrap().clone() {
+        let found = host.input_devices().ok()
+            .and_then(|mut devices| devices.find(|d| d.name().map_or(false, |n| n.eq_ignore_ascii_case(&name))));
+        match found {
+            Some(device) => return Ok(device),
+            None => eprintln!("input device '{}' is not available, using the default", name),
+        }
+    }
+    host.default_input_device().ok_or_else(|| Fair9Error::NoInputDevice.into())
+}
+
+/// OS-level microphone access: "granted", "denied", "undetermined" or "unsupported"
+/// (platforms without a permission model). Lets the UI explain a denial up front
+/// instead of surfacing a cryptic stream error.
+pub fn check_microphone_permission() -> String {
+    microphone_permission().to_string()
+}
+
+#[cfg(target_os = "windows")]
+fn microphone_permission() -> &'static str {
+    use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+    const CONSENT_KEY: &str =
+        r"Software\Microsoft\Windows\CurrentVersion\CapabilityAccessManager\ConsentStore\microphone";
+    let value: std::io::Result<String> = RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey(CONSENT_KEY)
+        .and_then(|key| key.get_value("Value"));
+    match value {
+        Ok(value) => consent_status(&value),
+        Err(_) => "undetermined", // Never asked, or an older Windows without the consent store
+    }
+}
+
+#[cfg(target_os = "macos")]
+#[link(name = "AVFoundation", kind = "framework")]
+extern "C" {
+    static AVMediaTypeAudio: *mut objc::runtime::Object;
+}
+
+#[cfg(target_os = "macos")]
+fn microphone_permission() -> &'static str {
+    use objc::{class, msg_send, sel, sel_impl};
+    // AVAuthorizationStatus: 0 not determined, 1 restricted, 2 denied, 3 authorized
+    let status: isize = unsafe {
+        msg_send![class!(AVCaptureDevice), authorizationStatusForMediaType: AVMediaTypeAudio]
+    };
+    match status {
+        3 => "granted",
+        1 | 2 => "denied",
+        _ => "undetermined",
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn microphone_permission() -> &'static str {
+    "unsupported"
+}
+
+/// The microphone consent store's `Value`: "Allow" or "Deny"
+#[cfg(any(target_os = "windows", test))]
+fn consent_status(value: &str) -> &'static str {
+    match value.trim() {
+        v if v.eq_ignore_ascii_case("Allow") => "granted",
+        v if v.eq_ignore_ascii_case("Deny") => "denied",
+        _ => "undetermined",
+    }
+}
+
+/// Open and start the default input device, feeding each callback's samples to `on_samples`.
+/// The returned stream must stay alive (on the calling thread) for capture to continue.
+fn open_input_stream(on_samples: impl FnMut(&[f32]) + Send + 'static) -> Result<cpal::Stream> {
+    let host = selected_host();
+    let device = selected_input_device(&host)?;
+    let config = device.default_input_config().context("Failed to get default input config")?;
+    let format = config.sample_format();
+    let config: cpal::StreamConfig = config.into();
+
+    // Many devices only offer integer formats; everything is normalized to f32 here
+    let stream = match format {
+        cpal::SampleFormat::F32 => build_capture_stream(&device, &config, on_samples, |s: f32| s),
+        cpal::SampleFormat::I16 => build_capture_stream(&device, &config, on_samples, i16_to_f32),
+        cpal::SampleFormat::U16 => build_capture_stream(&device, &config, on_samples, u16_to_f32),
+        other => return Err(anyhow!(
+            "Input device uses the {:?} sample format, which isn't supported (f32, i16 and u16 are)", other
+        )),
+    }?;
+
+    stream.play().context("Failed to play stream")?;
+    Ok(stream)
+}
+
+fn build_capture_stream<T: cpal::SizedSample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut on_samples: impl FnMut(&[f32]) + Send + 'static,
+    to_f32: fn(T) -> f32,
+) -> Result<cpal::Stream> {
+    let channels = config.channels as usize;
+    let mut resampler = StreamResampler::new(config.sample_rate.0, SAMPLE_RATE as u32);
+    let err_fn = move |err| {
+        eprintln!("an error occurred on stream: {}", err);
+    };
+
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &_| {
+            let samples: Vec<f32> = data.iter().map(|&s| to_f32(s)).collect();
+            on_samples(&capture_to_model_input(&mut resampler, &samples, channels))
+        },
+        err_fn,
+        None // Timeout
+    ).context("Failed to build input stream")
+}
+
+fn i16_to_f32(s: i16) -> f32 {
+    s as f32 / 32768.0
+}
+
+/// Unsigned samples are centred on 32768
+fn u16_to_f32(s: u16) -> f32 {
+    (s as f32 - 32768.0) / 32768.0
+}
+
+/// Devices deliver interleaved audio at their own rate (often 44.1 or 48kHz stereo);
+/// this is where live capture joins the 16kHz mono domain (see SAMPLE_RATE)
+fn capture_to_model_input(resampler: &mut StreamResampler, data: &[f32], channels: usize) -> Vec<f32> {
+    resampler.process(&downmix_to_mono(data, channels))
+}
+
+/// Capture losses since launch: samples the callback had to discard, and how
+/// many times that happened
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AudioHealth {
+    pub dropped_frames: u64,
+    pub xruns: u64,
+}
+
+/// Explains transcriptions that are worse than the audio should allow
+pub fn get_audio_health() -> AudioHealth {
+    AudioHealth {
+        dropped_frames: DROPPED_FRAMES.load(Ordering::SeqCst),
+        xruns: XRUNS.load(Ordering::SeqCst),
+    }
+}
+
+/// Append captured audio to the shared buffer and watch it for clipping.
+/// Never blocks on the buffer: while a pass holds it, audio waits in the backlog.
+fn capture_samples(data: &[f32]) {
+    match STATE.audio_buffer.try_lock() {
+        Ok(mut buffer) => {
+            let (cap, whole_take) = buffer_cap();
+            let backlog = std::mem::take(&mut *CAPTURE_BACKLOG.lock().unwrap());
+            let dropped = push_capped(&mut buffer, &backlog, cap) + push_capped(&mut buffer, data, cap);
+            if dropped > 0 {
+                BUFFER_POS.lock().unwrap().advance(dropped);
+                if whole_take && !RECORDING_LIMIT_REPORTED.swap(true, Ordering::SeqCst) {
+                    eprintln!("recording passed {} samples; dropping the oldest audio", cap);
+                    emit_event(EngineEvent::RecordingLimitReached { max_seconds: (cap / SAMPLE_RATE) as u32 });
+                }
+            }
+        }
+        Err(_) => {
+            let lost = stash_backlog(&mut CAPTURE_BACKLOG.lock().unwrap(), data, CAPTURE_BACKLOG_SAMPLES);
+            record_dropped_frames(lost);
+        }
+    }
+
+    let warn_ratio = f32::from_bits(CLIP_WARN_RATIO.load(Ordering::SeqCst));
+    if let Some(ratio) = CLIP_MONITOR.lock().unwrap().observe(data, warn_ratio) {
+        emit_event(EngineEvent::ClippingDetected { ratio });
+    }
+}
+
+/// Sample cap for the shared buffer, and whether it holds a take that is transcribed
+/// whole. Streaming passes drain the buffer as they go, so `MAX_BUFFER_SAMPLES` only
+/// guards against inference falling behind; a batch recording, or a live session with
+/// live processing off, is never drained and gets the much larger `MAX_RECORDING_SAMPLES`.
+fn buffer_cap() -> (usize, bool) {
+    if BATCH_RECORDING.load(Ordering::SeqCst) || !LIVE_PROCESSING.load(Ordering::SeqCst) {
+        (MAX_RECORDING_SAMPLES, true)
+    } else {
+        (MAX_BUFFER_SAMPLES.load(Ordering::SeqCst), false)
+    }
+}
+
+/// Queue `data` behind the locked buffer; returns how many samples didn't fit
+fn stash_backlog(backlog: &mut Vec<f32>, data: &[f32], cap: usize) -> usize {
+    let room = cap.saturating_sub(backlog.len()).min(data.len());
+    backlog.extend_from_slice(&data[..room]);
+    data.len() - room
+}
+
+fn record_dropped_frames(n: usize) {
+    if n > 0 {
+        DROPPED_FRAMES.fetch_add(n as u64, Ordering::SeqCst);
+        XRUNS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+pub fn create_transcription_stream(sink: StreamSink<TranscriptionResult>) -> Result<(), Fair9Error> {
+    Ok(spawn_transcription_stream(move |result| {
+        sink.add(result);
+    })?)
+}
+
+/// Callback flavour of `create_transcription_stream` for non-Flutter consumers
+/// (a CLI printing to stdout, tests). `on_text` receives the text of each emission.
+pub fn create_transcription_stream_cb(on_text: impl Fn(String) + Send + 'static) -> Result<(), Fair9Error> {
+    Ok(spawn_transcription_stream(text_only(on_text))?)
+}
+
+fn text_only(on_text: impl Fn(String)) -> impl Fn(TranscriptionResult) {
+    move |result| on_text(result.text)
+}
+
+/// Best guess at what's been said so far, from one model run over a snapshot of
+/// the live buffer. Read-only: the buffer, stream window, committed text and
+/// metrics are left alone, so it's safe to call as often as needed.
+pub fn peek_transcription() -> Result<String, Fair9Error> {
+    Ok(peek_with(|samples| {
+        // Peeks are opportunistic; never queue one behind a real transcription
+        let guard = lock_model(false)?;
+        let ctx = guard.as_ref().ok_or(Fair9Error::ModelNotLoaded)?;
+        Ok(run_whisper(ctx, samples, false)?.text)
+    })?)
+}
+
+fn peek_with(run: impl FnOnce(&[f32]) -> Result<String>) -> Result<String> {
+    // Copy and release the lock so capture keeps flowing while the model runs
+    let snapshot = STATE.audio_buffer.lock().unwrap().clone();
+    if snapshot.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(clean_transcript(&run(&snapshot)?))
+}
+
+/// Capture callback for the live stream: audio only counts while listening and not paused
+fn capture_live(data: &[f32]) {
+    if STATE.is_listening.load(Ordering::SeqCst) && !is_paused() {
+        capture_samples(data);
+    }
+}
+
+fn spawn_transcription_stream(emit: impl Fn(TranscriptionResult) + Send + 'static) -> Result<()> {
+    let gen = SHUTDOWN_GEN.load(Ordering::SeqCst);
+
+    // Start listening thread
+    thread::spawn(move || {
+        let _stream = match open_input_stream(capture_live) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to start capture: {:#}", e);
+                return;
+            }
+        };
+
+        let mut window = StreamWindow::default();
+        // A pass that was already running when the session paused; emitted on resume
+        let mut held: Option<TranscriptionResult> = None;
+
+        // Processing loop
+        loop {
+            thread::sleep(std::time::Duration::from_millis(500));
+
+            // Exiting the loop drops the CPAL stream and releases the mic
+            if SHUTDOWN_GEN.load(Ordering::SeqCst) != gen {
+                break;
+            }
+            if !is_paused() {
+                if let Some(result) = held.take() {
+                    emit(result);
+                }
+            }
+            
+            let (samples, is_final) = next_stream_pass(&mut window).unwrap_or_default();
+
+            // Silence would only decode to [BLANK_AUDIO]; don't spend a model run on it
+            if !samples.is_empty() && has_speech(&samples, get_vad_threshold()) {
+                record_session_pass(&samples, is_final);
+
+                // Run Whisper
+                let mut guard = STATE.model_ctx.lock().unwrap();
+                if guard.is_some() {
+                    let decoded = match run_whisper_or_fall_back(&mut guard, &samples, is_final) {
+                        Ok(decoded) => decoded,
+                        Err(e) => {
+                            eprintln!("transcription pass failed: {:#}", e);
+                            continue;
+                        }
+                    };
+
+                    if let Some(result) = finish_pass(&decoded.text, is_final, decoded.confidence) {
+                        if is_paused() {
+                            held = Some(result);
+                        } else {
+                            emit(result);
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// What the streaming loop should transcribe this tick, if anything
+fn next_stream_pass(window: &mut StreamWindow) -> Option<(Vec<f32>, bool)> {
+    // Audio buffered with live processing off gets its single pass once the session stops
+    if let Some(samples) = DEFERRED_AUDIO.lock().unwrap().take() {
+        return Some((samples, true));
+    }
+
+    if !STATE.is_listening.load(Ordering::SeqCst) {
+        // Clear buffer if not listening (a batch recording owns it otherwise)
+        let mut buffer = STATE.audio_buffer.lock().unwrap();
+        if !buffer.is_empty() && !BATCH_RECORDING.load(Ordering::SeqCst) {
+            reset_buffer(&mut buffer);
+        }
+        *window = StreamWindow::default();
+        return None;
+    }
+    // Paused sessions keep their buffer and window for when capture resumes
+    if is_paused() || !LIVE_PROCESSING.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    let mut buffer = STATE.audio_buffer.lock().unwrap();
+    window.next_pass(&mut buffer, &mut BUFFER_POS.lock().unwrap())
+}
+
+/// Keep capturing but skip the model passes while off, e.g. to save battery.
+/// Turning it back on commits everything buffered since as one final; stopping
+/// while it's off transcribes the buffered audio in one pass instead. While off,
+/// up to 30 minutes are buffered (see `MAX_RECORDING_SAMPLES`), not the streaming cap.
+pub fn set_live_processing(enabled: bool) -> Result<(), Fair9Error> {
+    LIVE_PROCESSING.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Post-process one raw Whisper pass into an emission, or None if it's held back.
+/// `confidence` is the pass's mean token probability, when known.
+fn finish_pass(raw: &str, is_final: bool, confidence: Option<f32>) -> Option<TranscriptionResult> {
+    let clean_text = clean_transcript(raw);
+    // Semantic correction round-trips to Ollama, so only finals pay for it
+    let corrected = if is_final {
+        apply_auto_ai_cleanup(apply_semantic_correction(clean_text))
+    } else {
+        clean_text
+    };
+    let min_confidence = f32::from_bits(SNIPPET_MIN_CONFIDENCE.load(Ordering::SeqCst));
+    let end_only = SNIPPET_END_ONLY.load(Ordering::SeqCst);
+    let SnippetExpansion { text, cursor_offset } = if snippets_allowed(is_final, confidence, min_confidence, end_only) {
+        expand_snippets(corrected, is_final)
+    } else {
+        SnippetExpansion::plain(corrected)
+    };
+
+    if !should_emit(&text, is_final, MIN_INTERIM_WORDS.load(Ordering::SeqCst)) {
+        return None;
+    }
+    // Translate mode always emits English; auto-detect leaves nothing to go on
+    let output_language = if TRANSLATE_MODE.load(Ordering::SeqCst) { Some("en") } else { *LANGUAGE.lock().unwrap() };
+    let ipa = match output_language {
+        Some(language) if OUTPUT_IPA.load(Ordering::SeqCst) => text_to_ipa(text.clone(), language.to_string()).ok(),
+        _ => None,
+    };
+    // One lock for read and update, so an edit from the UI can't be lost mid-pass
+    let mut committed = COMMITTED_TEXT.lock().unwrap();
+    let transcript = append_to_transcript(&committed, &text);
+    if is_final {
+        *committed = transcript.clone();
+    }
+    let word_count = text.split_whitespace().count() as u32;
+    let elapsed_ms = LISTENING_STARTED.lock().unwrap().map_or(0, |t| t.elapsed().as_millis() as u64);
+    Some(TranscriptionResult { text, is_final, ipa, transcript, word_count, elapsed_ms, cursor_offset })
+}
+
+/// Interim passes are still settling, so they only expand snippets when the model
+/// was at least this confident (mean token probability, 0–1). Finals always expand.
+/// 0 (the default) lets every pass expand; above 1 keeps expansion to finals only.
+pub fn set_snippet_min_confidence(p: f32) -> Result<(), Fair9Error> {
+    if !p.is_finite() || p < 0.0 {
+        return Err(Fair9Error::InvalidArgument("Snippet confidence threshold must be a non-negative number".to_string()));
+    }
+    SNIPPET_MIN_CONFIDENCE.store(p.to_bits(), Ordering::SeqCst);
+    Ok(())
+}
+
+/// Only expand snippets once an utterance is finalized. Interim re-transcriptions
+/// are shown as spoken, so a trigger never flickers in and out of its expansion.
+pub fn set_snippet_end_only(enabled: bool) -> Result<(), Fair9Error> {
+    SNIPPET_END_ONLY.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// The expansion rule for streamed passes. Wherever expansion happens, only a trigger
+/// ending the utterance fires (`find_snippet` matches suffixes only). Finals always
+/// expand; interims don't in end-only mode, or when below the confidence threshold.
+fn snippets_allowed(is_final: bool, confidence: Option<f32>, min_confidence: f32, end_only: bool) -> bool {
+    if is_final {
+        return true;
+    }
+    // Unknown confidence (e.g. replays) counts as certain
+    !end_only && (min_confidence <= 0.0 || confidence.unwrap_or(1.0) >= min_confidence)
+}
+
+// ── Batch Recording ──────────────────────────────────────────────────
+
+/// Sessions shorter than this (an accidental hotkey tap) are discarded without
+/// running the model. 0 disables the guard.
+pub fn set_min_session_ms(ms: u64) -> Result<(), Fair9Error> {
+    MIN_SESSION_MS.store(ms as usize, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Record until `stop_and_transcribe`, then transcribe the whole take at once
+pub fn start_batch_recording() -> Result<(), Fair9Error> {
+    if BATCH_RECORDING.swap(true, Ordering::SeqCst) {
+        return Err(Fair9Error::AlreadyRecording);
+    }
+    clear_audio_buffer()?;
+    *SESSION_STARTED.lock().unwrap() = Some(std::time::Instant::now());
+
+    // The CPAL stream lives on its own thread until recording stops
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let stream = match open_input_stream(|data| {
+            if BATCH_RECORDING.load(Ordering::SeqCst) {
+                capture_samples(data);
+            }
+        }) {
+            Ok(stream) => {
+                let _ = ready_tx.send(Ok(()));
+                stream
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+        while BATCH_RECORDING.load(Ordering::SeqCst) {
+            thread::sleep(std::time::Duration::from_millis(20));
+        }
+        drop(stream);
+    });
+
+    match ready_rx.recv() {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => {
+            BATCH_RECORDING.store(false, Ordering::SeqCst);
+            Err(e.into())
+        }
+        Err(_) => {
+            BATCH_RECORDING.store(false, Ordering::SeqCst);
+            Err(Fair9Error::Other("Capture thread exited unexpectedly".to_string()))
+        }
+    }
+}
+
+fn is_session_too_short(elapsed_ms: u64, min_session_ms: u64) -> bool {
+    elapsed_ms < min_session_ms
+}
+
+/// Stop the batch recording and transcribe it. Returns an empty string (no speech)
+/// when the session was shorter than the minimum hold.
+pub fn stop_and_transcribe() -> Result<String, Fair9Error> {
+    let Some(samples) = finish_batch_recording()? else {
+        return Ok(String::new());
+    };
+    let text = transcribe_samples(&samples)?;
+    Ok(process_snippets(apply_auto_ai_cleanup(apply_semantic_correction(text))))
+}
+
+/// Stop the batch recording and return its segments with timings, for captions:
+/// a JSON array of `{"text", "t0", "t1"}` with offsets in ms from the start of the
+/// recording. Nothing recorded (or a too-short tap) gives `[]`.
+pub fn stop_and_transcribe_with_timestamps() -> Result<String, Fair9Error> {
+    let samples = finish_batch_recording()?.unwrap_or_default();
+    Ok(captions_json(&samples, |audio| {
+        let guard = lock_model(true)?;
+        let ctx = guard.as_ref().ok_or(Fair9Error::ModelNotLoaded)?;
+        let mut params = build_full_params();
+        params.set_token_timestamps(true);
+        Ok(counted(decode_segments(ctx, params, audio))?.segments)
+    })?)
+}
+
+fn captions_json(samples: &[f32], run: impl FnOnce(&[f32]) -> Result<Vec<Segment>>) -> Result<String> {
+    if samples.is_empty() {
+        return Ok("[]".to_string());
+    }
+    let captions: Vec<serde_json::Value> = run(samples)?
+        .into_iter()
+        .map(|seg| (clean_transcript(&seg.text), seg.start_ms, seg.end_ms))
+        .filter(|(text, _, _)| !text.is_empty())
+        .map(|(text, t0, t1)| json!({ "text": text, "t0": t0, "t1": t1 }))
+        .collect();
+    Ok(serde_json::to_string(&captions)?)
+}
+
+/// Stop the batch recording and transcribe only the last utterance, i.e. the
+/// final stretch of speech after the last pause of at least a second. Useful
+/// for a quick correction after a long take.
+pub fn transcribe_last_utterance() -> Result<String, Fair9Error> {
+    let Some(samples) = finish_batch_recording()? else {
+        return Ok(String::new());
+    };
+    let Some(region) = last_speech_region(&samples) else {
+        return Ok(String::new());
+    };
+    let text = transcribe_samples(&samples[region])?;
+    Ok(process_snippets(apply_auto_ai_cleanup(apply_semantic_correction(text))))
+}
+
+/// End the batch session and hand back its audio, or None for a too-short tap
+fn finish_batch_recording() -> Result<Option<Vec<f32>>> {
+    if !BATCH_RECORDING.swap(false, Ordering::SeqCst) {
+        return Err(Fair9Error::NotRecording.into());
+    }
+    let elapsed = SESSION_STARTED.lock().unwrap().take().map(|t| t.elapsed()).unwrap_or_default();
+    let samples = reset_buffer(&mut STATE.audio_buffer.lock().unwrap());
+
+    if is_session_too_short(elapsed.as_millis() as u64, MIN_SESSION_MS.load(Ordering::SeqCst) as u64) {
+        return Ok(None);
+    }
+    Ok(Some(samples))
+}
+
+/// RMS level a VAD frame must exceed to count as speech. Raise it for noisy rooms,
+/// lower it for quiet or distant mics.
+pub fn set_vad_threshold(rms: f32) -> Result<(), Fair9Error> {
+    if !rms.is_finite() || rms < 0.0 {
+        return Err(Fair9Error::InvalidArgument("VAD threshold must be a non-negative number".to_string()));
+    }
+    VAD_THRESHOLD_RMS.store(rms.to_bits(), Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn get_vad_threshold() -> f32 {
+    f32::from_bits(VAD_THRESHOLD_RMS.load(Ordering::SeqCst))
+}
+
+/// Listen to the room for `duration_ms` (keep quiet!) and set the VAD threshold to
+/// VAD_CALIBRATION_FACTOR times the ambient level. Returns the new threshold.
+/// Refused while listening or recording, since it needs the mic to itself.
+pub fn calibrate_vad(duration_ms: u64) -> Result<f32, Fair9Error> {
+    if !(100..=10_000).contains(&duration_ms) {
+        return Err(Fair9Error::InvalidArgument("Calibration needs between 100ms and 10s of audio".to_string()));
+    }
+    if STATE.is_listening.load(Ordering::SeqCst) || BATCH_RECORDING.load(Ordering::SeqCst) {
+        return Err(Fair9Error::Other("Stop listening before calibrating the microphone".to_string()));
+    }
+
+    let ambient = Arc::new(Mutex::new(Vec::new()));
+    let sink = ambient.clone();
+    let stream = open_input_stream(move |data| sink.lock().unwrap().extend_from_slice(data))?;
+    thread::sleep(std::time::Duration::from_millis(duration_ms));
+    // Releases the mic; the shared buffer and stream state were never touched
+    drop(stream);
+
+    let samples = std::mem::take(&mut *ambient.lock().unwrap());
+    let threshold = threshold_from_ambient(&samples)?;
+    set_vad_threshold(threshold)?;
+    Ok(threshold)
+}
+
+fn threshold_from_ambient(samples: &[f32]) -> Result<f32> {
+    if samples.is_empty() {
+        return Err(anyhow!("No audio was captured during calibration"));
+    }
+    // A digitally silent input would otherwise count every click as speech
+    Ok((calculate_rms(samples.to_vec()) * VAD_CALIBRATION_FACTOR).max(MIN_CALIBRATED_VAD_THRESHOLD))
+}
+
+/// Whether any VAD frame is above `threshold`
+fn has_speech(samples: &[f32], threshold: f32) -> bool {
+    samples.chunks(VAD_FRAME_SAMPLES).any(|frame| calculate_rms(frame.to_vec()) > threshold)
+}
+
+/// Sample range of the last speech region: frames above the VAD threshold,
+/// bounded by at least SILENCE_DURATION_MS of silence (or the buffer edges)
+fn last_speech_region(samples: &[f32]) -> Option<std::ops::Range<usize>> {
+    speech_regions(samples, SILENCE_DURATION_MS as u64).pop()
+}
+
+/// Silence at least this long between two stretches of speech marks a likely
+/// change of speaker for `detect_turns`
+pub fn set_turn_gap_ms(ms: u64) -> Result<(), Fair9Error> {
+    if ms == 0 {
+        return Err(Fair9Error::InvalidArgument("Turn gap must be greater than zero".to_string()));
+    }
+    TURN_GAP_MS.store(ms as usize, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Rough speaker-turn hint for meeting notes: `(start, end)` sample ranges of
+/// 16kHz speech separated by long pauses. Not diarization; two people talking
+/// without a pause come back as one turn.
+pub fn detect_turns(samples: Vec<f32>) -> Vec<(usize, usize)> {
+    speech_regions(&samples, TURN_GAP_MS.load(Ordering::SeqCst) as u64)
+        .into_iter()
+        .map(|region| (region.start, region.end))
+        .collect()
+}
+
+/// Stretches of frames above the VAD threshold. Pauses shorter than `gap_ms`
+/// (breaths, hesitations) stay inside a region.
+fn speech_regions(samples: &[f32], gap_ms: u64) -> Vec<std::ops::Range<usize>> {
+    let threshold = get_vad_threshold();
+    let gap_frames = (gap_ms as usize * SAMPLE_RATE / 1000).div_ceil(VAD_FRAME_SAMPLES).max(1);
+    let to_samples = |first: usize, last: usize| first * VAD_FRAME_SAMPLES..((last + 1) * VAD_FRAME_SAMPLES).min(samples.len());
+
+    let mut regions = Vec::new();
+    let mut current: Option<(usize, usize)> = None; // First and last voiced frame
+    for (i, frame) in samples.chunks(VAD_FRAME_SAMPLES).enumerate() {
+        if calculate_rms(frame.to_vec()) <= threshold {
+            continue;
+        }
+        current = match current {
+            Some((first, last)) if i - last - 1 < gap_frames => Some((first, i)),
+            Some((first, last)) => {
+                regions.push(to_samples(first, last));
+                Some((i, i))
+            }
+            None => Some((i, i)),
+        };
+    }
+    if let Some((first, last)) = current {
+        regions.push(to_samples(first, last));
+    }
+    regions
+}
+
+// ── Session Record/Replay ────────────────────────────────────────────
+
+/// One transcription pass exactly as the stream fed it to the model
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct RecordedPass {
+    samples: Vec<f32>,
+    is_final: bool,
+}
+
+/// A recorded dictation session: the settings in effect plus every pass, in order
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct SessionFixture {
+    settings: Settings,
+    passes: Vec<RecordedPass>,
+}
+
+/// Start capturing live stream passes (and the current settings) for a regression fixture
+pub fn start_session_recording() -> Result<(), Fair9Error> {
+    let mut recording = SESSION_RECORDING.lock().unwrap();
+    if recording.is_some() {
+        return Err(Fair9Error::Other("Session recording already in progress".to_string()));
+    }
+    *recording = Some(SessionFixture { settings: Settings::current(), passes: Vec::new() });
+    Ok(())
+}
+
+/// Stop capturing and write the fixture to `path`. Returns the number of recorded passes.
+pub fn stop_session_recording(path: String) -> Result<usize, Fair9Error> {
+    let fixture = SESSION_RECORDING.lock().unwrap().take()
+        .ok_or_else(|| anyhow!("No session recording in progress"))?;
+    fs::write(&path, serde_json::to_string(&fixture)?)
+        .with_context(|| format!("Failed to write session fixture {:?}", path))?;
+    Ok(fixture.passes.len())
+}
+
+fn record_session_pass(samples: &[f32], is_final: bool) {
+    if let Some(fixture) = SESSION_RECORDING.lock().unwrap().as_mut() {
+        fixture.passes.push(RecordedPass { samples: samples.to_vec(), is_final });
+    }
+}
+
+/// Feed a recorded session back through the model and pipeline under its recorded
+/// settings, returning the texts the stream would have emitted. Settings are restored after.
+pub fn replay_session(fixture: String) -> Result<Vec<String>, Fair9Error> {
+    let data = fs::read_to_string(&fixture)
+        .with_context(|| format!("Failed to read session fixture {:?}", fixture))?;
+    let fixture: SessionFixture = serde_json::from_str(&data).context("Invalid session fixture")?;
+
+    let guard = lock_model(true)?;
+    let ctx = guard.as_ref().ok_or(Fair9Error::ModelNotLoaded)?;
+    Ok(replay_with(&fixture, |samples, is_final| run_whisper(ctx, samples, is_final).map(|d| d.text))?)
+}
+
+fn replay_with(fixture: &SessionFixture, mut run: impl FnMut(&[f32], bool) -> Result<String>) -> Result<Vec<String>> {
+    let previous = Settings::current();
+    fixture.settings.apply();
+    let result = fixture.passes.iter()
+        .map(|pass| run(&pass.samples, pass.is_final).map(|raw| finish_pass(&raw, pass.is_final, None)))
+        .collect::<Result<Vec<_>>>();
+    previous.apply();
+    Ok(result?.into_iter().flatten().map(|r| r.text).collect())
+}
+
+// ── File Transcription ───────────────────────────────────────────────
+
+const SUPPORTED_AUDIO_EXTENSIONS: &[&str] = &["wav"];
+
+/// Result for one file of a batch directory transcription
+#[derive(Clone, Debug)]
+pub struct TranscriptionFileResult {
+    pub filename: String,
+    pub text: String,
+    pub error: Option<String>,
+}
+
+/// Decode a PCM16 or float32 WAV into mono samples, returning them with the file's rate
+fn read_wav(path: &std::path::Path) -> Result<(Vec<f32>, u32)> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(anyhow!("Not a WAV file"));
+    }
+
+    let mut format: Option<(u16, u16, u32, u16)> = None; // (tag, channels, rate, bits)
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let size = u32::from_le_bytes([bytes[pos + 4], bytes[pos + 5], bytes[pos + 6], bytes[pos + 7]]) as usize;
+        let body = &bytes[pos + 8..(pos + 8 + size).min(bytes.len())];
+
+        if id == b"fmt " && body.len() >= 16 {
+            format = Some((
+                u16::from_le_bytes([body[0], body[1]]),
+                u16::from_le_bytes([body[2], body[3]]),
+                u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+                u16::from_le_bytes([body[14], body[15]]),
+            ));
+        } else if id == b"data" {
+            let (tag, channels, rate, bits) = format.ok_or_else(|| anyhow!("WAV data before fmt chunk"))?;
+            let interleaved: Vec<f32> = match (tag, bits) {
+                (1, 16) => decode_pcm16(body),
+                (3, 32) => body
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+                _ => return Err(anyhow!("Unsupported WAV encoding (format {}, {} bits)", tag, bits)),
+            };
+            return Ok((downmix_to_mono(&interleaved, channels as usize), rate));
+        }
+        // Chunks are word-aligned
+        pos += 8 + size + (size & 1);
+    }
+    Err(anyhow!("WAV file has no data chunk"))
+}
+
+/// Little-endian signed 16-bit PCM → f32 in [-1, 1)
+fn decode_pcm16(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(2)
+        .map(|b| i16_to_f32(i16::from_le_bytes([b[0], b[1]])))
+        .collect()
+}
+
+/// Interleaved i16 PCM at any rate/channel count → 16kHz mono, ready for the model
+fn pcm16_to_model_input(bytes: &[u8], sample_rate: u32, channels: u16) -> Result<Vec<f32>> {
+    if sample_rate == 0 || channels == 0 {
+        return Err(anyhow!("Sample rate and channel count must be greater than zero"));
+    }
+    let frame_bytes = 2 * channels as usize;
+    if bytes.len() % frame_bytes != 0 {
+        return Err(anyhow!(
+            "PCM data is {} bytes, not a whole number of {}-channel 16-bit frames",
+            bytes.len(), channels
+        ));
+    }
+    let mono = downmix_to_mono(&decode_pcm16(bytes), channels as usize);
+    Ok(resample(&mono, sample_rate, SAMPLE_RATE as u32))
+}
+
+/// Transcribe raw little-endian i16 PCM (e.g. from a non-CPAL source over FFI)
+pub fn transcribe_pcm16(bytes: Vec<u8>, sample_rate: u32, channels: u16) -> Result<String, Fair9Error> {
+    Ok(transcribe_samples(&pcm16_to_model_input(&bytes, sample_rate, channels)?)?)
+}
+
+/// Average each interleaved frame into one sample. Used for files, PCM buffers and
+/// live capture alike; a trailing partial frame is dropped.
+fn downmix_to_mono(interleaved: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return interleaved.to_vec();
+    }
+    interleaved
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Resampler trade-off: linear is cheap, windowed sinc keeps more of the signal
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResampleQuality {
+    Linear,
+    Sinc,
+}
+
+/// "linear" or "sinc" for files and PCM buffers, or "auto" (default, sinc). Live
+/// capture always goes through StreamResampler, whose sinc kernel is its low-pass.
+pub fn set_resample_quality(mode: String) -> Result<(), Fair9Error> {
+    let quality = match mode.trim().to_lowercase().as_str() {
+        "auto" => None,
+        "linear" => Some(ResampleQuality::Linear),
+        "sinc" => Some(ResampleQuality::Sinc),
+        other => return Err(Fair9Error::InvalidArgument(format!("Unknown resample quality '{}' (expected linear, sinc or auto)", other))),
+    };
+    *RESAMPLE_QUALITY.lock().unwrap() = quality;
+    Ok(())
+}
+
+fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    match RESAMPLE_QUALITY.lock().unwrap().unwrap_or(ResampleQuality::Sinc) {
+        ResampleQuality::Linear => resample_linear(samples, from_rate, to_rate),
+        ResampleQuality::Sinc => resample_sinc(samples, from_rate, to_rate),
+    }
+}
+
+/// Blackman-windowed sinc resampler. When downsampling the cutoff drops to the new
+/// Nyquist and the kernel widens to match, so high frequencies don't alias into speech.
+fn resample_sinc(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let ratio = from_rate as f64 / to_rate as f64;
+    let cutoff = (1.0 / ratio).min(1.0);
+    let half_width = (SINC_ZERO_CROSSINGS as f64 / cutoff).ceil() as i64;
+    let out_len = (samples.len() as f64 / ratio).round() as usize;
+    (0..out_len)
+        .map(|i| {
+            let center = i as f64 * ratio;
+            let first = (center.floor() as i64 - half_width + 1).max(0);
+            let last = (center.floor() as i64 + half_width).min(samples.len() as i64 - 1);
+            let (mut acc, mut norm) = (0.0f64, 0.0f64);
+            for j in first..=last {
+                let weight = sinc_tap(center - j as f64, cutoff, half_width);
+                acc += samples[j as usize] as f64 * weight;
+                norm += weight;
+            }
+            // Normalising keeps unity gain, including near the edges where taps are missing
+            if norm.abs() > 1e-9 { (acc / norm) as f32 } else { 0.0 }
+        })
+        .collect()
+}
+
+/// Weight of the input sample `t` samples away from the output position:
+/// a sinc at `cutoff`, Blackman-windowed over [-half_width, half_width]
+fn sinc_tap(t: f64, cutoff: f64, half_width: i64) -> f64 {
+    let x = cutoff * t;
+    let sinc = if x.abs() < 1e-9 { 1.0 } else { (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x) };
+    let phase = std::f64::consts::PI * (t / half_width as f64 + 1.0);
+    sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+}
+
+/// Windowed-sinc resampler for live capture, fed one callback at a time. Output `k`
+/// sits at exactly `k * from / to` in the input, so chunk sizes that don't divide
+/// the ratio never accumulate rounding, and the tail of each callback is kept as
+/// the next one's left taps, so chunk boundaries don't click. When downsampling,
+/// the kernel is the low-pass that keeps 48kHz content from aliasing into speech.
+struct StreamResampler {
+    from_rate: u64,
+    to_rate: u64,
+    cutoff: f64,
+    half_width: i64,
+    /// Zero-padded input still needed; `history[0]` is padded sample `dropped`
+    history: Vec<f32>,
+    dropped: u64,
+    produced: u64,
+    /// Normalised taps for each sub-sample phase, built on first use
+    kernels: Vec<Vec<f32>>,
+    phase_step: u64,
+}
+
+impl StreamResampler {
+    fn new(from_rate: u32, to_rate: u32) -> Self {
+        let cutoff = (to_rate as f64 / from_rate as f64).min(1.0);
+        let half_width = (SINC_ZERO_CROSSINGS as f64 / cutoff).ceil() as i64;
+        let (mut a, mut b) = (from_rate.max(1) as u64, to_rate.max(1) as u64);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        StreamResampler {
+            from_rate: from_rate as u64,
+            to_rate: to_rate as u64,
+            cutoff,
+            half_width,
+            // Output trails the input by half a kernel, which this silence stands in
+            // for, so the first callback already yields its full share of samples
+            history: vec![0.0; 2 * half_width as usize - 1],
+            dropped: 0,
+            produced: 0,
+            kernels: vec![Vec::new(); (to_rate as u64 / a).max(1) as usize],
+            phase_step: a,
+        }
+    }
+
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.from_rate == self.to_rate || self.to_rate == 0 {
+            return input.to_vec();
+        }
+        self.history.extend_from_slice(input);
+        let taps = 2 * self.half_width as usize;
+        let available = self.dropped + self.history.len() as u64;
+        let mut out = Vec::new();
+        loop {
+            let position = self.produced * self.from_rate;
+            let first = position / self.to_rate;
+            if first + taps as u64 > available {
+                break;
+            }
+            let remainder = position % self.to_rate;
+            let start = (first - self.dropped) as usize;
+            let phase = self.kernel(remainder);
+            let kernel = &self.kernels[phase];
+            out.push(self.history[start..start + taps].iter().zip(kernel).map(|(s, w)| s * w).sum());
+            self.produced += 1;
+        }
+        let next_first = self.produced * self.from_rate / self.to_rate;
+        let consumed = ((next_first - self.dropped) as usize).min(self.history.len());
+        self.history.drain(..consumed);
+        self.dropped += consumed as u64;
+        out
+    }
+
+    /// Index into `kernels` of the taps for an output `remainder / to_rate` of a
+    /// sample past the kernel's centre
+    fn kernel(&mut self, remainder: u64) -> usize {
+        let phase = (remainder / self.phase_step) as usize;
+        if self.kernels[phase].is_empty() {
+            let frac = remainder as f64 / self.to_rate as f64;
+            let weights: Vec<f64> = (0..2 * self.half_width)
+                .map(|j| sinc_tap(frac + (self.half_width - 1 - j) as f64, self.cutoff, self.half_width))
+                .collect();
+            let norm: f64 = weights.iter().sum();
+            self.kernels[phase] = weights.iter().map(|w| (w / norm) as f32).collect();
+        }
+        phase
+    }
+}
+
+/// Linear-interpolation resampler; good enough for speech headed to Whisper
+fn resample_linear(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let ratio = from_rate as f64 / to_rate as f64;
+    let out_len = (samples.len() as f64 / ratio).round() as usize;
+    (0..out_len)
+        .map(|i| {
+            let src = i as f64 * ratio;
+            let idx = src.floor() as usize;
+            let frac = (src - idx as f64) as f32;
+            let a = samples[idx.min(samples.len() - 1)];
+            let b = samples[(idx + 1).min(samples.len() - 1)];
+            a + (b - a) * frac
+        })
+        .collect()
+}
+
+/// The model, held for the whole of an inference so that only one runs at a time
+/// (whisper.cpp contexts aren't safe to share). With `wait`, callers queue behind
+/// the running transcription; without, they get a "Transcription busy" error.
+fn lock_model(wait: bool) -> Result<std::sync::MutexGuard<'static, Option<WhisperContext>>> {
+    if wait {
+        return Ok(STATE.model_ctx.lock().unwrap());
+    }
+    match STATE.model_ctx.try_lock() {
+        Ok(guard) => Ok(guard),
+        Err(std::sync::TryLockError::WouldBlock) => Err(Fair9Error::TranscriptionBusy.into()),
+        Err(std::sync::TryLockError::Poisoned(e)) => Err(anyhow!("Model state is unusable after a crash: {}", e)),
+    }
+}
+
+/// Transcribe 16kHz mono samples with the loaded model
+fn transcribe_samples(samples: &[f32]) -> Result<String> {
+    let mut guard = lock_model(true)?;
+    let decoded = run_whisper_or_fall_back(&mut guard, samples, true)?;
+    *LAST_SPEAKING_RATE.lock().unwrap() = Some(speaking_rate_wpm(&decoded.segments));
+    Ok(clean_transcript(&decoded.text))
+}
+
+/// The same audio transcribed with and without the whisper-mode DSP, for A/B comparison
+pub struct DspComparison {
+    pub with_dsp: String,
+    pub without_dsp: String,
+}
+
+/// Transcribe `samples` twice so users can hear whether whisper mode's gain and
+/// high-pass help their voice. Decoding settings are identical for both passes.
+pub fn transcribe_samples_both(samples: Vec<f32>) -> Result<DspComparison, Fair9Error> {
+    let guard = lock_model(true)?;
+    let ctx = guard.as_ref().ok_or(Fair9Error::ModelNotLoaded)?;
+    Ok(compare_dsp(&samples, |audio| decode_segments(ctx, build_full_params(), audio).map(|d| d.text))?)
+}
+
+fn compare_dsp(samples: &[f32], mut run: impl FnMut(&[f32]) -> Result<String>) -> Result<DspComparison> {
+    let with_dsp = clean_transcript(&run(&whisper_mode_dsp(samples))?);
+    let without_dsp = clean_transcript(&run(samples)?);
+    Ok(DspComparison { with_dsp, without_dsp })
+}
+
+pub fn transcribe_file(path: String) -> Result<String, Fair9Error> {
+    let (samples, rate) = read_wav(std::path::Path::new(&path))?;
+    Ok(transcribe_samples(&resample(&samples, rate, SAMPLE_RATE as u32))?)
+}
+
+fn is_supported_audio(path: &std::path::Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map_or(false, |e| SUPPORTED_AUDIO_EXTENSIONS.iter().any(|s| e.eq_ignore_ascii_case(s)))
+}
+
+/// Transcribe every supported file in `dir` one at a time (the model context is
+/// shared), emitting a result per file and carrying on past individual failures.
+fn transcribe_directory_with(dir: &std::path::Path, mut emit: impl FnMut(TranscriptionFileResult)) -> Result<()> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {:?}", dir))?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && is_supported_audio(p))
+        .collect();
+    files.sort();
+
+    for path in files {
+        let filename = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let result = match transcribe_file(path.to_string_lossy().to_string()) {
+            Ok(text) => TranscriptionFileResult { filename, text, error: None },
+            Err(e) => TranscriptionFileResult { filename, text: String::new(), error: Some(format!("{:#}", e)) },
+        };
+        emit(result);
+    }
+    Ok(())
+}
+
+pub fn transcribe_directory(dir: String, sink: StreamSink<TranscriptionFileResult>) -> Result<(), Fair9Error> {
+    let dir = PathBuf::from(dir);
+    if !dir.is_dir() {
+        return Err(Fair9Error::Other(format!("Not a directory: {:?}", dir)));
+    }
+
+    thread::spawn(move || {
+        if let Err(e) = transcribe_directory_with(&dir, |result| {
+            sink.add(result);
+        }) {
+            eprintln!("directory transcription failed: {:#}", e);
+        }
+        sink.close();
+    });
+
+    Ok(())
+}
+
+/// Tear everything down for app exit: stop the session, cancel in-flight
+/// injection/AI work, flush pending writes (see `flush_all`), and unload the model.
+pub fn shutdown() -> Result<(), Fair9Error> {
+    SHUTDOWN_GEN.fetch_add(1, Ordering::SeqCst);
+    BATCH_RECORDING.store(false, Ordering::SeqCst);
+    stop_listening()?;
+
+    // Flush before unloading so a failed write is still reported
+    let flushed = flush_pending_writes();
+
+    let mut guard = STATE.model_ctx.lock().unwrap();
+    *guard = None;
+
+    Ok(flushed?)
+}
+
+/// Ask GitHub for the latest release and compare it with this build. Returns JSON:
+/// `{"current":"1.2.0","latest":"1.3.0","update_available":true}`. Being offline or
+/// rate limited isn't an error; it gives `update_available: false` plus an `error` note.
+pub fn check_for_updates() -> Result<String, Fair9Error> {
+    let url = format!("https://api.github.com/repos/{}/releases/latest", GITHUB_REPO);
+    Ok(update_status_json(APP_VERSION, fetch_latest_tag(&url)).to_string())
+}
+
+fn fetch_latest_tag(url: &str) -> Result<String> {
+    #[derive(Deserialize)]
+    struct Release {
+        tag_name: String,
+    }
+    let response = ureq::get(url)
+        .timeout(UPDATE_CHECK_TIMEOUT)
+        .set("User-Agent", &format!("Fair9/{}", APP_VERSION)) // GitHub rejects requests without one
+        .set("Accept", "application/vnd.github+json")
+        .call();
+    let release: Release = match response {
+        Ok(res) => res.into_json().context("Failed to parse the latest release")?,
+        Err(ureq::Error::Status(403, _)) | Err(ureq::Error::Status(429, _)) => {
+            return Err(anyhow!("GitHub API rate limit reached; try again later"));
+        }
+        Err(ureq::Error::Status(404, _)) => return Err(anyhow!("No releases published yet")),
+        Err(e) => return Err(anyhow::Error::new(e).context("Failed to reach GitHub")),
+    };
+    Ok(release.tag_name)
+}
+
+fn update_status_json(current: &str, latest_tag: Result<String>) -> serde_json::Value {
+    match latest_tag {
+        Ok(tag) => {
+            let latest = tag.trim().trim_start_matches(['v', 'V']).to_string();
+            json!({
+                "current": current,
+                "update_available": is_newer_version(&latest, current),
+                "latest": latest,
+            })
+        }
+        Err(e) => json!({
+            "current": current,
+            "latest": null,
+            "update_available": false,
+            "error": format!("{:#}", e),
+        }),
+    }
+}
+
+/// Semver precedence: numeric major.minor.patch (missing parts count as 0), then a
+/// release outranks its pre-releases. Build metadata is ignored. Anything that
+/// doesn't parse is never reported as newer.
+fn is_newer_version(candidate: &str, current: &str) -> bool {
+    fn parse(version: &str) -> Option<(Vec<u64>, Option<&str>)> {
+        let version = version.trim().trim_start_matches(['v', 'V']);
+        let version = version.split('+').next()?;
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, Some(pre)),
+            None => (version, None),
+        };
+        let mut parts = core.split('.').map(|p| p.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+        if parts.len() > 3 {
+            return None;
+        }
+        parts.resize(3, 0);
+        Some((parts, pre))
+    }
+    // Pre-release identifiers: numeric ones compare as numbers and sort before alphanumeric
+    fn pre_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+        let (mut a, mut b) = (a.split('.'), b.split('.'));
+        loop {
+            let ord = match (a.next(), b.next()) {
+                (None, None) => return std::cmp::Ordering::Equal,
+                (None, Some(_)) => return std::cmp::Ordering::Less,
+                (Some(_), None) => return std::cmp::Ordering::Greater,
+                (Some(x), Some(y)) => match (x.parse::<u64>(), y.parse::<u64>()) {
+                    (Ok(x), Ok(y)) => x.cmp(&y),
+                    (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+                    (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+                    (Err(_), Err(_)) => x.cmp(y),
+                },
+            };
+            if ord != std::cmp::Ordering::Equal {
+                return ord;
+            }
+        }
+    }
+    let (Some((candidate_core, candidate_pre)), Some((current_core, current_pre))) = (parse(candidate), parse(current)) else {
+        return false;
+    };
+    let ordering = candidate_core.cmp(&current_core).then_with(|| match (candidate_pre, current_pre) {
+        (None, None) => std::cmp::Ordering::Equal,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (Some(a), Some(b)) => pre_cmp(a, b),
+    });
+    ordering == std::cmp::Ordering::Greater
+}
+
+/// Build details for the About screen
+#[derive(Clone, Debug)]
+pub struct VersionInfo {
+    pub version: String,
+    pub git_repo: String,
+    pub whisper_backend: String,
+    pub gpu: bool,
+}
+
+pub fn version_info() -> VersionInfo {
+    VersionInfo {
+        version: APP_VERSION.to_string(),
+        git_repo: GITHUB_REPO.to_string(),
+        whisper_backend: WHISPER_BACKEND.to_string(),
+        gpu: GPU_BUILD,
+    }
+}
+
+/// Where inference runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Acceleration {
+    Gpu,
+    Cpu,         // CPU-only build
+    CpuFallback, // GPU build whose GPU failed mid-session
+}
+
+impl Acceleration {
+    fn for_build() -> Self {
+        if GPU_BUILD { Acceleration::Gpu } else { Acceleration::Cpu }
+    }
+
+    /// Only a GPU run that failed moves, and only when fallback is enabled; once on
+    /// the CPU, failures are ordinary errors
+    fn after_failure(self, fallback_enabled: bool) -> Self {
+        match self {
+            Acceleration::Gpu if fallback_enabled => Acceleration::CpuFallback,
+            other => other,
+        }
+    }
+}
+
+/// Whether a failed GPU inference reloads the model on the CPU and carries on (the
+/// default) or keeps failing on the GPU
+pub fn set_gpu_fallback(enabled: bool) -> Result<(), Fair9Error> {
+    GPU_FALLBACK.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Errors from inside whisper.cpp; "Model not loaded" and the like aren't the GPU's fault
+fn is_inference_failure(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| cause.is::<whisper_rs::WhisperError>())
+}
+
+/// `run_whisper`, except that a pass failing on the GPU is redone on the CPU context
+/// `fall_back_to_cpu` loads, so the audio (maybe a whole final) isn't lost.
+/// Finals are counted once, however many attempts they took.
+fn run_whisper_or_fall_back(model: &mut Option<WhisperContext>, samples: &[f32], is_final: bool) -> Result<Decoded> {
+    let ctx = model.as_ref().ok_or(Fair9Error::ModelNotLoaded)?;
+    let result = match run_whisper(ctx, samples, is_final) {
+        Err(e) if fall_back_to_cpu(model, &e) => {
+            let ctx = model.as_ref().ok_or(Fair9Error::ModelNotLoaded)?;
+            run_whisper(ctx, samples, is_final)
+        }
+        result => result,
+    };
+    if is_final { counted(result) } else { result }
+}
+
+/// After a failed run: if that was the GPU, swap `model` for a CPU context of the same
+/// file and emit `GpuFallback`. True when the swap happened and the run is worth retrying.
+fn fall_back_to_cpu(model: &mut Option<WhisperContext>, e: &anyhow::Error) -> bool {
+    if !is_inference_failure(e) {
+        return false;
+    }
+    let mut acceleration = ACCELERATION.lock().unwrap();
+    let next = acceleration.0.after_failure(GPU_FALLBACK.load(Ordering::SeqCst));
+    if next == acceleration.0 {
+        return false;
+    }
+    let Some(path) = LOADED_MODEL_PATH.lock().unwrap().clone() else {
+        return false;
+    };
+    match load_context(&path, false) {
+        Ok(ctx) => {
+            let reason = format!("{:#}", e);
+            eprintln!("GPU inference failed, continuing on the CPU: {}", reason);
+            *model = Some(ctx);
+            *acceleration = (next, Some(reason.clone()));
+            emit_event(EngineEvent::GpuFallback { reason });
+            true
+        }
+        Err(load_err) => {
+            eprintln!("GPU inference failed and the CPU fallback didn't load: {:#}", load_err);
+            false
+        }
+    }
+}
+
+/// Where inference is running right now
+#[derive(Clone, Debug)]
+pub struct AccelerationInfo {
+    pub backend: String,
+    pub gpu_active: bool,
+    /// The GPU failed this session and inference moved to the CPU
+    pub fell_back: bool,
+    pub fallback_reason: Option<String>,
+}
+
+pub fn get_acceleration_info() -> AccelerationInfo {
+    let (acceleration, reason) = ACCELERATION.lock().unwrap().clone();
+    AccelerationInfo {
+        backend: WHISPER_BACKEND.to_string(),
+        gpu_active: acceleration == Acceleration::Gpu,
+        fell_back: acceleration == Acceleration::CpuFallback,
+        fallback_reason: reason,
+    }
+}
+
+// ── Settings ─────────────────────────────────────────────────────────
+
+/// Persisted user preferences (settings.json next to snippets.json).
+/// Snippets themselves are stored separately and never touched here.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Settings {
+    whisper_mode: bool,
+    semantic_correction: bool,
+    strip_nonspeech_tags: bool,
+    suppress_blank: bool,
+    suppress_non_speech: bool,
+    min_interim_words: usize,
+    max_buffer_ms: u64,
+    clip_warn_ratio: f32,
+    snippet_expansion: bool,
+    snippet_escape_prefix: String,
+    snippet_resolution: SnippetResolution,
+    snippet_prefix: Option<String>,
+    text_transforms: Vec<TextTransform>,
+    output_ipa: bool,
+    profanity_filter: bool,
+    profanity_list: Vec<String>,
+    flatten_newlines: bool,
+    persist_metrics: bool,
+    max_ai_input_chars: usize,
+    max_segment_chars: i32,
+    split_on_word: bool,
+    max_snippet_chars: usize,
+    auto_code_prompt: bool,
+    thread_count: u32,
+    min_session_ms: u64,
+    snippet_cooldown_ms: u64,
+    snippet_min_confidence: f32,
+    spoken_punctuation: bool,
+    autocap_after_punct: bool,
+    auto_ai_cleanup: Option<String>,
+    audio_host: Option<String>,
+    model_load_retries: u32,
+    interim_strategy: DecodeStrategy,
+    final_strategy: DecodeStrategy,
+    language: String,
+    translate_mode: bool,
+    snippet_end_only: bool,
+    resample_quality: Option<ResampleQuality>,
+    min_words_for_ai: usize,
+    filler_words: Option<Vec<String>>,
+    dedup_repeats: bool,
+    max_repeats: usize,
+    injection_mode: InjectionMode,
+    strip_trailing_punct: bool,
+    live_processing: bool,
+    vad_threshold: f32,
+    turn_gap_ms: u64,
+    input_device: Option<String>,
+    ai_chunk_chars: usize,
+    snippet_normalize: bool,
+    snippet_date_formats: (String, String),
+    gpu_fallback: bool,
+    snippet_fuzzy: bool,
+    snippet_fuzzy_distance: usize,
+    keyword_boost_words: Vec<String>,
+    keyword_boost_weight: f32,
+    ollama_timeout_ms: u64,
+    ollama_retries: u32,
+    ai_system_prompt: Option<String>,
+    ai_provider: AiProvider,
+    whisper_mode_dsp: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            whisper_mode: false,
+            semantic_correction: false,
+            strip_nonspeech_tags: true,
+            suppress_blank: true,
+            suppress_non_speech: false,
+            min_interim_words: 0,
+            max_buffer_ms: (DEFAULT_MAX_BUFFER_SAMPLES * 1000 / SAMPLE_RATE) as u64,
+            clip_warn_ratio: DEFAULT_CLIP_WARN_RATIO,
+            snippet_expansion: true,
+            snippet_escape_prefix: DEFAULT_SNIPPET_ESCAPE_PREFIX.to_string(),
+            snippet_resolution: SnippetResolution::Longest,
+            snippet_prefix: None,
+            text_transforms: Vec::new(),
+            output_ipa: false,
+            profanity_filter: false,
+            profanity_list: DEFAULT_PROFANITY_LIST.iter().map(|w| w.to_string()).collect(),
+            flatten_newlines: false,
+            persist_metrics: false,
+            max_ai_input_chars: DEFAULT_MAX_AI_INPUT_CHARS,
+            max_segment_chars: 0,
+            split_on_word: false,
+            max_snippet_chars: DEFAULT_MAX_SNIPPET_CHARS,
+            auto_code_prompt: false,
+            thread_count: 0,
+            min_session_ms: DEFAULT_MIN_SESSION_MS,
+            snippet_cooldown_ms: 0,
+            snippet_min_confidence: 0.0,
+            spoken_punctuation: false,
+            autocap_after_punct: true,
+            auto_ai_cleanup: None,
+            audio_host: None,
+            model_load_retries: DEFAULT_MODEL_LOAD_RETRIES as u32,
+            interim_strategy: DecodeStrategy::Greedy,
+            final_strategy: DecodeStrategy::Beam,
+            language: DEFAULT_LANGUAGE.to_string(),
+            translate_mode: false,
+            snippet_end_only: false,
+            resample_quality: None,
+            min_words_for_ai: DEFAULT_MIN_WORDS_FOR_AI,
+            filler_words: None,
+            dedup_repeats: false,
+            max_repeats: DEFAULT_MAX_REPEATS,
+            injection_mode: InjectionMode::Keystroke,
+            strip_trailing_punct: false,
+            live_processing: true,
+            vad_threshold: DEFAULT_VAD_THRESHOLD_RMS,
+            turn_gap_ms: DEFAULT_TURN_GAP_MS,
+            input_device: None,
+            ai_chunk_chars: 0,
+            snippet_normalize: false,
+            snippet_date_formats: (DEFAULT_SNIPPET_DATE_FORMAT.to_string(), DEFAULT_SNIPPET_TIME_FORMAT.to_string()),
+            gpu_fallback: true,
+            snippet_fuzzy: false,
+            snippet_fuzzy_distance: DEFAULT_SNIPPET_FUZZY_DISTANCE,
+            keyword_boost_words: Vec::new(),
+            keyword_boost_weight: 0.0,
+            ollama_timeout_ms: DEFAULT_OLLAMA_TIMEOUT_MS,
+            ollama_retries: DEFAULT_OLLAMA_RETRIES as u32,
+            ai_system_prompt: None,
+            ai_provider: AiProvider::Ollama,
+            whisper_mode_dsp: false,
+        }
+    }
+}
+
+impl Settings {
+    fn current() -> Self {
+        let keyword_boost = KEYWORD_BOOST.lock().unwrap().clone();
+        Settings {
+            whisper_mode: WHISPER_MODE.load(Ordering::SeqCst),
+            semantic_correction: SEMANTIC_CORRECTION.load(Ordering::SeqCst),
+            strip_nonspeech_tags: STRIP_NONSPEECH_TAGS.load(Ordering::SeqCst),
+            suppress_blank: SUPPRESS_BLANK.load(Ordering::SeqCst),
+            suppress_non_speech: SUPPRESS_NON_SPEECH.load(Ordering::SeqCst),
+            min_interim_words: MIN_INTERIM_WORDS.load(Ordering::SeqCst),
+            max_buffer_ms: (MAX_BUFFER_SAMPLES.load(Ordering::SeqCst) * 1000 / SAMPLE_RATE) as u64,
+            clip_warn_ratio: f32::from_bits(CLIP_WARN_RATIO.load(Ordering::SeqCst)),
+            snippet_expansion: SNIPPET_EXPANSION.load(Ordering::SeqCst),
+            snippet_escape_prefix: SNIPPET_ESCAPE_PREFIX.lock().unwrap().clone(),
+            snippet_resolution: *SNIPPET_RESOLUTION.lock().unwrap(),
+            snippet_prefix: SNIPPET_PREFIX.lock().unwrap().clone(),
+            text_transforms: list_text_transforms(),
+            output_ipa: OUTPUT_IPA.load(Ordering::SeqCst),
+            profanity_filter: PROFANITY_FILTER.load(Ordering::SeqCst),
+            profanity_list: PROFANITY_LIST.lock().unwrap().0.clone(),
+            flatten_newlines: FLATTEN_NEWLINES.load(Ordering::SeqCst),
+            persist_metrics: PERSIST_METRICS.load(Ordering::SeqCst),
+            max_ai_input_chars: MAX_AI_INPUT_CHARS.load(Ordering::SeqCst),
+            max_segment_chars: MAX_SEGMENT_CHARS.load(Ordering::SeqCst) as i32,
+            split_on_word: SPLIT_ON_WORD.load(Ordering::SeqCst),
+            max_snippet_chars: MAX_SNIPPET_CHARS.load(Ordering::SeqCst),
+            auto_code_prompt: AUTO_CODE_PROMPT.load(Ordering::SeqCst),
+            thread_count: THREAD_COUNT.load(Ordering::SeqCst) as u32,
+            min_session_ms: MIN_SESSION_MS.load(Ordering::SeqCst) as u64,
+            snippet_cooldown_ms: SNIPPET_COOLDOWN_MS.load(Ordering::SeqCst) as u64,
+            snippet_min_confidence: f32::from_bits(SNIPPET_MIN_CONFIDENCE.load(Ordering::SeqCst)),
+            spoken_punctuation: SPOKEN_PUNCTUATION_ENABLED.load(Ordering::SeqCst),
+            autocap_after_punct: AUTOCAP_AFTER_PUNCT.load(Ordering::SeqCst),
+            auto_ai_cleanup: AUTO_AI_CLEANUP.lock().unwrap().clone(),
+            audio_host: AUDIO_HOST.lock().unwrap().clone(),
+            model_load_retries: MODEL_LOAD_RETRIES.load(Ordering::SeqCst) as u32,
+            interim_strategy: *INTERIM_STRATEGY.lock().unwrap(),
+            final_strategy: *FINAL_STRATEGY.lock().unwrap(),
+            language: LANGUAGE.lock().unwrap().unwrap_or("auto").to_string(),
+            translate_mode: TRANSLATE_MODE.load(Ordering::SeqCst),
+            snippet_end_only: SNIPPET_END_ONLY.load(Ordering::SeqCst),
+            resample_quality: *RESAMPLE_QUALITY.lock().unwrap(),
+            min_words_for_ai: MIN_WORDS_FOR_AI.load(Ordering::SeqCst),
+            filler_words: FILLER_WORDS.lock().unwrap().clone(),
+            dedup_repeats: DEDUP_REPEATS.load(Ordering::SeqCst),
+            max_repeats: MAX_REPEATS.load(Ordering::SeqCst),
+            injection_mode: *INJECTION_MODE.lock().unwrap(),
+            strip_trailing_punct: STRIP_TRAILING_PUNCT.load(Ordering::SeqCst),
+            live_processing: LIVE_PROCESSING.load(Ordering::SeqCst),
+            vad_threshold: get_vad_threshold(),
+            turn_gap_ms: TURN_GAP_MS.load(Ordering::SeqCst) as u64,
+            input_device: INPUT_DEVICE.lock().unwrap().clone(),
+            ai_chunk_chars: AI_CHUNK_CHARS.load(Ordering::SeqCst),
+            snippet_normalize: SNIPPET_NORMALIZE.load(Ordering::SeqCst),
+            snippet_date_formats: SNIPPET_DATE_FORMATS.lock().unwrap().clone(),
+            gpu_fallback: GPU_FALLBACK.load(Ordering::SeqCst),
+            snippet_fuzzy: SNIPPET_FUZZY.load(Ordering::SeqCst),
+            snippet_fuzzy_distance: SNIPPET_FUZZY_DISTANCE.load(Ordering::SeqCst),
+            keyword_boost_words: keyword_boost.0.clone(),
+            keyword_boost_weight: keyword_boost.1,
+            ollama_timeout_ms: OLLAMA_TIMEOUT_MS.load(Ordering::SeqCst),
+            ollama_retries: OLLAMA_RETRIES.load(Ordering::SeqCst) as u32,
+            ai_system_prompt: CUSTOM_AI_SYSTEM_PROMPT.lock().unwrap().clone(),
+            ai_provider: *AI_PROVIDER.lock().unwrap(),
+            whisper_mode_dsp: WHISPER_MODE_DSP.load(Ordering::SeqCst),
+        }
+    }
+
+    fn apply(&self) {
+        WHISPER_MODE.store(self.whisper_mode, Ordering::SeqCst);
+        SEMANTIC_CORRECTION.store(self.semantic_correction, Ordering::SeqCst);
+        STRIP_NONSPEECH_TAGS.store(self.strip_nonspeech_tags, Ordering::SeqCst);
+        SUPPRESS_BLANK.store(self.suppress_blank, Ordering::SeqCst);
+        SUPPRESS_NON_SPEECH.store(self.suppress_non_speech, Ordering::SeqCst);
+        MIN_INTERIM_WORDS.store(self.min_interim_words, Ordering::SeqCst);
+        MAX_BUFFER_SAMPLES.store(self.max_buffer_ms as usize * SAMPLE_RATE / 1000, Ordering::SeqCst);
+        CLIP_WARN_RATIO.store(self.clip_warn_ratio.to_bits(), Ordering::SeqCst);
+        SNIPPET_EXPANSION.store(self.snippet_expansion, Ordering::SeqCst);
+        *SNIPPET_ESCAPE_PREFIX.lock().unwrap() = self.snippet_escape_prefix.clone();
+        *SNIPPET_RESOLUTION.lock().unwrap() = self.snippet_resolution;
+        *SNIPPET_PREFIX.lock().unwrap() = self.snippet_prefix.clone();
+        set_text_transforms(&self.text_transforms);
+        OUTPUT_IPA.store(self.output_ipa, Ordering::SeqCst);
+        PROFANITY_FILTER.store(self.profanity_filter, Ordering::SeqCst);
+        FLATTEN_NEWLINES.store(self.flatten_newlines, Ordering::SeqCst);
+        PERSIST_METRICS.store(self.persist_metrics, Ordering::SeqCst);
+        MAX_AI_INPUT_CHARS.store(self.max_ai_input_chars, Ordering::SeqCst);
+        MAX_SEGMENT_CHARS.store(self.max_segment_chars.max(0) as usize, Ordering::SeqCst);
+        SPLIT_ON_WORD.store(self.split_on_word, Ordering::SeqCst);
+        if let Err(e) = set_profanity_list(self.profanity_list.clone()) {
+            eprintln!("keeping previous profanity list: {:#}", e);
+        }
+        MAX_SNIPPET_CHARS.store(self.max_snippet_chars, Ordering::SeqCst);
+        AUTO_CODE_PROMPT.store(self.auto_code_prompt, Ordering::SeqCst);
+        THREAD_COUNT.store(self.thread_count as usize, Ordering::SeqCst);
+        MIN_SESSION_MS.store(self.min_session_ms as usize, Ordering::SeqCst);
+        SNIPPET_COOLDOWN_MS.store(self.snippet_cooldown_ms as usize, Ordering::SeqCst);
+        SNIPPET_MIN_CONFIDENCE.store(self.snippet_min_confidence.to_bits(), Ordering::SeqCst);
+        SPOKEN_PUNCTUATION_ENABLED.store(self.spoken_punctuation, Ordering::SeqCst);
+        AUTOCAP_AFTER_PUNCT.store(self.autocap_after_punct, Ordering::SeqCst);
+        *AUTO_AI_CLEANUP.lock().unwrap() = self.auto_ai_cleanup.clone();
+        *AUDIO_HOST.lock().unwrap() = self.audio_host.clone();
+        MODEL_LOAD_RETRIES.store(self.model_load_retries as usize, Ordering::SeqCst);
+        *INTERIM_STRATEGY.lock().unwrap() = self.interim_strategy;
+        *FINAL_STRATEGY.lock().unwrap() = self.final_strategy;
+        *LANGUAGE.lock().unwrap() = parse_language(&self.language).unwrap_or(Some(DEFAULT_LANGUAGE));
+        TRANSLATE_MODE.store(self.translate_mode, Ordering::SeqCst);
+        SNIPPET_END_ONLY.store(self.snippet_end_only, Ordering::SeqCst);
+        *RESAMPLE_QUALITY.lock().unwrap() = self.resample_quality;
+        MIN_WORDS_FOR_AI.store(self.min_words_for_ai, Ordering::SeqCst);
+        match &self.filler_words {
+            Some(words) => { let _ = set_filler_words(words.clone()); }
+            None => { let _ = reset_filler_words(); }
+        }
+        DEDUP_REPEATS.store(self.dedup_repeats, Ordering::SeqCst);
+        MAX_REPEATS.store(self.max_repeats.max(1), Ordering::SeqCst);
+        *INJECTION_MODE.lock().unwrap() = self.injection_mode;
+        STRIP_TRAILING_PUNCT.store(self.strip_trailing_punct, Ordering::SeqCst);
+        LIVE_PROCESSING.store(self.live_processing, Ordering::SeqCst);
+        if set_vad_threshold(self.vad_threshold).is_err() {
+            eprintln!("ignoring invalid VAD threshold {}", self.vad_threshold);
+        }
+        TURN_GAP_MS.store(self.turn_gap_ms.max(1) as usize, Ordering::SeqCst);
+        *INPUT_DEVICE.lock().unwrap() = self.input_device.clone();
+        AI_CHUNK_CHARS.store(self.ai_chunk_chars, Ordering::SeqCst);
+        SNIPPET_NORMALIZE.store(self.snippet_normalize, Ordering::SeqCst);
+        let (date, time) = self.snippet_date_formats.clone();
+        if let Err(e) = set_snippet_date_format(date, time) {
+            eprintln!("keeping previous snippet date formats: {:#}", e);
+        }
+        GPU_FALLBACK.store(self.gpu_fallback, Ordering::SeqCst);
+        SNIPPET_FUZZY.store(self.snippet_fuzzy, Ordering::SeqCst);
+        SNIPPET_FUZZY_DISTANCE.store(self.snippet_fuzzy_distance, Ordering::SeqCst);
+        if let Err(e) = set_keyword_boost(self.keyword_boost_words.clone(), self.keyword_boost_weight) {
+            eprintln!("ignoring invalid keyword boost: {:#}", e);
+        }
+        OLLAMA_TIMEOUT_MS.store(self.ollama_timeout_ms.max(1), Ordering::SeqCst);
+        OLLAMA_RETRIES.store(self.ollama_retries as usize, Ordering::SeqCst);
+        *CUSTOM_AI_SYSTEM_PROMPT.lock().unwrap() = self.ai_system_prompt.clone().filter(|p| !p.trim().is_empty());
+        *AI_PROVIDER.lock().unwrap() = self.ai_provider;
+        WHISPER_MODE_DSP.store(self.whisper_mode_dsp, Ordering::SeqCst);
+    }
+
+    /// Names of the fields that differ between two snapshots
+    fn changed_fields(&self, other: &Settings) -> Vec<String> {
+        let (Ok(serde_json::Value::Object(a)), Ok(serde_json::Value::Object(b))) =
+            (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return Vec::new();
+        };
+        a.iter()
+            .filter(|(key, value)| b.get(*key) != Some(*value))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+fn get_settings_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("settings.json"))
+}
+
+fn write_settings(settings: &Settings) -> Result<()> {
+    let path = get_settings_path()?;
+    write_data_file(&path, serde_json::to_string_pretty(settings)?, "settings file")?;
+    *PERSISTED_SETTINGS.lock().unwrap() = Some(settings.clone());
+    Ok(())
+}
+
+/// Save the current preferences to settings.json, next to snippets.json
+pub fn save_settings() -> Result<(), Fair9Error> {
+    Ok(write_settings(&Settings::current())?)
+}
+
+/// Restore preferences from settings.json at startup. Returns true if saved settings
+/// were applied. A missing file means defaults; so does an unreadable or corrupt one,
+/// which is logged and left on disk until the next explicit save. Fields the file
+/// lacks (it was written by an older version) keep their defaults.
+pub fn load_settings() -> Result<bool, Fair9Error> {
+    let loaded = get_settings_path().and_then(|path| {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(&path).context("Failed to read settings file")?;
+        Ok(Some(serde_json::from_str::<Settings>(&data).context("Invalid settings file")?))
+    });
+    match loaded {
+        Ok(Some(settings)) => {
+            settings.apply();
+            *PERSISTED_SETTINGS.lock().unwrap() = Some(settings);
+            Ok(true)
+        }
+        Ok(None) => {
+            let defaults = Settings::default();
+            defaults.apply();
+            *PERSISTED_SETTINGS.lock().unwrap() = Some(defaults);
+            Ok(false)
+        }
+        Err(e) => {
+            eprintln!("using default settings: {:#}", e);
+            Settings::default().apply();
+            *PERSISTED_SETTINGS.lock().unwrap() = None;
+            Ok(false)
+        }
+    }
+}
+
+/// Settings changed since settings.json was last read or written. If it hasn't been
+/// touched this session there's nothing to compare against, and nothing is pending.
+fn settings_pending() -> Option<Settings> {
+    let current = Settings::current();
+    let persisted = PERSISTED_SETTINGS.lock().unwrap();
+    persisted.as_ref().filter(|saved| **saved != current)?;
+    Some(current)
+}
+
+/// Restore every setting to its default, in memory and in settings.json.
+/// Snippets are left alone. Returns a short summary of what changed.
+pub fn reset_settings() -> Result<String, Fair9Error> {
+    let before = Settings::current();
+    let defaults = Settings::default();
+    defaults.apply();
+    write_settings(&defaults)?;
+
+    let changed = before.changed_fields(&defaults);
+    if changed.is_empty() {
+        Ok("All settings were already at their defaults".to_string())
+    } else {
+        Ok(format!("Reset {}", changed.join(", ")))
+    }
+}
+
+// ── Voice Snippets ───────────────────────────────────────────────────
+
+/// Bumped whenever the snippet file layout changes; see `migrate_snippets`.
+/// v0: no `version` field, snippets may lack `id` and `variants`.
+/// v1: every snippet has an id and at least one variant.
+const SNIPPET_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct SnippetFile {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    snippets: Vec<VoiceSnippet>,
+}
+
+/// Bring a snippet file up to SNIPPET_SCHEMA_VERSION. Also returns whether anything
+/// changed, so the loader knows to write the upgraded file back.
+fn migrate_snippets(file: SnippetFile) -> Result<(Vec<VoiceSnippet>, bool)> {
+    if file.version > SNIPPET_SCHEMA_VERSION {
+        // Loading it would silently drop fields this build doesn't know about
+        return Err(anyhow!(
+            "Snippets file is version {}, but this version of Fair9 only understands up to {}",
+            file.version, SNIPPET_SCHEMA_VERSION
+        ));
+    }
+    // v0 → v1 is filling in ids and variants; upgrade_snippet also repairs hand-edited v1 files
+    let changed = file.version < SNIPPET_SCHEMA_VERSION
+        || file.snippets.iter().any(|s| s.id.trim().is_empty() || s.variants.is_empty());
+    Ok((file.snippets.into_iter().map(upgrade_snippet).collect(), changed))
+}
+
+/// Maximum snippet content length (in characters) accepted by add/import
+pub fn set_max_snippet_chars(n: usize) -> Result<(), Fair9Error> {
+    if n == 0 {
+        return Err(Fair9Error::InvalidArgument("Max snippet size must be greater than zero".to_string()));
+    }
+    MAX_SNIPPET_CHARS.store(n, Ordering::SeqCst);
+    Ok(())
+}
+
+fn validate_snippet(trigger: &str, content: &str) -> Result<()> {
+    if trigger.trim().is_empty() {
+        return Err(anyhow!("Snippet trigger cannot be empty"));
+    }
+    let max = MAX_SNIPPET_CHARS.load(Ordering::SeqCst);
+    let len = content.chars().count();
+    if len > max {
+        return Err(anyhow!(
+            "Snippet '{}' is {} characters, over the {} character limit",
+            trigger, len, max
+        ));
+    }
+    Ok(())
+}
+
+pub fn add_snippet(trigger: String, content: String) -> Result<(), Fair9Error> {
+    add_snippet_variants(trigger, vec![content])
+}
+
+/// Add a snippet whose expansion is picked at random from `variants`,
+/// e.g. several email sign-offs behind one trigger
+pub fn add_snippet_variants(trigger: String, variants: Vec<String>) -> Result<(), Fair9Error> {
+    Ok(push_snippet(trigger, variants, false)?)
+}
+
+fn push_snippet(trigger: String, variants: Vec<String>, is_regex: bool) -> Result<()> {
+    let variants: Vec<String> = variants.into_iter().map(stored_snippet_content).collect();
+    let Some(first) = variants.first() else {
+        return Err(anyhow!("Snippet '{}' needs at least one variant", trigger.trim()));
+    };
+    for content in &variants {
+        validate_snippet(&trigger, content)?;
+    }
+
+    let mut store = SNIPPETS.lock().unwrap();
+    if store.iter().any(|s| s.trigger.eq_ignore_ascii_case(trigger.trim())) {
+        return Err(Fair9Error::SnippetExists(trigger.trim().to_string()).into());
+    }
+    store.push(VoiceSnippet {
+        id: new_snippet_id(),
+        trigger: trigger.trim().to_string(),
+        content: first.clone(),
+        variants,
+        is_regex,
+    });
+    SNIPPETS_DIRTY.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Add a snippet whose trigger is a regular expression matched (case-insensitively)
+/// against the end of the utterance, e.g. `call (?P<name>\w+)` with content
+/// "Calling $name". Use `${1}` when a group is followed by letters or digits.
+pub fn add_regex_snippet(pattern: String, content: String) -> Result<(), Fair9Error> {
+    snippet_regex(pattern.trim()).map_err(|e| anyhow!("Invalid snippet pattern '{}': {}", pattern.trim(), e))?;
+    Ok(push_snippet(pattern, vec![content], true)?)
+}
+
+/// Anchored to the end of the utterance, like literal triggers
+fn snippet_regex(pattern: &str) -> std::result::Result<Regex, regex::Error> {
+    regex::RegexBuilder::new(&format!("(?:{})$", pattern)).case_insensitive(true).build()
+}
+
+/// Compiled regex trigger, or None (logged once) if the pattern doesn't compile
+fn cached_snippet_regex(pattern: &str) -> Option<Regex> {
+    let mut cache = SNIPPET_REGEX_CACHE.lock().unwrap();
+    cache.entry(pattern.to_string())
+        .or_insert_with(|| match snippet_regex(pattern) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                eprintln!("skipping snippet with invalid trigger pattern '{}': {}", pattern, e);
+                None
+            }
+        })
+        .clone()
+}
+
+/// Clean up pasted snippet content as it's saved: trailing spaces and tabs are
+/// trimmed from each line and CRLF/CR become LF. Blank lines are kept. Off by default.
+pub fn set_snippet_normalize(enabled: bool) -> Result<(), Fair9Error> {
+    SNIPPET_NORMALIZE.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+fn stored_snippet_content(content: String) -> String {
+    if SNIPPET_NORMALIZE.load(Ordering::SeqCst) {
+        normalize_snippet_content(&content)
+    } else {
+        content
+    }
+}
+
+fn normalize_snippet_content(content: &str) -> String {
+    content
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .split('\n')
+        .map(|line| line.trim_end_matches([' ', '\t']))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn new_snippet_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Fill in what older files and imports lack: single-content snippets become a
+/// one-element variant list, and snippets without an id get a fresh one
+fn upgrade_snippet(mut snippet: VoiceSnippet) -> VoiceSnippet {
+    if snippet.variants.is_empty() {
+        snippet.variants = vec![snippet.content.clone()];
+    }
+    if snippet.id.trim().is_empty() {
+        snippet.id = new_snippet_id();
+    }
+    snippet
+}
+
+/// Remove the snippet with this trigger (case-insensitive)
+pub fn remove_snippet(trigger: String) -> Result<(), Fair9Error> {
+    remove_snippet_where(|s| s.trigger.eq_ignore_ascii_case(trigger.trim()))
+        .ok_or_else(|| Fair9Error::SnippetNotFound(trigger.clone()))
+}
+
+/// Remove a snippet by its id, which unlike the trigger never changes
+pub fn remove_snippet_by_id(id: String) -> Result<(), Fair9Error> {
+    remove_snippet_where(|s| s.id == id)
+        .ok_or(Fair9Error::SnippetNotFound(id))
+}
+
+fn remove_snippet_where(pred: impl Fn(&VoiceSnippet) -> bool) -> Option<()> {
+    let mut store = SNIPPETS.lock().unwrap();
+    let index = store.iter().position(pred)?;
+    store.remove(index);
+    SNIPPETS_DIRTY.store(true, Ordering::SeqCst);
+    Some(())
+}
+
+/// Seed the generator behind variant selection, for reproducible expansions
+pub fn set_snippet_seed(seed: u64) -> Result<(), Fair9Error> {
+    *SNIPPET_RNG.lock().unwrap() = seed;
+    Ok(())
+}
+
+/// The content to type for one expansion: a random variant, or `content` if there are none
+fn pick_variant(snippet: &VoiceSnippet) -> String {
+    match snippet.variants.len() {
+        0 => snippet.content.clone(),
+        1 => snippet.variants[0].clone(),
+        n => snippet.variants[roll_variant(n)].clone(),
+    }
+}
+
+/// `pick_variant` for a streamed pass. The first interim to show a trigger rolls,
+/// later interims and the final reuse that roll, so the preview doesn't flicker
+/// between variants and the typed text is the one that was shown.
+fn pick_utterance_variant(snippet: &VoiceSnippet, is_final: bool) -> String {
+    let n = snippet.variants.len();
+    if n < 2 {
+        return pick_variant(snippet);
+    }
+    let mut rolled = SNIPPET_UTTERANCE_VARIANTS.lock().unwrap();
+    let key = snippet.trigger.trim().to_lowercase();
+    let index = match rolled.get(&key) {
+        Some(&index) if index < n => index,
+        _ => roll_variant(n),
+    };
+    if !is_final {
+        rolled.insert(key, index);
+    }
+    snippet.variants[index].clone()
+}
+
+fn roll_variant(n: usize) -> usize {
+    (next_random(&mut SNIPPET_RNG.lock().unwrap()) % n as u64) as usize
+}
+
+/// SplitMix64: tiny, seedable, and plenty random for picking a sign-off
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Import snippets from a `{"snippets": [...]}` JSON document.
+/// The whole import is rejected if any entry is invalid; duplicates are skipped.
+/// Returns the number of snippets added.
+pub fn import_snippets(json: String) -> Result<usize, Fair9Error> {
+    let file: SnippetFile = serde_json::from_str(&json).context("Invalid snippets JSON")?;
+    let (snippets, _) = migrate_snippets(file)?;
+    let snippets: Vec<VoiceSnippet> = snippets.into_iter()
+        .map(|s| VoiceSnippet {
+            content: stored_snippet_content(s.content),
+            variants: s.variants.into_iter().map(stored_snippet_content).collect(),
+            ..s
+        })
+        .collect();
+    for snippet in &snippets {
+        validate_snippet(&snippet.trigger, &snippet.content)?;
+        for variant in &snippet.variants {
+            validate_snippet(&snippet.trigger, variant)?;
+        }
+    }
+
+    let mut store = SNIPPETS.lock().unwrap();
+    let mut added = 0;
+    for snippet in snippets {
+        if store.iter().any(|s| s.trigger.eq_ignore_ascii_case(snippet.trigger.trim())) {
+            continue;
+        }
+        // Keep an imported id unless it would clash with one we already have
+        let id = if store.iter().any(|s| s.id == snippet.id) { String::new() } else { snippet.id };
+        store.push(upgrade_snippet(VoiceSnippet {
+            id,
+            trigger: snippet.trigger.trim().to_string(),
+            ..snippet
+        }));
+        added += 1;
+    }
+    if added > 0 {
+        SNIPPETS_DIRTY.store(true, Ordering::SeqCst);
+    }
+    Ok(added)
+}
+
+fn get_snippets_path() -> Result<PathBuf> {
+    let active = ACTIVE_SNIPPET_LIBRARY.lock().unwrap().clone();
+    snippet_library_path(&active)
+}
+
+/// "default" keeps living at snippets.json; named libraries go in snippet_libraries/<name>.json
+fn snippet_library_path(name: &str) -> Result<PathBuf> {
+    let dir = get_data_dir()?;
+    if name == DEFAULT_SNIPPET_LIBRARY {
+        return Ok(dir.join("snippets.json"));
+    }
+    Ok(dir.join("snippet_libraries").join(format!("{}.json", name)))
+}
+
+fn validate_library_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ');
+    if !valid {
+        return Err(anyhow!("Invalid library name '{}': use letters, digits, spaces, '-' or '_'", name));
+    }
+    Ok(())
+}
+
+/// All snippet libraries on disk, plus "default" which always exists
+pub fn list_snippet_libraries() -> Result<Vec<String>, Fair9Error> {
+    let mut names = vec![DEFAULT_SNIPPET_LIBRARY.to_string()];
+    let dir = get_data_dir()?.join("snippet_libraries");
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                if stem != DEFAULT_SNIPPET_LIBRARY {
+                    names.push(stem.to_string());
+                }
+            }
+        }
+    }
+    names[1..].sort();
+    Ok(names)
+}
+
+pub fn get_active_snippet_library() -> String {
+    ACTIVE_SNIPPET_LIBRARY.lock().unwrap().clone()
+}
+
+/// Save the current library, then load `name` into the store.
+/// Switching to a library that doesn't exist yet creates it empty.
+pub fn switch_snippet_library(name: String) -> Result<usize, Fair9Error> {
+    let name = name.trim().to_string();
+    validate_library_name(&name)?;
+
+    flush_pending_writes()?;
+    *ACTIVE_SNIPPET_LIBRARY.lock().unwrap() = name.clone();
+
+    let path = snippet_library_path(&name)?;
+    if !path.exists() {
+        SNIPPETS.lock().unwrap().clear();
+        save_snippets()?;
+        return Ok(0);
+    }
+    load_snippets()
+}
+
+/// Replace the in-memory store with `snippets.json`. A missing file loads as empty.
+pub fn load_snippets() -> Result<usize, Fair9Error> {
+    let path = get_snippets_path()?;
+    let (snippets, migrated) = if path.exists() {
+        let data = fs::read_to_string(&path).context("Failed to read snippets file")?;
+        migrate_snippets(serde_json::from_str(&data).context("Invalid snippets file")?)?
+    } else {
+        (Vec::new(), false)
+    };
+
+    let count = snippets.len();
+    *SNIPPETS.lock().unwrap() = snippets;
+    SNIPPETS_DIRTY.store(false, Ordering::SeqCst);
+    // Write the upgrade back once so generated ids stay stable across launches
+    if migrated {
+        if let Err(e) = save_snippets() {
+            eprintln!("failed to save migrated snippets: {:#}", e);
+            SNIPPETS_DIRTY.store(true, Ordering::SeqCst);
+        }
+    }
+    Ok(count)
+}
+
+pub fn save_snippets() -> Result<(), Fair9Error> {
+    let path = get_snippets_path()?;
+    let data = {
+        let store = SNIPPETS.lock().unwrap();
+        serde_json::to_string_pretty(&json!({ "version": SNIPPET_SCHEMA_VERSION, "snippets": *store }))?
+    };
+    write_data_file(&path, data, "snippets file")?;
+    SNIPPETS_DIRTY.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Write everything still pending to disk right away: snippet edits, changed
+/// settings, AI command history and (when persisted) metrics. Call it when the app
+/// is paused or the machine is about to sleep.
+pub fn flush_all() -> Result<(), Fair9Error> {
+    Ok(flush_pending_writes()?)
+}
+
+fn flush_pending_writes() -> Result<()> {
+    if SNIPPETS_DIRTY.load(Ordering::SeqCst) {
+        save_snippets()?;
+    }
+    if let Some(settings) = settings_pending() {
+        write_settings(&settings)?;
+    }
+    if HISTORY_DIRTY.load(Ordering::SeqCst) {
+        save_command_history()?;
+    }
+    if PERSIST_METRICS.load(Ordering::SeqCst) && METRICS_DIRTY.load(Ordering::SeqCst) {
+        save_transcription_metrics()?;
+    }
+    Ok(())
+}
+
+/// Type out a snippet's content. Large expansions are refused unless `confirmed`
+/// is set, so the UI can ask the user first instead of typing for minutes.
+pub fn inject_snippet(trigger: String, delay_ms: u64, confirmed: bool) -> Result<(), Fair9Error> {
+    let expansion = SNIPPETS.lock().unwrap().iter()
+        .find(|s| s.trigger.eq_ignore_ascii_case(trigger.trim()))
+        .map(|s| snippet_output(pick_variant(s), true))
+        .ok_or_else(|| Fair9Error::SnippetNotFound(trigger.clone()))?;
+    if needs_injection_confirmation(&expansion.text) && !confirmed {
+        return Err(Fair9Error::SnippetNeedsConfirmation {
+            trigger,
+            chars: expansion.text.chars().count() as u32,
+        });
+    }
+    inject_with_cursor(expansion.text, expansion.cursor_offset, delay_ms)
+}
+
+fn needs_injection_confirmation(content: &str) -> bool {
+    content.chars().count() > SNIPPET_CONFIRM_CHARS
+}
+
+/// Globally suspend (or resume) snippet expansion in the pipeline
+pub fn set_snippet_expansion(enabled: bool) -> Result<(), Fair9Error> {
+    SNIPPET_EXPANSION.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Spoken prefix that bypasses expansion for one utterance. Empty disables the escape.
+pub fn set_snippet_escape_prefix(prefix: String) -> Result<(), Fair9Error> {
+    *SNIPPET_ESCAPE_PREFIX.lock().unwrap() = prefix.trim().to_string();
+    Ok(())
+}
+
+/// Which trigger wins when several match the same utterance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SnippetResolution {
+    First,    // Store order
+    Longest,  // Most specific trigger
+    Shortest,
+}
+
+impl SnippetResolution {
+    fn parse(mode: &str) -> Result<Self> {
+        match mode.trim().to_lowercase().as_str() {
+            "first" => Ok(SnippetResolution::First),
+            "longest" => Ok(SnippetResolution::Longest),
+            "shortest" => Ok(SnippetResolution::Shortest),
+            other => Err(anyhow!("Unknown snippet resolution '{}' (expected first, longest or shortest)", other)),
+        }
+    }
+}
+
+/// Pick the winner when an utterance matches several triggers: "first", "longest" (default) or "shortest"
+pub fn set_snippet_resolution(mode: String) -> Result<(), Fair9Error> {
+    *SNIPPET_RESOLUTION.lock().unwrap() = SnippetResolution::parse(&mode)?;
+    Ok(())
+}
+
+/// "Okay, Insert bio." → ["okay", "insert", "bio"] so Whisper's casing and punctuation
+/// don't block a match. Punctuation-only tokens stay as "" to keep indices aligned.
+fn snippet_words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|w| w.trim_matches(|c: char| c.is_ascii_punctuation()).to_lowercase())
+        .collect()
+}
+
+/// Token index where `trigger` starts if it is the whole utterance or its trailing words
+fn trigger_suffix_start(utterance: &[String], trigger: &str) -> Option<usize> {
+    let trigger: Vec<String> = snippet_words(trigger).into_iter().filter(|w| !w.is_empty()).collect();
+    let words: Vec<(usize, &String)> = utterance.iter().enumerate().filter(|(_, w)| !w.is_empty()).collect();
+    if trigger.is_empty() || trigger.len() > words.len() {
+        return None;
+    }
+    let tail = &words[words.len() - trigger.len()..];
+    if tail.iter().zip(&trigger).all(|((_, word), t)| *word == t) {
+        Some(tail[0].0)
+    } else {
+        None
+    }
+}
+
+/// Winning snippet for an utterance and how many leading tokens to keep before its content
+fn find_snippet(utterance: &str) -> Option<(VoiceSnippet, usize)> {
+    let mut words = snippet_words(utterance);
+    let required_prefix = SNIPPET_PREFIX.lock().unwrap().clone();
+    let prefixed = required_prefix.is_some();
+    if let Some(prefix) = &required_prefix {
+        // "snippet insert bio": the prefix is consumed and the rest must be exactly a trigger
+        words = strip_leading_words(&words, prefix)?;
+    }
+
+    let resolution = *SNIPPET_RESOLUTION.lock().unwrap();
+    let store = SNIPPETS.lock().unwrap();
+    let mut matches = store.iter()
+        .filter_map(|s| {
+            if s.is_regex {
+                return regex_trigger_start(utterance, &s.trigger, required_prefix.as_deref()).map(|start| (s, start));
+            }
+            trigger_suffix_start(&words, &s.trigger).map(|start| (s, start))
+        })
+        .filter(|(s, start)| !prefixed || s.is_regex || *start == 0);
+    let trigger_len = |(s, _): &(&VoiceSnippet, usize)| s.trigger.trim().chars().count();
+    // min_by_key keeps the earliest of equal candidates, so ties fall back to store order
+    let best = match resolution {
+        SnippetResolution::First => matches.next(),
+        SnippetResolution::Longest => matches.min_by_key(|m| std::cmp::Reverse(trigger_len(m))),
+        SnippetResolution::Shortest => matches.min_by_key(trigger_len),
+    };
+    let fuzzy = SNIPPET_FUZZY.load(Ordering::SeqCst);
+    let best = best.or_else(|| {
+        if !fuzzy {
+            return None;
+        }
+        let max_distance = SNIPPET_FUZZY_DISTANCE.load(Ordering::SeqCst);
+        store.iter()
+            .filter(|s| !s.is_regex)
+            .filter_map(|s| fuzzy_trigger_start(&words, &s.trigger, max_distance).map(|(start, d)| (s, start, d)))
+            .filter(|(_, start, _)| !prefixed || *start == 0)
+            .min_by_key(|(_, _, d)| *d)
+            .map(|(s, start, _)| (s, start))
+    });
+    best.map(|(s, start)| {
+        let snippet = if s.is_regex { fill_captures(s, utterance) } else { s.clone() };
+        (snippet, if prefixed { 0 } else { start })
+    })
+}
+
+/// Token index where a regex trigger's match starts, if it covers the end of the
+/// utterance (trailing punctuation aside) and begins on a word. With a required
+/// prefix, the match must come straight after it.
+fn regex_trigger_start(utterance: &str, pattern: &str, prefix: Option<&str>) -> Option<usize> {
+    let re = cached_snippet_regex(pattern)?;
+    let text = utterance.trim_end_matches(|c: char| c.is_ascii_punctuation() || c.is_whitespace());
+    let found = re.find(text)?;
+    let lead = &text[..found.start()];
+    if !lead.is_empty() && !lead.ends_with(char::is_whitespace) {
+        return None;
+    }
+    if let Some(prefix) = prefix {
+        let spoken: Vec<String> = snippet_words(lead).into_iter().filter(|w| !w.is_empty()).collect();
+        strip_leading_words(&spoken, prefix).filter(|rest| rest.is_empty())?;
+    }
+    Some(lead.split_whitespace().count())
+}
+
+/// The snippet with its regex captures substituted into every variant
+fn fill_captures(snippet: &VoiceSnippet, utterance: &str) -> VoiceSnippet {
+    let text = utterance.trim_end_matches(|c: char| c.is_ascii_punctuation() || c.is_whitespace());
+    let Some(re) = cached_snippet_regex(&snippet.trigger) else {
+        return snippet.clone();
+    };
+    let Some(captures) = re.captures(text) else {
+        return snippet.clone();
+    };
+    let expand = |template: &str| {
+        let mut out = String::new();
+        captures.expand(template, &mut out);
+        out
+    };
+    VoiceSnippet {
+        content: expand(&snippet.content),
+        variants: snippet.variants.iter().map(|v| expand(v)).collect(),
+        ..snippet.clone()
+    }
+}
+
+/// Like `trigger_suffix_start`, but the trailing words only have to be within
+/// `max_distance` edits of the trigger. Returns the start index and the distance.
+/// A trigger can't be matched by more edits than it has characters.
+fn fuzzy_trigger_start(utterance: &[String], trigger: &str, max_distance: usize) -> Option<(usize, usize)> {
+    let trigger = snippet_words(trigger).into_iter().filter(|w| !w.is_empty()).collect::<Vec<_>>().join(" ");
+    let words: Vec<(usize, &String)> = utterance.iter().enumerate().filter(|(_, w)| !w.is_empty()).collect();
+    let trigger_words = trigger.split(' ').count();
+    if trigger.is_empty() || trigger_words > words.len() {
+        return None;
+    }
+    let tail = &words[words.len() - trigger_words..];
+    let spoken = tail.iter().map(|(_, w)| w.as_str()).collect::<Vec<_>>().join(" ");
+    let distance = levenshtein(&spoken, &trigger);
+    (distance <= max_distance && distance < trigger.chars().count()).then(|| (tail[0].0, distance))
+}
+
+/// Let near-miss transcriptions ("insert bile") fire the closest trigger within
+/// `max_distance` character edits. Exact matches always win; among fuzzy ones the
+/// smallest distance wins, then store order. Off by default.
+pub fn set_snippet_fuzzy(enabled: bool, max_distance: usize) -> Result<(), Fair9Error> {
+    SNIPPET_FUZZY_DISTANCE.store(max_distance, Ordering::SeqCst);
+    SNIPPET_FUZZY.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Remaining words when the utterance opens with `prefix`, ignoring case and punctuation
+fn strip_leading_words(words: &[String], prefix: &str) -> Option<Vec<String>> {
+    let mut rest = words.iter().filter(|w| !w.is_empty()).cloned();
+    for expected in snippet_words(prefix).into_iter().filter(|w| !w.is_empty()) {
+        if rest.next()? != expected {
+            return None;
+        }
+    }
+    Some(rest.collect())
+}
+
+/// Require a spoken prefix before triggers ("snippet insert bio"). None or empty restores bare triggers.
+pub fn set_snippet_prefix(prefix: Option<String>) -> Result<(), Fair9Error> {
+    let prefix = prefix.map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+    *SNIPPET_PREFIX.lock().unwrap() = prefix;
+    Ok(())
+}
+
+/// If `text` starts with the escape word, return the rest of the utterance
+fn strip_escape_prefix(text: &str, prefix: &str) -> Option<String> {
+    if prefix.is_empty() {
+        return None;
+    }
+    let trimmed = text.trim_start();
+    let head = trimmed.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = &trimmed[prefix.len()..];
+    // Must be a whole word: "literally," or "literally insert", not "literallyx"
+    if !rest.is_empty() && !rest.starts_with(|c: char| c.is_whitespace() || c == ',') {
+        return None;
+    }
+    Some(rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',').to_string())
+}
+
+/// Replace a trailing trigger with its snippet's content, keeping any words spoken before it.
+/// Only a final pass starts the trigger's cooldown; interims preview the expansion
+/// without using it up, so the final that follows still expands.
+fn apply_snippet_expansion(text: &str, is_final: bool) -> SnippetExpansion {
+    let found = find_snippet(text).filter(|(snippet, _)| {
+        let cooldown = std::time::Duration::from_millis(SNIPPET_COOLDOWN_MS.load(Ordering::SeqCst) as u64);
+        snippet_off_cooldown(&snippet.trigger, std::time::Instant::now(), cooldown, is_final)
+    });
+    match found {
+        Some((snippet, 0)) => snippet_output(pick_utterance_variant(&snippet, is_final), is_final),
+        Some((snippet, start)) => {
+            let lead: Vec<&str> = text.split_whitespace().take(start).collect();
+            let lead = format!("{} ", lead.join(" "));
+            let expanded = snippet_output(pick_utterance_variant(&snippet, is_final), is_final);
+            SnippetExpansion {
+                cursor_offset: expanded.cursor_offset.map(|offset| offset + lead.len()),
+                text: lead + &expanded.text,
+            }
+        }
+        None => SnippetExpansion::plain(text.to_string()),
+    }
+}
+
+/// After a snippet fires, the same trigger won't expand again for `ms`, so content that
+/// contains its own trigger can't re-fire on the next re-transcription. 0 disables.
+pub fn set_snippet_cooldown_ms(ms: u64) -> Result<(), Fair9Error> {
+    SNIPPET_COOLDOWN_MS.store(ms as usize, Ordering::SeqCst);
+    if ms == 0 {
+        SNIPPET_LAST_FIRED.lock().unwrap().clear();
+    }
+    Ok(())
+}
+
+/// True unless `trigger` fired less than `cooldown` before `now`; with `record`, a
+/// true result also counts as a firing
+fn snippet_off_cooldown(trigger: &str, now: std::time::Instant, cooldown: std::time::Duration, record: bool) -> bool {
+    if cooldown.is_zero() {
+        return true;
+    }
+    let mut last_fired = SNIPPET_LAST_FIRED.lock().unwrap();
+    let key = trigger.trim().to_lowercase();
+    if let Some(at) = last_fired.get(&key) {
+        if now.saturating_duration_since(*at) < cooldown {
+            return false;
+        }
+    }
+    if record {
+        last_fired.insert(key, now);
+    }
+    true
+}
+
+/// For single-line targets (search boxes, chat inputs) where Enter submits:
+/// snippet line breaks become single spaces when typed
+pub fn set_flatten_newlines(enabled: bool) -> Result<(), Fair9Error> {
+    FLATTEN_NEWLINES.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Snippet content as it should be typed, honouring newline flattening. Placeholders
+/// are only filled in on finals, so interims don't read the clipboard on every pass.
+fn snippet_output(content: String, is_final: bool) -> SnippetExpansion {
+    if !is_final {
+        return render_snippet(&content, str::to_string);
+    }
+    let now = chrono::Local::now();
+    let mut clipboard: Option<Option<String>> = None;
+    render_snippet(&content, |part| {
+        expand_placeholders(part, now, || clipboard.get_or_insert_with(read_clipboard_text).clone())
+    })
+}
+
+/// Strip `{cursor}` markers, the first of which sets where the caret goes. They come
+/// out before `fill` expands the placeholders, so a marker in pasted text stays text.
+fn render_snippet(content: &str, mut fill: impl FnMut(&str) -> String) -> SnippetExpansion {
+    let flatten = |text: String| if FLATTEN_NEWLINES.load(Ordering::SeqCst) { flatten_newlines(&text) } else { text };
+    let Some(at) = content.find(CURSOR_MARKER) else {
+        return SnippetExpansion::plain(flatten(fill(content)));
+    };
+    let before = fill(&content[..at]);
+    let after = fill(&content[at + CURSOR_MARKER.len()..].replace(CURSOR_MARKER, ""));
+    // Flattening only touches whitespace, so the caret is still the marker after
+    // any that the filled-in text brought with it
+    let skip = before.matches(CURSOR_MARKER).count();
+    let mut text = flatten(format!("{}{}{}", before, CURSOR_MARKER, after));
+    let offset = text.match_indices(CURSOR_MARKER).nth(skip).map(|(i, _)| i);
+    if let Some(offset) = offset {
+        text.replace_range(offset..offset + CURSOR_MARKER.len(), "");
+    }
+    SnippetExpansion { text, cursor_offset: offset }
+}
+
+/// strftime-style formats for the `{date}` and `{time}` placeholders; `{datetime}`
+/// is the two joined by a space. "%x" and "%X" give the conventional date and time.
+pub fn set_snippet_date_format(date_format: String, time_format: String) -> Result<(), Fair9Error> {
+    for format in [&date_format, &time_format] {
+        let invalid = chrono::format::StrftimeItems::new(format).any(|item| item == chrono::format::Item::Error);
+        if invalid || format.trim().is_empty() {
+            return Err(Fair9Error::InvalidArgument(format!("Invalid date/time format '{}'", format)));
+        }
+    }
+    *SNIPPET_DATE_FORMATS.lock().unwrap() = (date_format, time_format);
+    Ok(())
+}
+
+/// Fill in `{date}`, `{time}`, `{datetime}` and `{clipboard}` at expansion time.
+/// Anything else in braces is left as written. The clipboard is only read when used.
+fn expand_placeholders(content: &str, now: chrono::DateTime<chrono::Local>, clipboard: impl FnOnce() -> Option<String>) -> String {
+    if !content.contains('{') {
+        return content.to_string();
+    }
+    let (date_format, time_format) = SNIPPET_DATE_FORMATS.lock().unwrap().clone();
+    let mut clipboard = Some(clipboard);
+    let mut clipboard_text = None;
+    PLACEHOLDER.replace_all(content, |caps: &regex::Captures| {
+        match &caps[1] {
+            "date" => now.format(&date_format).to_string(),
+            "time" => now.format(&time_format).to_string(),
+            "datetime" => format!("{} {}", now.format(&date_format), now.format(&time_format)),
+            "clipboard" => clipboard_text
+                .get_or_insert_with(|| clipboard.take().and_then(|read| read()).unwrap_or_default())
+                .clone(),
+            _ => caps[0].to_string(),
+        }
+    }).into_owned()
+}
+
+fn read_clipboard_text() -> Option<String> {
+    match arboard::Clipboard::new().and_then(|mut c| c.get_text()) {
+        Ok(text) => Some(text),
+        Err(e) => {
+            eprintln!("{{clipboard}} placeholder left empty: {}", e);
+            None
+        }
+    }
+}
+
+fn flatten_newlines(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Snippet stage of the pipeline: honours the escape prefix and the global toggle
+fn process_snippets(text: String) -> String {
+    expand_snippets(text, true).text
+}
+
+fn expand_snippets(text: String, is_final: bool) -> SnippetExpansion {
+    let prefix = SNIPPET_ESCAPE_PREFIX.lock().unwrap().clone();
+    let expansion = if let Some(rest) = strip_escape_prefix(&text, &prefix) {
+        SnippetExpansion::plain(rest)
+    } else if !SNIPPET_EXPANSION.load(Ordering::SeqCst) {
+        SnippetExpansion::plain(text)
+    } else {
+        apply_snippet_expansion(&text, is_final)
+    };
+    if is_final {
+        // The utterance is over, and its variant picks with it
+        SNIPPET_UTTERANCE_VARIANTS.lock().unwrap().clear();
+    }
+    expansion
+}
+
+/// Why an utterance did or didn't expand, for support and the snippet editor
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchReason {
+    Matched,
+    NoMatch,
+    Disabled,            // Snippet expansion is switched off
+    Escaped,             // Utterance starts with the escape prefix
+    MissingPrefix,       // A snippet prefix is required but wasn't spoken
+    BelowFuzzyThreshold, // A trigger is close, but not an exact or suffix match
+    Shadowed,            // The closest trigger matches, but another one wins the resolution
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchExplanation {
+    pub normalized: String,
+    pub closest_trigger: Option<String>,
+    pub distance: Option<usize>,
+    pub winning_trigger: Option<String>,
+    pub reason: MatchReason,
+}
+
+/// Diagnose how `utterance` is handled against the current store and settings
+pub fn explain_snippet_match(utterance: String) -> MatchExplanation {
+    let words: Vec<String> = snippet_words(&utterance).into_iter().filter(|w| !w.is_empty()).collect();
+    let normalized = words.join(" ");
+
+    let escape = SNIPPET_ESCAPE_PREFIX.lock().unwrap().clone();
+    let required_prefix = SNIPPET_PREFIX.lock().unwrap().clone();
+    let candidate_words = match &required_prefix {
+        Some(prefix) => strip_leading_words(&words, prefix).unwrap_or_else(|| words.clone()),
+        None => words.clone(),
+    };
+    let (closest_trigger, distance) = closest_trigger(&candidate_words).unzip();
+    let winning_trigger = find_snippet(&utterance).map(|(s, _)| s.trigger);
+
+    let reason = if strip_escape_prefix(&utterance, &escape).is_some() {
+        MatchReason::Escaped
+    } else if !SNIPPET_EXPANSION.load(Ordering::SeqCst) {
+        MatchReason::Disabled
+    } else if let Some(winner) = &winning_trigger {
+        let shadowed = distance == Some(0) && closest_trigger.as_ref().map_or(false, |c| !c.eq_ignore_ascii_case(winner));
+        if shadowed { MatchReason::Shadowed } else { MatchReason::Matched }
+    } else if required_prefix.as_ref().map_or(false, |p| strip_leading_words(&words, p).is_none()) {
+        MatchReason::MissingPrefix
+    } else if distance.map_or(false, |d| d <= SNIPPET_NEAR_MISS_DISTANCE) {
+        MatchReason::BelowFuzzyThreshold
+    } else {
+        MatchReason::NoMatch
+    };
+
+    MatchExplanation { normalized, closest_trigger, distance, winning_trigger, reason }
+}
+
+/// Trigger with the smallest edit distance to the whole utterance or its trailing
+/// words (whichever is closer); ties go to the longer, more specific trigger
+fn closest_trigger(words: &[String]) -> Option<(String, usize)> {
+    let store = SNIPPETS.lock().unwrap();
+    store.iter()
+        .filter(|s| !s.is_regex)
+        .map(|s| {
+            let trigger: Vec<String> = snippet_words(&s.trigger).into_iter().filter(|w| !w.is_empty()).collect();
+            let whole = levenshtein(&words.join(" "), &trigger.join(" "));
+            let tail = &words[words.len().saturating_sub(trigger.len())..];
+            let suffix = levenshtein(&tail.join(" "), &trigger.join(" "));
+            (s.trigger.clone(), whole.min(suffix))
+        })
+        .min_by_key(|(trigger, d)| (*d, std::cmp::Reverse(trigger.chars().count())))
+}
+
+/// Character-level edit distance
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb { diag } else { 1 + diag.min(above).min(row[j]) };
+            diag = above;
+        }
+    }
+    row[b.len()]
+}
+
+// ── Tests ────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+fn match_snippet(utterance: &str) -> Option<String> {
+    find_snippet(utterance).map(|(s, _)| s.content)
+}
+
+fn extract_json_string(json: &str, key: &str) -> Option<String> {
+   // Simple manual parser for tests to avoid heavy deps in test/mock 
+   // But we have serde now, so let's use it if we want, or keep logic simple
+   if let Ok(val) =  serde_json::from_str::<serde_json::Value>(json) {
+       return val.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+   }
+   None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Serializes tests that touch process-wide state other tests observe
+    // (shutdown cancels in-flight injection, data dir override, ...)
+    lazy_static! {
+        static ref GLOBAL_STATE_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    fn global_state_lock() -> std::sync::MutexGuard<'static, ()> {
+        GLOBAL_STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn temp_data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fair9-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_inject_text_normal_mode() {
+        let _lock = global_state_lock();
+        let text = "Hello Fair9 Test".to_string();
+        let delay_ms = 10; // Normal mode
+
+        let start = Instant::now();
+        let result = inject_text(text.clone(), delay_ms);
+        let elapsed = start.elapsed();
+
+        assert!(result.is_ok(), "inject_text should succeed");
+
+        let expected_min = std::time::Duration::from_millis(delay_ms * text.len() as u64);
+        assert!(
+            elapsed >= expected_min * 80 / 100, // Allow 20% timing tolerance
+            "Normal mode: elapsed {:?} should be >= ~{:?}",
+            elapsed, expected_min
+        );
+    }
+
+    #[test]
+    fn test_inject_text_legacy_mode_slower() {
+        let _lock = global_state_lock();
+        let text = "SpeedTest".to_string();
+
+        let start_normal = Instant::now();
+        inject_text(text.clone(), 10).unwrap();
+        let normal_elapsed = start_normal.elapsed();
+
+        let start_legacy = Instant::now();
+        inject_text(text.clone(), 30).unwrap();
+        let legacy_elapsed = start_legacy.elapsed();
+
+        assert!(
+            legacy_elapsed > normal_elapsed,
+            "Legacy mode ({:?}) should be slower than normal mode ({:?})",
+            legacy_elapsed, normal_elapsed
+        );
+    }
+
+    #[test]
+    fn test_inject_text_empty_string() {
+        let _lock = global_state_lock();
+        let start = Instant::now();
+        let result = inject_text("".to_string(), 10);
+        let elapsed = start.elapsed();
+
+        assert!(result.is_ok(), "Empty string should succeed");
+        assert!(
+            elapsed < std::time::Duration::from_millis(5),
+            "Empty string should complete near-instantly, took {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn test_estimate_injection_ms_matches_actual() {
+        let _lock = global_state_lock();
+        // "é" as e + combining accent and "\r\n" are one step each
+        assert_eq!(estimate_injection_ms("ne\u{301}e\r\nok".to_string(), 10), 60);
+        assert_eq!(estimate_injection_ms(String::new(), 10), 0);
+
+        let text = "Quick test".to_string();
+        let estimate = estimate_injection_ms(text.clone(), 10);
+        let start = Instant::now();
+        inject_text(text, 10).unwrap();
+        let elapsed = start.elapsed().as_millis() as u64;
+        assert!(elapsed >= estimate * 80 / 100, "elapsed {}ms vs estimate {}ms", elapsed, estimate);
+        assert!(elapsed <= estimate * 2 + 50, "elapsed {}ms vs estimate {}ms", elapsed, estimate);
+    }
+
+    #[test]
+    fn test_inject_text_unicode() {
+        let _lock = global_state_lock();
+        let result = inject_text("Fair9 ✓ héllo 日本".to_string(), 1);
+        assert!(result.is_ok(), "Unicode injection should succeed");
+    }
+
+    #[test]
+    fn test_min_interim_words_gate() {
+        // Interim below the threshold is withheld
+        assert!(!should_emit("insert", false, 3));
+        assert!(!should_emit("insert bio", false, 3));
+        assert!(should_emit("insert bio please", false, 3));
+        // Finals are unconditional
+        assert!(should_emit("insert", true, 3));
+        // Gate disabled
+        assert!(should_emit("insert", false, 0));
+        // Empty text is never emitted
+        assert!(!should_emit("", true, 0));
+    }
+
+    #[test]
+    fn test_stream_buffer_bounded_across_commits() {
+        let mut window = StreamWindow::default();
+        let mut pos = BufferPos::default();
+        let mut buffer = Vec::new();
+        let tick = vec![0.0f32; SAMPLE_RATE / 2]; // 500ms of audio per loop tick
+        let mut finals = 0;
+
+        for _ in 0..40 {
+            pos.advance(push_capped(&mut buffer, &tick, DEFAULT_MAX_BUFFER_SAMPLES));
+            if let Some((samples, is_final)) = window.next_pass(&mut buffer, &mut pos) {
+                if is_final {
+                    finals += 1;
+                    assert_eq!(samples.len(), FINAL_WINDOW_SAMPLES);
+                }
+            }
+            assert!(buffer.len() < FINAL_WINDOW_SAMPLES);
+        }
+        assert!(finals >= 6);
+        assert!(window.processed_to <= pos.start + buffer.len());
+    }
+
+    #[test]
+    fn test_stream_cursor_resets_on_clear() {
+        let mut window = StreamWindow::default();
+        let mut pos = BufferPos::default();
+        let mut buffer = vec![0.0f32; MIN_INTERIM_SAMPLES * 3];
+        assert!(matches!(window.next_pass(&mut buffer, &mut pos), Some((_, false))));
+        assert!(window.next_pass(&mut buffer, &mut pos).is_none());
+
+        // Cleared mid-session: a shorter new buffer must still be transcribed
+        buffer.clear();
+        pos.reset();
+        buffer.extend_from_slice(&vec![0.0; MIN_INTERIM_SAMPLES]);
+        let (samples, is_final) = window.next_pass(&mut buffer, &mut pos).unwrap();
+        assert_eq!((samples.len(), is_final), (MIN_INTERIM_SAMPLES, false));
+        assert!(window.next_pass(&mut buffer, &mut pos).is_none());
+
+        // Trimming at the cap keeps the length constant but the audio is new
+        pos.advance(push_capped(&mut buffer, &[0.5; 100], MIN_INTERIM_SAMPLES));
+        assert_eq!(buffer.len(), MIN_INTERIM_SAMPLES);
+        assert!(window.next_pass(&mut buffer, &mut pos).is_some());
+    }
+
+    #[test]
+    fn test_clear_audio_buffer_starts_new_generation() {
+        let _lock = global_state_lock();
+        STATE.audio_buffer.lock().unwrap().extend_from_slice(&[0.1; 160]);
+        let before = *BUFFER_POS.lock().unwrap();
+        clear_audio_buffer().unwrap();
+        assert!(STATE.audio_buffer.lock().unwrap().is_empty());
+        assert_eq!(BUFFER_POS.lock().unwrap().generation, before.generation + 1);
+        assert_eq!(BUFFER_POS.lock().unwrap().start, 0);
+    }
+
+    #[test]
+    fn test_capture_backlogs_then_drops_while_buffer_locked() {
+        let _lock = global_state_lock();
+        clear_audio_buffer().unwrap();
+        let before = get_audio_health();
+
+        let chunk = vec![0.1f32; CAPTURE_BACKLOG_SAMPLES / 2];
+        {
+            let _busy = STATE.audio_buffer.lock().unwrap(); // a transcription pass
+            capture_samples(&chunk);
+            capture_samples(&chunk);
+            assert_eq!(get_audio_health(), before); // held in the backlog
+            capture_samples(&chunk[..100]);
+        }
+        let after = get_audio_health();
+        assert_eq!(after.dropped_frames - before.dropped_frames, 100);
+        assert_eq!(after.xruns - before.xruns, 1);
+
+        capture_samples(&[0.2; 10]);
+        let buffer = get_audio_buffer_copy();
+        assert_eq!(buffer.len(), CAPTURE_BACKLOG_SAMPLES + 10);
+        assert_eq!(buffer.last(), Some(&0.2));
+        clear_audio_buffer().unwrap();
+
+        let mut backlog = Vec::new();
+        assert_eq!(stash_backlog(&mut backlog, &[0.0; 8], 5), 3);
+        assert_eq!(stash_backlog(&mut backlog, &[0.0; 8], 5), 8);
+    }
+
+    #[test]
+    fn test_live_processing_off_defers_to_final_on_stop() {
+        let _lock = global_state_lock();
+        clear_audio_buffer().unwrap();
+        set_live_processing(false).unwrap();
+        start_listening().unwrap();
+        let mut window = StreamWindow::default();
+
+        let audio = vec![0.1f32; FINAL_WINDOW_SAMPLES + MIN_INTERIM_SAMPLES];
+        for chunk in audio.chunks(1600) {
+            capture_samples(chunk);
+            assert!(next_stream_pass(&mut window).is_none(), "no interim passes while off");
+        }
+
+        stop_listening().unwrap();
+        let (samples, is_final) = next_stream_pass(&mut window).unwrap();
+        assert!(is_final);
+        assert_eq!(samples.len(), audio.len());
+        assert!(next_stream_pass(&mut window).is_none());
+
+        // With live processing on, stopping discards what hasn't been committed
+        set_live_processing(true).unwrap();
+        start_listening().unwrap();
+        capture_samples(&[0.1; 1600]);
+        stop_listening().unwrap();
+        assert!(next_stream_pass(&mut window).is_none());
+    }
+
+    #[test]
+    fn test_pause_keeps_session_but_gates_capture() {
+        let _lock = global_state_lock();
+        clear_audio_buffer().unwrap();
+        assert_eq!(pause_listening(), Err(Fair9Error::NotListening));
+
+        start_listening().unwrap();
+        set_committed_prefix("Before the break".to_string()).unwrap();
+        let mut window = StreamWindow::default();
+        capture_live(&[0.1; 1600]);
+
+        pause_listening().unwrap();
+        pause_listening().unwrap(); // idempotent
+        assert!(get_status().is_listening && get_status().is_paused);
+        capture_live(&[0.1; FINAL_WINDOW_SAMPLES]);
+        assert_eq!(STATE.audio_buffer.lock().unwrap().len(), 1600, "paused audio is not buffered");
+        assert!(next_stream_pass(&mut window).is_none(), "no passes while paused");
+        assert_eq!(STATE.audio_buffer.lock().unwrap().len(), 1600, "buffer survives the pause");
+        assert_eq!(get_committed_prefix(), "Before the break");
+
+        resume_listening().unwrap();
+        assert!(!get_status().is_paused);
+        capture_live(&[0.1; MIN_INTERIM_SAMPLES]);
+        let (samples, is_final) = next_stream_pass(&mut window).unwrap();
+        assert_eq!((samples.len(), is_final), (1600 + MIN_INTERIM_SAMPLES, false));
+
+        // Stopping ends the pause too, so the next session starts capturing
+        pause_listening().unwrap();
+        stop_listening().unwrap();
+        assert!(!get_status().is_paused);
+        clear_audio_buffer().unwrap();
+        set_committed_prefix(String::new()).unwrap();
+    }
+
+    #[test]
+    fn test_live_processing_off_keeps_audio_past_stream_cap() {
+        let _lock = global_state_lock();
+        clear_audio_buffer().unwrap();
+        set_live_processing(false).unwrap();
+        start_listening().unwrap();
+        let mut window = StreamWindow::default();
+
+        let total = DEFAULT_MAX_BUFFER_SAMPLES + SAMPLE_RATE * 5;
+        capture_samples(&[0.5; 1600]);
+        for _ in 1..total / 1600 {
+            capture_samples(&[0.1; 1600]);
+        }
+
+        stop_listening().unwrap();
+        let (samples, is_final) = next_stream_pass(&mut window).unwrap();
+        assert!(is_final);
+        assert_eq!(samples.len(), total);
+        assert_eq!(samples[0], 0.5, "the start of the session survives");
+        set_live_processing(true).unwrap();
+    }
+
+    #[test]
+    fn test_capture_converted_to_16k_mono() {
+        let _lock = global_state_lock();
+        // 10ms of 48kHz stereo: a 1kHz tone on the left, silence on the right
+        let stereo: Vec<f32> = (0..480)
+            .flat_map(|i| [(i as f32 * 2.0 * std::f32::consts::PI / 48.0).sin(), 0.0])
+            .collect();
+        let mono = capture_to_model_input(&mut StreamResampler::new(48_000, SAMPLE_RATE as u32), &stereo, 2);
+        assert_eq!(mono.len(), 160);
+        let peak = mono.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!((peak - 0.5).abs() < 0.05, "downmixed peak {}", peak);
+
+        let native = vec![0.25f32; 160];
+        let mut passthrough = StreamResampler::new(SAMPLE_RATE as u32, SAMPLE_RATE as u32);
+        assert_eq!(capture_to_model_input(&mut passthrough, &native, 1), native);
+        let mut from_44k = StreamResampler::new(44_100, SAMPLE_RATE as u32);
+        assert_eq!(capture_to_model_input(&mut from_44k, &vec![0.1; 441], 1).len(), 160);
+    }
+
+    #[test]
+    fn test_integer_sample_conversion() {
+        assert_eq!(i16_to_f32(0), 0.0);
+        assert_eq!(i16_to_f32(i16::MIN), -1.0);
+        assert!((i16_to_f32(i16::MAX) - 1.0).abs() < 1e-4);
+        assert_eq!(u16_to_f32(32768), 0.0);
+        assert_eq!(u16_to_f32(0), -1.0);
+        assert!((u16_to_f32(u16::MAX) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_downmix_interleaved_stereo() {
+        let stereo = [0.5, -0.5, 0.2, 0.4, 1.0, 0.0, -0.3, -0.1];
+        let mono = downmix_to_mono(&stereo, 2);
+        assert_eq!(mono.len(), stereo.len() / 2);
+        for (got, want) in mono.iter().zip([0.0, 0.3, 0.5, -0.2]) {
+            assert!((got - want).abs() < 1e-6, "{} vs {}", got, want);
+        }
+        assert_eq!(downmix_to_mono(&[0.1, 0.2, 0.3, 0.4], 4), vec![0.25]);
+        assert_eq!(downmix_to_mono(&[0.1, 0.2], 1), vec![0.1, 0.2]);
+
+        // A 16kHz stereo interface fills the buffer at the real rate, not double it
+        let _lock = global_state_lock();
+        clear_audio_buffer().unwrap();
+        let mut resampler = StreamResampler::new(SAMPLE_RATE as u32, SAMPLE_RATE as u32);
+        capture_samples(&capture_to_model_input(&mut resampler, &[0.1; 320], 2));
+        assert_eq!(get_audio_buffer_copy().len(), 160);
+        clear_audio_buffer().unwrap();
+    }
+
+    #[test]
+    fn test_44k_capture_reaches_model_at_16k() {
+        let _lock = global_state_lock();
+        clear_audio_buffer().unwrap();
+        // One second from a 44.1kHz mic, in 10ms callbacks
+        let device_audio: Vec<f32> = (0..44_100).map(|i| 0.2 * (i as f32 * 0.05).sin()).collect();
+        let mut resampler = StreamResampler::new(44_100, SAMPLE_RATE as u32);
+        for chunk in device_audio.chunks(441) {
+            capture_samples(&capture_to_model_input(&mut resampler, chunk, 1));
+        }
+        assert_eq!(get_audio_buffer_copy().len(), SAMPLE_RATE);
+
+        let mut seen = 0;
+        peek_with(|samples| {
+            seen = samples.len();
+            Ok(String::new())
+        }).unwrap();
+        assert_eq!(seen, SAMPLE_RATE);
+        clear_audio_buffer().unwrap();
+    }
+
+    #[test]
+    fn test_capture_rate_holds_for_uneven_callbacks() {
+        // Callback sizes that don't divide the rate ratio, from 44.1 and 48kHz stereo
+        // mics: every second of capture still adds one second at 16kHz
+        for (rate, frames) in [(44_100, 512), (44_100, 1_024), (48_000, 512), (48_000, 1_024)] {
+            let mut resampler = StreamResampler::new(rate, SAMPLE_RATE as u32);
+            let stereo = vec![0.1f32; rate as usize * 2];
+            let mut produced = 0;
+            for second in 1..=5 {
+                for chunk in stereo.chunks(frames * 2) {
+                    produced += capture_to_model_input(&mut resampler, chunk, 2).len();
+                }
+                let expected = second * SAMPLE_RATE;
+                assert!(produced.abs_diff(expected) <= 1, "{}Hz in {}-frame callbacks: {} samples after {}s", rate, frames, produced, second);
+            }
+        }
+    }
+
+    #[test]
+    fn test_push_capped_drops_oldest() {
+        let mut buffer = vec![1.0f32; 8];
+        push_capped(&mut buffer, &[2.0; 4], 10);
+        assert_eq!(buffer.len(), 10);
+        assert_eq!(&buffer[6..], &[2.0; 4]);
+        assert_eq!(buffer[0], 1.0);
+    }
+
+    #[test]
+    fn test_min_session_decision() {
+        assert!(is_session_too_short(120, DEFAULT_MIN_SESSION_MS));
+        assert!(is_session_too_short(249, 250));
+        assert!(!is_session_too_short(250, 250));
+        assert!(!is_session_too_short(3000, 250));
+        // Guard disabled
+        assert!(!is_session_too_short(0, 0));
+    }
+
+    #[test]
+    fn test_batch_recording_keeps_audio_past_stream_cap() {
+        let _lock = global_state_lock();
+        clear_audio_buffer().unwrap();
+        set_min_session_ms(0).unwrap();
+        BATCH_RECORDING.store(true, Ordering::SeqCst);
+        *SESSION_STARTED.lock().unwrap() = Some(Instant::now());
+
+        // 40s in 100ms chunks, the first one marked so a trim would show
+        let total = DEFAULT_MAX_BUFFER_SAMPLES + SAMPLE_RATE * 10;
+        capture_samples(&[0.5; 1600]);
+        for _ in 1..total / 1600 {
+            capture_samples(&[0.1; 1600]);
+        }
+
+        let samples = finish_batch_recording().unwrap().unwrap();
+        assert_eq!(samples.len(), total);
+        assert_eq!(samples[0], 0.5);
+        assert!(!RECORDING_LIMIT_REPORTED.load(Ordering::SeqCst));
+        set_min_session_ms(DEFAULT_MIN_SESSION_MS).unwrap();
+    }
+
+    #[test]
+    fn test_stop_without_recording_errors() {
+        let result = stop_and_transcribe();
+        assert_eq!(result.unwrap_err(), Fair9Error::NotRecording);
+    }
+
+    #[test]
+    fn test_error_variants_survive_anyhow() {
+        let _lock = global_state_lock();
+        // Raised in an internal anyhow function, then context added on the way up
+        let inner: Result<()> = Err(Fair9Error::ModelNotLoaded.into());
+        let wrapped = inner.context("Failed to transcribe").unwrap_err();
+        assert_eq!(Fair9Error::from(wrapped), Fair9Error::ModelNotLoaded);
+
+        let offline = anyhow!("connection refused").context(Fair9Error::OllamaOffline("http://localhost:11434".to_string()));
+        assert_eq!(Fair9Error::from(offline), Fair9Error::OllamaOffline("http://localhost:11434".to_string()));
+
+        let plain = Fair9Error::from(anyhow!("disk full").context("Failed to save"));
+        assert_eq!(plain, Fair9Error::Other("Failed to save: disk full".to_string()));
+        assert_eq!(plain.to_string(), "Failed to save: disk full");
+
+        assert!(matches!(remove_snippet("no such trigger".to_string()), Err(Fair9Error::SnippetNotFound(_))));
+        assert!(matches!(set_ollama_timeout_ms(0), Err(Fair9Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_captions_json() {
+        let _lock = global_state_lock();
+        let empty = captions_json(&[], |_| panic!("empty audio must not reach the model")).unwrap();
+        assert_eq!(empty, "[]");
+
+        let json = captions_json(&[0.0; 16], |_| Ok(vec![
+            Segment { text: " Hello there.".to_string(), start_ms: 0, end_ms: 1200 },
+            Segment { text: " [BLANK_AUDIO]".to_string(), start_ms: 1200, end_ms: 2000 },
+            Segment { text: " See you soon.".to_string(), start_ms: 2000, end_ms: 3400 },
+        ])).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"t0":0,"t1":1200,"text":"Hello there."},{"t0":2000,"t1":3400,"text":"See you soon."}]"#
+        );
+    }
+
+    #[test]
+    fn test_vad_threshold() {
+        let _lock = global_state_lock();
+        assert_eq!(get_vad_threshold(), DEFAULT_VAD_THRESHOLD_RMS);
+        let mut quiet = vec![0.0f32; VAD_FRAME_SAMPLES * 4];
+        quiet[VAD_FRAME_SAMPLES * 2..VAD_FRAME_SAMPLES * 3].fill(0.02);
+        assert!(has_speech(&quiet, get_vad_threshold()));
+
+        set_vad_threshold(0.05).unwrap();
+        assert_eq!(get_vad_threshold(), 0.05);
+        assert!(!has_speech(&quiet, get_vad_threshold()));
+        assert!(last_speech_region(&quiet).is_none());
+
+        assert!(set_vad_threshold(f32::NAN).is_err());
+        assert!(set_vad_threshold(f32::INFINITY).is_err());
+        assert!(set_vad_threshold(-0.01).is_err());
+        assert_eq!(get_vad_threshold(), 0.05);
+        set_vad_threshold(DEFAULT_VAD_THRESHOLD_RMS).unwrap();
+    }
+
+    #[test]
+    fn test_calibration_threshold() {
+        let _lock = global_state_lock();
+        let hum: Vec<f32> = (0..SAMPLE_RATE).map(|i| if i % 2 == 0 { 0.004 } else { -0.004 }).collect();
+        assert!((threshold_from_ambient(&hum).unwrap() - 0.01).abs() < 1e-6);
+        assert_eq!(threshold_from_ambient(&[0.0; 160]).unwrap(), MIN_CALIBRATED_VAD_THRESHOLD);
+        assert!(threshold_from_ambient(&[]).is_err());
+
+        assert!(calibrate_vad(50).is_err());
+        start_listening().unwrap();
+        assert!(calibrate_vad(500).unwrap_err().to_string().contains("Stop listening"));
+        stop_listening().unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_transcriptions_are_serialized() {
+        let _lock = global_state_lock();
+        let (held_tx, held_rx) = std::sync::mpsc::channel();
+        let running = thread::spawn(move || {
+            let _inference = lock_model(true).unwrap(); // a long transcription
+            held_tx.send(()).unwrap();
+            thread::sleep(std::time::Duration::from_millis(200));
+        });
+        held_rx.recv().unwrap();
+
+        capture_samples(&[0.1; 160]);
+        let err = peek_transcription().unwrap_err();
+        clear_audio_buffer().unwrap();
+        assert_eq!(err, Fair9Error::TranscriptionBusy);
+        assert!(get_status().model_loaded);
+
+        // A waiting caller runs once the first finishes (and finds no model here)
+        let started = std::time::Instant::now();
+        let err = transcribe_samples(&[0.0; 160]).unwrap_err();
+        assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+        assert!(err.to_string().contains("Model not loaded"));
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn test_clip_ratio_decision() {
+        let mut monitor = ClipMonitor::default();
+        // 1% of a window pinned at full scale
+        let mut clipped = vec![0.2f32; CLIP_WINDOW_SAMPLES];
+        for s in clipped.iter_mut().step_by(100) {
+            *s = -1.0;
+        }
+        assert!(monitor.observe(&clipped[..CLIP_WINDOW_SAMPLES / 2], 0.001).is_none()); // window not complete
+        let ratio = monitor.observe(&clipped[CLIP_WINDOW_SAMPLES / 2..], 0.001).unwrap();
+        assert!((ratio - 0.01).abs() < 0.001);
+
+        // Same audio under a laxer threshold doesn't warn
+        assert!(monitor.observe(&clipped, 0.05).is_none());
+        // Clean audio never warns
+        assert!(monitor.observe(&vec![0.5f32; CLIP_WINDOW_SAMPLES], 0.001).is_none());
+
+        assert!(set_clip_warn_ratio(f32::NAN).is_err());
+        assert!(set_clip_warn_ratio(1.5).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_stops_session_and_flushes() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("shutdown");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+
+        start_listening().unwrap();
+        STATE.audio_buffer.lock().unwrap().extend_from_slice(&[0.1; 160]);
+        add_snippet("shutdown sig".to_string(), "Cheers".to_string()).unwrap();
+        assert!(SNIPPETS_DIRTY.load(Ordering::SeqCst));
+
+        shutdown().unwrap();
+
+        assert!(!STATE.is_listening.load(Ordering::SeqCst));
+        assert!(STATE.audio_buffer.lock().unwrap().is_empty());
+        assert!(STATE.model_ctx.lock().unwrap().is_none());
+        assert!(!SNIPPETS_DIRTY.load(Ordering::SeqCst));
+        let saved = fs::read_to_string(dir.join("snippets.json")).unwrap();
+        assert!(saved.contains("shutdown sig"));
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "shutdown sig");
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn write_test_wav(path: &std::path::Path, samples: &[i16], rate: u32, channels: u16) {
+        let data_len = (samples.len() * 2) as u32;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&rate.to_le_bytes());
+        bytes.extend_from_slice(&(rate * channels as u32 * 2).to_le_bytes());
+        bytes.extend_from_slice(&(channels * 2).to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            bytes.extend_from_slice(&s.to_le_bytes());
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_speaking_rate_ignores_edge_silence() {
+        let seg = |text: &str, start_ms, end_ms| Segment { text: text.to_string(), start_ms, end_ms };
+        let segments = vec![
+            seg(" [BLANK_AUDIO]", 0, 4_000),
+            seg(" The quick brown fox jumps", 4_000, 8_000),
+            seg(" over the lazy dog again", 8_000, 10_000),
+            seg(" ", 10_000, 15_000),
+        ];
+        // 10 words over the 6 seconds of speech
+        assert_eq!(speaking_rate_wpm(&segments), 100.0);
+        assert_eq!(speaking_rate_wpm(&segments[..1]), 0.0);
+        assert_eq!(speaking_rate_wpm(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_check_microphone_permission() {
+        let status = check_microphone_permission();
+        assert!(["granted", "denied", "undetermined", "unsupported"].contains(&status.as_str()), "{}", status);
+
+        assert_eq!(consent_status("Allow"), "granted");
+        assert_eq!(consent_status("Deny"), "denied");
+        assert_eq!(consent_status(""), "undetermined");
+    }
+
+    #[test]
+    fn test_set_input_device_validates_name() {
+        let _lock = global_state_lock();
+        let devices = list_input_devices();
+        let Some(first) = devices.first().cloned() else {
+            return; // No microphones on this machine
+        };
+        set_input_device(first.to_uppercase()).unwrap();
+        assert_eq!(*INPUT_DEVICE.lock().unwrap(), Some(first.to_uppercase()));
+
+        let err = set_input_device("No Such Mic".to_string()).unwrap_err().to_string();
+        assert!(err.contains("not found") && err.contains(&first), "{}", err);
+        set_input_device(String::new()).unwrap();
+        assert!(INPUT_DEVICE.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_list_audio_hosts_includes_default() {
+        let _lock = global_state_lock();
+        let default = cpal::default_host().id().name().to_string();
+        assert!(list_audio_hosts().contains(&default));
+
+        set_audio_host("NoSuchHost".to_string()).unwrap();
+        assert_eq!(selected_host().id().name(), default);
+        set_audio_host(String::new()).unwrap();
+        assert!(AUDIO_HOST.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_interim_and_final_decode_strategies() {
+        let _lock = global_state_lock();
+        assert_eq!(strategy_for(false), DecodeStrategy::Greedy);
+        assert_eq!(strategy_for(true), DecodeStrategy::Beam);
+        assert!(matches!(strategy_for(false).sampling(), SamplingStrategy::Greedy { best_of: 1 }));
+        assert!(matches!(strategy_for(true).sampling(), SamplingStrategy::BeamSearch { beam_size: BEAM_SIZE, .. }));
+
+        set_final_strategy(" Greedy ".to_string()).unwrap();
+        assert_eq!(strategy_for(true), DecodeStrategy::Greedy);
+        assert!(set_final_strategy("fastest".to_string()).is_err());
+
+        set_final_strategy("beam".to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_word_probabilities() {
+        let tokens: Vec<(String, f32)> = vec![
+            ("[_BEG_]".into(), 0.99),
+            (" Hello".into(), 0.95),
+            (" wor".into(), 0.9),
+            ("ld".into(), 0.4),
+            (" Fair".into(), 0.8),
+            ("9".into(), 0.85),
+            ("<|endoftext|>".into(), 0.1),
+        ];
+        assert_eq!(
+            word_probabilities(&tokens),
+            vec![("Hello".to_string(), 0.95), ("world".to_string(), 0.4), ("Fair9".to_string(), 0.8)]
+        );
+    }
+
+    #[test]
+    fn test_inference_duty_cycle() {
+        use std::time::{Duration, Instant};
+        let t0 = Instant::now();
+        let secs = |s: u64| Duration::from_secs(s);
+        let mut duty = DutyCycle::default();
+        assert_eq!(duty.ratio(t0), 0.0);
+
+        // 2s of inference every 10s
+        for i in 0..6 {
+            duty.record(t0 + secs(i * 10), secs(2));
+        }
+        assert!((duty.ratio(t0 + secs(60)) - 0.2).abs() < 1e-4);
+        // Less than a minute since the first run: measured over that time only
+        let mut fresh = DutyCycle::default();
+        fresh.record(t0, secs(2));
+        assert!((fresh.ratio(t0 + secs(4)) - 0.5).abs() < 1e-4);
+
+        // Runs older than the window stop counting
+        duty.record(t0 + secs(100), secs(30));
+        assert!((duty.ratio(t0 + secs(130)) - 0.5).abs() < 1e-4);
+        assert_eq!(duty.runs.len(), 3); // Runs ending before t=40 were pruned
+    }
+
+    #[test]
+    fn test_merge_tokens_into_words() {
+        let tokens: Vec<(String, (i64, i64))> = vec![
+            ("[_BEG_]".into(), (0, 0)),
+            (" Hello".into(), (0, 320)),
+            (" wor".into(), (300, 450)), // overlaps previous word slightly
+            ("ld".into(), (450, 600)),
+            ("!".into(), (600, 620)),
+            (" Fair".into(), (700, 820)),
+            ("9".into(), (820, 900)),
+            ("<|endoftext|>".into(), (900, 900)),
+        ];
+        let words = merge_tokens_into_words(&tokens);
+        let texts: Vec<&str> = words.iter().map(|w| w.text.as_str()).collect();
+        assert_eq!(texts, vec!["Hello", "world!", "Fair9"]);
+
+        for w in &words {
+            assert!(w.start_ms <= w.end_ms);
+        }
+        for pair in words.windows(2) {
+            assert!(pair[0].end_ms <= pair[1].start_ms, "words must not overlap: {:?}", pair);
+        }
+        assert_eq!(words[1].start_ms, 320);
+        assert_eq!(words[1].end_ms, 620);
+    }
+
+    #[test]
+    fn test_read_wav_stereo_downmix() {
+        let dir = temp_data_dir("wav");
+        let path = dir.join("stereo.wav");
+        write_test_wav(&path, &[16384, 0, 16384, 0], 16000, 2);
+
+        let (samples, rate) = read_wav(&path).unwrap();
+        assert_eq!(rate, 16000);
+        assert_eq!(samples.len(), 2);
+        assert!((samples[0] - 0.25).abs() < 0.001);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pcm16_bytes_to_model_input() {
+        // 0.1s of 48kHz stereo, left at half scale and right silent
+        let bytes: Vec<u8> = (0..4_800)
+            .flat_map(|_| [16384i16, 0])
+            .flat_map(i16::to_le_bytes)
+            .collect();
+        let samples = pcm16_to_model_input(&bytes, 48_000, 2).unwrap();
+        assert_eq!(samples.len(), 1_600);
+        assert!(samples.iter().all(|s| (s - 0.25).abs() < 0.001));
+
+        assert!(pcm16_to_model_input(&bytes[..3], 48_000, 2).is_err());
+        assert!(pcm16_to_model_input(&bytes, 0, 2).is_err());
+        assert!(transcribe_pcm16(bytes, 48_000, 0).is_err());
+    }
+
+    #[test]
+    fn test_linear_and_sinc_resamplers() {
+        let tone = |rate: usize, len: usize| -> Vec<f32> {
+            (0..len).map(|i| (2.0 * std::f32::consts::PI * 440.0 * i as f32 / rate as f32).sin() * 0.5).collect()
+        };
+        let input = tone(48_000, 4_800);
+        let expected = tone(16_000, 1_600);
+
+        for resampled in [resample_linear(&input, 48_000, 16_000), resample_sinc(&input, 48_000, 16_000)] {
+            assert_eq!(resampled.len(), 1_600);
+            // Skip the edges, where the sinc kernel is truncated
+            let max_err = resampled[50..1_550].iter().zip(&expected[50..1_550])
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0f32, f32::max);
+            assert!(max_err < 0.01, "max error {}", max_err);
+        }
+
+        let dc = resample_sinc(&[0.3; 441], 44_100, 16_000);
+        assert_eq!(dc.len(), 160);
+        assert!(dc.iter().all(|s| (s - 0.3).abs() < 1e-4));
+        assert_eq!(resample_sinc(&[0.1, 0.2], 16_000, 16_000), vec![0.1, 0.2]);
+    }
+
+    #[test]
+    fn test_stream_resampler_matches_whole_buffer() {
+        // A 440Hz tone fed in uneven callbacks comes out as the same tone, half a
+        // kernel late, with no seams where the callbacks meet
+        let input: Vec<f32> = (0..48_000).map(|i| (2.0 * std::f32::consts::PI * 440.0 * i as f32 / 48_000.0).sin() * 0.5).collect();
+        let mut resampler = StreamResampler::new(48_000, 16_000);
+        let delay = resampler.half_width as f32 / 3.0;
+        let mut out = Vec::new();
+        for chunk in input.chunks(1_013) {
+            out.extend(resampler.process(chunk));
+        }
+        assert_eq!(out.len(), 16_000);
+        let max_err = (100..15_900)
+            .map(|k| {
+                let t = (k as f32 - delay) / 16_000.0;
+                (out[k] - (2.0 * std::f32::consts::PI * 440.0 * t).sin() * 0.5).abs()
+            })
+            .fold(0.0f32, f32::max);
+        assert!(max_err < 0.01, "max error {}", max_err);
+
+        // 10kHz is above the new Nyquist: the low-pass removes it rather than folding
+        // it down to 6kHz
+        let high: Vec<f32> = (0..48_000).map(|i| (2.0 * std::f32::consts::PI * 10_000.0 * i as f32 / 48_000.0).sin() * 0.5).collect();
+        let mut resampler = StreamResampler::new(48_000, 16_000);
+        let out: Vec<f32> = high.chunks(480).flat_map(|chunk| resampler.process(chunk)).collect();
+        let peak = out[200..].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!(peak < 0.01, "aliased peak {}", peak);
+    }
+
+    #[test]
+    fn test_resample_quality_setting() {
+        let _lock = global_state_lock();
+        assert!(set_resample_quality("cubic".to_string()).is_err());
+        let input: Vec<f32> = (0..480).map(|i| ((i % 7) as f32 - 3.0) / 10.0).collect();
+        assert_eq!(resample(&input, 48_000, 16_000), resample_sinc(&input, 48_000, 16_000));
+
+        set_resample_quality("linear".to_string()).unwrap();
+        assert_eq!(resample(&input, 48_000, 16_000), resample_linear(&input, 48_000, 16_000));
+        set_resample_quality("auto".to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_transcribe_directory_emits_per_file() {
+        let dir = temp_data_dir("batch");
+        write_test_wav(&dir.join("a.wav"), &[0; 1600], 16000, 1);
+        write_test_wav(&dir.join("b.wav"), &[0; 1600], 16000, 1);
+        fs::write(dir.join("notes.txt"), "not audio").unwrap();
+
+        let mut results = Vec::new();
+        transcribe_directory_with(&dir, |r| results.push(r)).unwrap();
+
+        // Unsupported files are skipped; each audio file yields a result even when it fails
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].filename, "a.wav");
+        assert_eq!(results[1].filename, "b.wav");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_update_version_comparison() {
+        assert!(is_newer_version("1.3.0", "1.2.9"));
+        assert!(is_newer_version("1.10.0", "1.9.0")); // numeric, not lexical
+        assert!(is_newer_version("v2", "1.2.9"));
+        assert!(!is_newer_version("1.2.9", "1.2.9"));
+        assert!(!is_newer_version("1.2.9+build.5", "1.2.9"));
+        assert!(!is_newer_version("1.2.8", "1.2.9"));
+        // A release outranks its own pre-releases, and pre-releases order by identifier
+        assert!(is_newer_version("1.3.0", "1.3.0-beta.2"));
+        assert!(!is_newer_version("1.3.0-beta.2", "1.3.0"));
+        assert!(is_newer_version("1.3.0-beta.10", "1.3.0-beta.2"));
+        assert!(is_newer_version("1.3.0-rc.1", "1.3.0-beta.2"));
+        assert!(!is_newer_version("nightly", "1.2.9"));
+    }
+
+    #[test]
+    fn test_update_status_json() {
+        let status = update_status_json("1.2.0", Ok("v1.3.0".to_string()));
+        assert_eq!(status, json!({ "current": "1.2.0", "latest": "1.3.0", "update_available": true }));
+        let status = update_status_json("1.2.0", Ok("1.2.0".to_string()));
+        assert_eq!(status["update_available"], false);
+
+        // Rate limits and network failures degrade to "no update" with a note
+        let status = update_status_json("1.2.0", Err(anyhow!("GitHub API rate limit reached; try again later")));
+        assert_eq!(status["update_available"], false);
+        assert!(status["latest"].is_null());
+        assert!(status["error"].as_str().unwrap().contains("rate limit"));
+    }
+
+    #[test]
+    fn test_version_info_matches_const() {
+        let info = version_info();
+        assert_eq!(info.version, APP_VERSION);
+        assert_eq!(info.git_repo, GITHUB_REPO);
+        assert!(!info.whisper_backend.is_empty());
+        assert_eq!(info.gpu, !["cpu", "openblas"].contains(&info.whisper_backend.as_str()));
+    }
+
+    #[test]
+    fn test_calculate_rms_silent() {
+        let silent = vec![0.0f32; 1600];
+        let rms = calculate_rms(&silent);
+        assert_eq!(rms, 0.0, "Silent audio should have 0 RMS");
+    }
+
+    #[test]
+    fn test_calculate_rms_loud() {
+        let loud = vec![1.0f32; 1600];
+        let rms = calculate_rms(&loud);
+        assert!((rms - 1.0).abs() < 0.001, "Constant 1.0 audio should have RMS ~1.0");
+    }
+
+    #[test]
+    fn test_calculate_rms_empty() {
+        let empty: Vec<f32> = vec![];
+        let rms = calculate_rms(&empty);
+        assert_eq!(rms, 0.0, "Empty buffer should return 0 RMS");
+    }
+
+    #[test]
+    fn test_waveform_point_count() {
+        // Ramp from -1.0 to ~1.0 over 1000 samples
+        let samples: Vec<f32> = (0..1000).map(|i| i as f32 / 500.0 - 1.0).collect();
+        let peaks = compute_waveform(&samples, 100);
+        assert_eq!(peaks.len(), 100);
+        assert_eq!(peaks[0].min, -1.0);
+        assert!(peaks.iter().all(|p| p.min <= p.max));
+        assert!(peaks.windows(2).all(|w| w[0].max < w[1].max));
+
+        // Short buffers don't invent data
+        assert_eq!(compute_waveform(&samples[..10], 100).len(), 10);
+        assert!(compute_waveform(&[], 100).is_empty());
+    }
+
+    // ── Filler Word Removal Tests ──────────────────────────────
+
+    #[test]
+    fn test_clean_filler_basic() {
+        let input = "I um want to uh create a function";
+        let result = remove_fillers(input, DEFAULT_FILLER_WORDS);
+        assert_eq!(result, "I want to create a function");
+    }
+
+    #[test]
+    fn test_clean_filler_multiple() {
+        let input = "so um like basically I you know think hmm we should";
+        let result = remove_fillers(input, DEFAULT_FILLER_WORDS);
+        assert_eq!(result, "so I think we should");
+    }
+
+    #[test]
+    fn test_clean_filler_no_false_positives() {
+        // "like" as legitimate word, "plumber" contains "um" substring
+        let input = "I would like to book a plumber";
+        let result = remove_fillers(input, DEFAULT_FILLER_WORDS);
+        // "like" as standalone filler IS removed, but "plumber" is preserved
+        assert_eq!(result, "I would to book a plumber");
+    }
+
+    #[test]
+    fn test_clean_filler_sentence_initial() {
+        assert_eq!(remove_fillers("Um, I think so", DEFAULT_FILLER_WORDS), "I think so");
+        assert_eq!(remove_fillers("uh we should go", DEFAULT_FILLER_WORDS), "we should go");
+        assert_eq!(remove_fillers("You know, it works", DEFAULT_FILLER_WORDS), "it works");
+    }
+
+    #[test]
+    fn test_clean_filler_before_punctuation() {
+        assert_eq!(remove_fillers("We went there uh.", DEFAULT_FILLER_WORDS), "We went there.");
+        assert_eq!(remove_fillers("Is it like?", DEFAULT_FILLER_WORDS), "Is it?");
+        assert_eq!(remove_fillers("Stop um! Wait hmm; then um: go", DEFAULT_FILLER_WORDS), "Stop! Wait; then: go");
+        assert_eq!(remove_fillers("I went, um, to the store", DEFAULT_FILLER_WORDS), "I went to the store");
+        assert_eq!(remove_fillers("The plumber looks alike, umbrella too", DEFAULT_FILLER_WORDS), "The plumber looks alike, umbrella too");
+    }
+
+    #[test]
+    fn test_custom_filler_words() {
+        let _lock = global_state_lock();
+        set_filler_words(vec!["Um".to_string(), " you  know ".to_string(), "you".to_string()]).unwrap();
+        assert_eq!(clean_filler_words("Um, I actually, you know, did it for you."), "I actually did it for.");
+        set_filler_words(Vec::new()).unwrap();
+        assert_eq!(clean_filler_words("um okay"), "um okay");
+        reset_filler_words().unwrap();
+        assert_eq!(clean_filler_words("um I actually did"), "I did");
+    }
+
+    #[test]
+    fn test_collapse_repetition_loops() {
+        let looped = format!("I think{} so.", " the".repeat(10));
+        assert_eq!(collapse_repeats(&looped, 3), "I think the so.");
+        assert_eq!(collapse_repeats("Thank you. Thank you. Thank you. Thank you. Thank you.", 3), "Thank you.");
+        assert_eq!(collapse_repeats("That was very very good.", 3), "That was very very good.");
+        assert_eq!(collapse_repeats("no, no, no", 3), "no, no, no");
+        assert_eq!(collapse_repeats("go go go go", 4), "go go go go");
+        assert_eq!(collapse_repeats("", 3), "");
+
+        // Opt-in: transcripts are left as decoded until enabled
+        let _lock = global_state_lock();
+        let looped = "Thank you. Thank you. Thank you. Thank you. Thank you.";
+        assert_eq!(clean_transcript(looped), looped);
+        set_dedup_repeats(true).unwrap();
+        assert_eq!(clean_transcript(looped), "Thank you.");
+        set_dedup_repeats(false).unwrap();
+    }
+
+    #[test]
+    fn test_strip_trailing_punct() {
+        assert_eq!(strip_trailing_punct("git status."), "git status");
+        assert_eq!(strip_trailing_punct("Is it ready?"), "Is it ready");
+        assert_eq!(strip_trailing_punct("Ship it!"), "Ship it");
+        assert_eq!(strip_trailing_punct("Wait, what?!"), "Wait, what?");
+        assert_eq!(strip_trailing_punct("Hmm..."), "Hmm...");
+        assert_eq!(strip_trailing_punct("e.g. this, or that"), "e.g. this, or that");
+        assert_eq!(strip_trailing_punct("Dr. Smith said hi."), "Dr. Smith said hi");
+        assert_eq!(strip_trailing_punct(""), "");
+    }
+
+    #[test]
+    fn test_clean_filler_empty() {
+        let input = "";
+        let result = remove_fillers(input, DEFAULT_FILLER_WORDS);
+        assert_eq!(result, "");
+    }
+
+    // ── Non-speech Tag Tests ───────────────────────────────────
+
+    #[test]
+    fn test_strip_nonspeech_tags() {
+        assert_eq!(strip_nonspeech_tags("[BLANK_AUDIO]"), "");
+        assert_eq!(strip_nonspeech_tags(" Hello [MUSIC] world"), "Hello world");
+        assert_eq!(strip_nonspeech_tags("(wind blowing) It is cold"), "It is cold");
+        assert_eq!(strip_nonspeech_tags("Thanks (APPLAUSE)"), "Thanks");
+        assert_eq!(strip_nonspeech_tags("(laughs) okay"), "okay");
+    }
+
+    #[test]
+    fn test_strip_nonspeech_keeps_legit_parens() {
+        assert_eq!(
+            strip_nonspeech_tags("See the diagram (figure two) for details"),
+            "See the diagram (figure two) for details"
+        );
+        assert_eq!(strip_nonspeech_tags("Close it (the window)"), "Close it (the window)");
+        // Unbalanced brackets are left alone
+        assert_eq!(strip_nonspeech_tags("a ( b"), "a ( b");
+    }
+
+    // ── Document Append Tests ──────────────────────────────────
+
+    #[test]
+    fn test_append_after_sentence_end() {
+        assert_eq!(
+            append_to_document("This is done.".to_string(), "next we ship".to_string()),
+            "This is done. Next we ship"
+        );
+        assert_eq!(append_to_document("".to_string(), "hello there".to_string()), "Hello there");
+        assert_eq!(
+            append_to_document("Title\n".to_string(), "first line".to_string()),
+            "Title\nFirst line"
+        );
+    }
+
+    #[test]
+    fn test_append_mid_sentence() {
+        assert_eq!(
+            append_to_document("We should".to_string(), "Probably wait".to_string()),
+            "We should probably wait"
+        );
+        assert_eq!(
+            append_to_document("Yesterday".to_string(), "I called the API team".to_string()),
+            "Yesterday I called the API team"
+        );
+        assert_eq!(
+            append_to_document("Ask the".to_string(), "API owners".to_string()),
+            "Ask the API owners"
+        );
+        assert_eq!(append_to_document("Wait".to_string(), ", really?".to_string()), "Wait, really?");
+        assert_eq!(append_to_document("Trailing ".to_string(), "Space".to_string()), "Trailing space");
+    }
+
+    #[test]
+    fn test_autocap_after_spoken_punctuation() {
+        assert_eq!(apply_spoken_punctuation("this works period next sentence", true), "This works. Next sentence");
+        assert_eq!(apply_spoken_punctuation("this works period next sentence", false), "this works. next sentence");
+        assert_eq!(
+            apply_spoken_punctuation("Is it ready? Question mark. yes comma ship it exclamation point", true),
+            "Is it ready? Yes, ship it!"
+        );
+        assert_eq!(apply_spoken_punctuation("one comma two colon three", true), "One, two: three");
+    }
+
+    #[test]
+    fn test_spoken_punctuation_in_transcript() {
+        let _lock = global_state_lock();
+        assert_eq!(clean_transcript(" this works period next sentence"), "this works period next sentence");
+        set_spoken_punctuation(true).unwrap();
+        assert_eq!(clean_transcript(" this works period next sentence"), "This works. Next sentence");
+        set_spoken_punctuation(false).unwrap();
+    }
+
+    #[test]
+    fn test_format_as_styles() {
+        let fmt = |text: &str, style: &str| format_as(text.to_string(), style.to_string()).unwrap();
+        assert_eq!(fmt("  Hello there. ", "plain"), "Hello there.");
+        assert_eq!(fmt("Hello there.", "blockquote"), "> Hello there.");
+        assert_eq!(fmt("Hello there.", "bullet"), "- Hello there.");
+        assert_eq!(fmt("let x = 1;", "codeblock"), "```\nlet x = 1;\n```");
+        assert_eq!(fmt("Hi", "Blockquote"), "> Hi");
+        assert!(format_as("Hi".to_string(), "table".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_format_as_multiline() {
+        let fmt = |text: &str, style: &str| format_as(text.to_string(), style.to_string()).unwrap();
+        assert_eq!(fmt("First point.\n\nSecond point.\n", "blockquote"), "> First point.\n>\n> Second point.");
+        assert_eq!(fmt("Milk\n\n  Eggs  \nBread", "bullet"), "- Milk\n- Eggs\n- Bread");
+        assert_eq!(fmt("fn a() {}\nfn b() {}", "codeblock"), "```\nfn a() {}\nfn b() {}\n```");
+        // Embedded fences get a longer outer fence
+        assert_eq!(fmt("```\ncode\n```", "codeblock"), "````\n```\ncode\n```\n````");
+    }
+
+    #[test]
+    fn test_text_transform_chain_in_order() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("transforms");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+
+        register_text_transform("units".to_string(), r"(\d+) percent".to_string(), "$1%".to_string()).unwrap();
+        register_text_transform("brand".to_string(), r"(?i)fair ?nine".to_string(), "Fair9".to_string()).unwrap();
+        assert_eq!(clean_transcript("fair nine is 100 percent local"), "Fair9 is 100% local");
+
+        // Order matters: the second rule sees the first rule's output
+        register_text_transform("shout".to_string(), "%".to_string(), " PERCENT".to_string()).unwrap();
+        assert_eq!(apply_text_transforms("5 percent".to_string()), "5 PERCENT");
+
+        // Persisted in settings.json
+        let saved = fs::read_to_string(dir.join("settings.json")).unwrap();
+        assert!(saved.contains("\"shout\""));
+
+        assert!(register_text_transform("bad".to_string(), "(".to_string(), "".to_string()).is_err());
+        remove_text_transform("units".to_string()).unwrap();
+        assert_eq!(apply_text_transforms("5 percent".to_string()), "5 percent");
+        assert!(remove_text_transform("units".to_string()).is_err());
+
+        // A write that fails leaves the chain as it was
+        fs::remove_file(dir.join("settings.json")).unwrap();
+        fs::create_dir(dir.join("settings.json")).unwrap();
+        assert!(register_text_transform("late".to_string(), "x".to_string(), "y".to_string()).is_err());
+        assert!(remove_text_transform("brand".to_string()).is_err());
+        let names: Vec<String> = list_text_transforms().into_iter().map(|t| t.name).collect();
+        assert_eq!(names, ["brand", "shout"]);
+
+        set_text_transforms(&[]);
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
 
     #[test]
-    fn test_inject_text_normal_mode() {
-        let text = "Hello Fair9 Test".to_string();
-        let delay_ms = 10; // Normal mode
+    fn test_english_word_to_ipa() {
+        let g2p = EnglishG2p;
+        assert_eq!(g2p.word_to_ipa("the"), "ðə");
+        assert_eq!(g2p.word_to_ipa("cat"), "kæt");
+        assert_eq!(g2p.word_to_ipa("Ship"), "ʃɪp");
+        assert_eq!(g2p.word_to_ipa("make"), "meɪk");
+        assert_eq!(g2p.word_to_ipa("think"), "θɪŋk");
+        assert_eq!(g2p.word_to_ipa("city"), "sɪti");
+        assert_eq!(g2p.word_to_ipa("bell"), "bɛl");
+    }
 
-        let start = Instant::now();
-        let result = inject_text(text.clone(), delay_ms);
-        let elapsed = start.elapsed();
+    #[test]
+    fn test_text_to_ipa_languages() {
+        assert_eq!(text_to_ipa("The cat, the ship.".to_string(), "en".to_string()).unwrap(), "ðə kæt ðə ʃɪp");
+        let err = text_to_ipa("le chat".to_string(), "fr".to_string()).unwrap_err();
+        assert!(err.to_string().contains("not supported"));
+    }
 
-        assert!(result.is_ok(), "inject_text should succeed");
+    #[test]
+    fn test_ipa_follows_output_language() {
+        let _lock = global_state_lock();
+        set_output_ipa(true).unwrap();
+        assert_eq!(finish_pass("the cat", true, None).unwrap().ipa.as_deref(), Some("ðə kæt"));
 
-        let expected_min = std::time::Duration::from_millis(delay_ms * text.len() as u64);
-        assert!(
-            elapsed >= expected_min * 80 / 100, // Allow 20% timing tolerance
-            "Normal mode: elapsed {:?} should be >= ~{:?}",
-            elapsed, expected_min
-        );
+        // No English rules applied to German, nor to a language still to be detected
+        set_language("de".to_string()).unwrap();
+        assert_eq!(finish_pass("der Hund", true, None).unwrap().ipa, None);
+        set_language("auto".to_string()).unwrap();
+        assert_eq!(finish_pass("der Hund", true, None).unwrap().ipa, None);
+
+        // Translation comes out in English whatever was spoken
+        set_translate_mode(true).unwrap();
+        assert_eq!(finish_pass("the cat", true, None).unwrap().ipa.as_deref(), Some("ðə kæt"));
+
+        set_translate_mode(false).unwrap();
+        set_language("en".to_string()).unwrap();
+        set_output_ipa(false).unwrap();
+        set_committed_prefix(String::new()).unwrap();
     }
 
     #[test]
-    fn test_inject_text_legacy_mode_slower() {
-        let text = "SpeedTest".to_string();
+    fn test_last_speech_region_skips_earlier_utterance() {
+        let tone = |secs: f32| -> Vec<f32> {
+            (0..(SAMPLE_RATE as f32 * secs) as usize).map(|i| 0.3 * (i as f32 * 0.2).sin()).collect()
+        };
+        let silence = |secs: f32| vec![0.0f32; (SAMPLE_RATE as f32 * secs) as usize];
 
-        let start_normal = Instant::now();
-        inject_text(text.clone(), 10).unwrap();
-        let normal_elapsed = start_normal.elapsed();
+        // silence, speech, long pause, speech (with a short breath inside), silence
+        let mut buffer = silence(0.5);
+        buffer.extend(tone(1.0));
+        let second_start = buffer.len() + SAMPLE_RATE * 2;
+        buffer.extend(silence(2.0));
+        buffer.extend(tone(0.6));
+        buffer.extend(silence(0.3));
+        buffer.extend(tone(0.6));
+        let second_end = buffer.len();
+        buffer.extend(silence(0.5));
 
-        let start_legacy = Instant::now();
-        inject_text(text.clone(), 30).unwrap();
-        let legacy_elapsed = start_legacy.elapsed();
+        let region = last_speech_region(&buffer).unwrap();
+        let frame = VAD_FRAME_SAMPLES;
+        assert!(region.start.abs_diff(second_start) <= frame, "start {} vs {}", region.start, second_start);
+        assert!(region.end.abs_diff(second_end) <= frame, "end {} vs {}", region.end, second_end);
 
-        assert!(
-            legacy_elapsed > normal_elapsed,
-            "Legacy mode ({:?}) should be slower than normal mode ({:?})",
-            legacy_elapsed, normal_elapsed
+        assert!(last_speech_region(&silence(1.0)).is_none());
+    }
+
+    #[test]
+    fn test_detect_turns_splits_on_long_pause() {
+        let _lock = global_state_lock();
+        let tone = |secs: f32| -> Vec<f32> {
+            (0..(SAMPLE_RATE as f32 * secs) as usize).map(|i| 0.3 * (i as f32 * 0.2).sin()).collect()
+        };
+        let silence = |secs: f32| vec![0.0f32; (SAMPLE_RATE as f32 * secs) as usize];
+
+        // Speaker A (with a breath), a 2s pause, speaker B
+        let mut buffer = silence(0.3);
+        buffer.extend(tone(1.0));
+        buffer.extend(silence(0.4));
+        buffer.extend(tone(0.8));
+        let first_end = buffer.len();
+        buffer.extend(silence(2.0));
+        let second_start = buffer.len();
+        buffer.extend(tone(1.2));
+        let second_end = buffer.len();
+
+        let turns = detect_turns(buffer.clone());
+        assert_eq!(turns.len(), 2, "{:?}", turns);
+        let frame = VAD_FRAME_SAMPLES;
+        assert!(turns[0].0.abs_diff(SAMPLE_RATE * 3 / 10) <= frame);
+        assert!(turns[0].1.abs_diff(first_end) <= frame);
+        assert!(turns[1].0.abs_diff(second_start) <= frame);
+        assert!(turns[1].1.abs_diff(second_end) <= frame);
+
+        set_turn_gap_ms(3000).unwrap();
+        assert_eq!(detect_turns(buffer).len(), 1);
+        set_turn_gap_ms(DEFAULT_TURN_GAP_MS).unwrap();
+        assert!(detect_turns(silence(1.0)).is_empty());
+    }
+
+    #[test]
+    fn test_profanity_filter_masks_whole_words() {
+        let _lock = global_state_lock();
+        assert_eq!(filter_profanity("oh damn".to_string()), "oh damn");
+
+        set_profanity_filter(true).unwrap();
+        assert_eq!(
+            filter_profanity("Damn, that class was a pain in the ass.".to_string()),
+            "***, that class was a pain in the ***."
         );
+        assert_eq!(filter_profanity("Classic assessment".to_string()), "Classic assessment");
+
+        set_profanity_list(vec!["Heck".to_string()]).unwrap();
+        assert_eq!(filter_profanity("heck no, damn".to_string()), "*** no, damn");
+
+        set_profanity_list(DEFAULT_PROFANITY_LIST.iter().map(|w| w.to_string()).collect()).unwrap();
+        set_profanity_filter(false).unwrap();
     }
 
     #[test]
-    fn test_inject_text_empty_string() {
+    fn test_text_callback_collects_emissions() {
+        let _lock = global_state_lock();
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let sink = collected.clone();
+        let emit = text_only(move |text| sink.lock().unwrap().push(text));
+
+        for (raw, is_final) in [(" Hello", false), ("", false), (" Hello world.", true)] {
+            if let Some(result) = finish_pass(raw, is_final, None) {
+                emit(result);
+            }
+        }
+        assert_eq!(*collected.lock().unwrap(), vec!["Hello".to_string(), "Hello world.".to_string()]);
+    }
+
+    #[test]
+    fn test_init_model_from_path_validates_file() {
+        let dir = temp_data_dir("model_path");
+        fs::create_dir_all(&dir).unwrap();
+
+        let fixture = dir.join("ggml-fixture.bin");
+        let mut bytes = GGML_MAGIC.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0u8; 64]);
+        fs::write(&fixture, &bytes).unwrap();
+        assert!(validate_model_file(&fixture).is_ok());
+
+        let bogus = dir.join("not-a-model.bin");
+        fs::write(&bogus, b"<html>404</html>").unwrap();
+        let err = init_model_from_path(bogus.to_string_lossy().to_string()).unwrap_err();
+        assert!(err.to_string().contains("not a ggml"), "{}", err);
+
+        let missing = init_model_from_path(dir.join("missing.bin").to_string_lossy().to_string());
+        assert!(missing.unwrap_err().to_string().contains("not found"));
+        assert!(init_model_from_path("models/ggml.bin".to_string()).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Needs a real model file, which is too big to check in:
+    // FAIR9_TEST_MODEL=/abs/path/ggml-tiny.en.bin cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn test_real_tiny_model_loads_and_decodes() {
+        use std::io::Read;
+        let _lock = global_state_lock();
+        let path = std::env::var("FAIR9_TEST_MODEL").expect("FAIR9_TEST_MODEL should name a ggml model file");
+        let mut header = [0u8; 4];
+        fs::File::open(&path).unwrap().read_exact(&mut header).unwrap();
+        assert_eq!(&header, b"lmgg");
+        assert!(validate_model_file(std::path::Path::new(&path)).is_ok());
+
+        init_model_from_path(path).unwrap();
+        assert!(transcribe_samples(&vec![0.0; SAMPLE_RATE]).is_ok());
+        *STATE.model_ctx.lock().unwrap() = None;
+        *LOADED_MODEL_PATH.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_model_load_retries_transient_failures_only() {
+        let no_delay = std::time::Duration::ZERO;
+        let mut calls = 0;
+        let loaded = load_with_retry(2, no_delay, || {
+            calls += 1;
+            if calls == 1 {
+                let busy = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "file in use");
+                return Err(anyhow::Error::new(busy).context("Failed to read model header"));
+            }
+            Ok("ctx")
+        });
+        assert_eq!(loaded.unwrap(), "ctx");
+        assert_eq!(calls, 2);
+
+        let mut calls = 0;
+        let bad = load_with_retry(2, no_delay, || -> Result<()> {
+            calls += 1;
+            Err(anyhow!("model.bin is not a ggml Whisper model"))
+        });
+        assert!(bad.is_err());
+        assert_eq!(calls, 1);
+
+        // A download still being written: the header read runs off the end
+        let mut calls = 0;
+        let flaky = load_with_retry(1, no_delay, || -> Result<()> {
+            calls += 1;
+            Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file still growing").into())
+        });
+        assert!(flaky.is_err());
+        assert_eq!(calls, 2);
+
+        // whisper.cpp rejecting the model won't change on a second try
+        let mut calls = 0;
+        let corrupt = load_with_retry(2, no_delay, || -> Result<()> {
+            calls += 1;
+            Err(anyhow::Error::new(whisper_rs::WhisperError::InitError).context("Model file is corrupt or not a ggml model"))
+        });
+        assert!(corrupt.unwrap_err().to_string().contains("corrupt"));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let missing = load_with_retry(2, no_delay, || -> Result<()> {
+            calls += 1;
+            Err(std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into())
+        });
+        assert!(missing.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_list_available_models() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("list-models");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+        assert_eq!(list_available_models().unwrap(), "[]");
+
+        let models = dir.join("models").join("whisper-cpp");
+        fs::create_dir_all(&models).unwrap();
+        fs::write(models.join("ggml-tiny.en-q8_0.bin"), [0u8; 10]).unwrap();
+        fs::write(models.join("ggml-large-v3.bin"), [0u8; 30]).unwrap();
+        fs::write(models.join("custom.bin"), [0u8; 5]).unwrap();
+        fs::write(models.join("README.md"), "notes").unwrap();
+
+        let listed: Vec<serde_json::Value> = serde_json::from_str(&list_available_models().unwrap()).unwrap();
+        let names: Vec<&str> = listed.iter().map(|m| m["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["custom.bin", "ggml-large-v3.bin", "ggml-tiny.en-q8_0.bin"]);
+        assert_eq!(listed[1]["family"], "large");
+        assert_eq!(listed[1]["size_bytes"], 30);
+        assert_eq!(listed[2]["family"], "tiny");
+        assert!(listed[0]["family"].is_null());
+
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // ══ Snippet Tests ══════════════════════════════════════════════
+    #[test]
+    fn test_snippet_match_exact() {
+        let _lock = global_state_lock();
+        // Manually add a snippet to the store
+        {
+            let mut store = SNIPPETS.lock().unwrap();
+            store.push(VoiceSnippet {
+                id: String::new(),
+                trigger: "insert bio".to_string(),
+                content: "I am a software engineer...".to_string(),
+                variants: Vec::new(),
+                is_regex: false,
+            });
+        }
+        let result = match_snippet("insert bio");
+        assert!(result.is_some());
+        assert_eq!(result.unwrap(), "I am a software engineer...");
+        // Cleanup
+        SNIPPETS.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn test_snippet_match_case_insensitive() {
+        let _lock = global_state_lock();
+        {
+            let mut store = SNIPPETS.lock().unwrap();
+            store.push(VoiceSnippet {
+                id: String::new(),
+                trigger: "Insert Bio".to_string(),
+                content: "Bio content here".to_string(),
+                variants: Vec::new(),
+                is_regex: false,
+            });
+        }
+        let result = match_snippet("INSERT BIO");
+        assert!(result.is_some());
+        assert_eq!(result.unwrap(), "Bio content here");
+        SNIPPETS.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn test_snippet_no_match() {
+        let _lock = global_state_lock();
+        {
+            let mut store = SNIPPETS.lock().unwrap();
+            store.push(VoiceSnippet {
+                id: String::new(),
+                trigger: "insert bio".to_string(),
+                content: "Bio content here".to_string(),
+                variants: Vec::new(),
+                is_regex: false,
+            });
+        }
+        let result = match_snippet("hello world");
+        assert!(result.is_none());
+        SNIPPETS.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn test_snippet_suffix_match_keeps_lead() {
+        let _lock = global_state_lock();
+        add_snippet("sign off".to_string(), "Best, Sam".to_string()).unwrap();
+
+        assert_eq!(process_snippets("Thanks again. Sign off.".to_string()), "Thanks again. Best, Sam");
+        // Trigger must be the trailing words, not a fragment of one
+        assert_eq!(process_snippets("sign offer".to_string()), "sign offer");
+        assert_eq!(process_snippets("sign off now".to_string()), "sign off now");
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "sign off");
+    }
+
+    #[test]
+    fn test_paste_restores_clipboard_even_on_failure() {
+        let clipboard = std::cell::RefCell::new(String::new());
+        let set = |t: &str| -> Result<()> {
+            *clipboard.borrow_mut() = t.to_string();
+            Ok(())
+        };
+        let pasted = std::cell::RefCell::new(String::new());
+        paste_preserving("Dictated text", Some("copied link".to_string()), set, || {
+            *pasted.borrow_mut() = clipboard.borrow().clone();
+            Ok(())
+        }).unwrap();
+        assert_eq!(*pasted.borrow(), "Dictated text");
+        assert_eq!(*clipboard.borrow(), "copied link");
+
+        let result = paste_preserving("Dictated text", Some("copied link".to_string()), set, || {
+            Err(anyhow!("paste shortcut failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(*clipboard.borrow(), "copied link");
+    }
+
+    #[test]
+    fn test_linux_injection_backend() {
+        assert_eq!(linux_injection_backend(Some(":0"), None).unwrap(), "XTest");
+        assert_eq!(linux_injection_backend(Some(":0"), Some("wayland-0")).unwrap(), "XTest"); // XWayland
+        let err = linux_injection_backend(None, Some("wayland-0")).unwrap_err();
+        assert!(err.to_string().contains("not supported on Wayland"));
+        assert!(linux_injection_backend(Some(""), Some("wayland-0")).is_err());
+    }
+
+    #[test]
+    fn test_injection_mode_parse() {
+        assert_eq!(InjectionMode::parse(" Paste ").unwrap(), InjectionMode::Paste);
+        assert_eq!(InjectionMode::parse("keystroke").unwrap(), InjectionMode::Keystroke);
+        assert!(set_injection_mode("telepathy".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_snippet_resolution_modes() {
+        let _lock = global_state_lock();
+        SNIPPETS.lock().unwrap().clear();
+        add_snippet("bio".to_string(), "Short bio".to_string()).unwrap();
+        add_snippet("insert bio".to_string(), "Long bio".to_string()).unwrap();
+
+        // Default prefers the most specific trigger
+        assert_eq!(match_snippet("insert bio").unwrap(), "Long bio");
+
+        set_snippet_resolution("first".to_string()).unwrap();
+        assert_eq!(match_snippet("insert bio").unwrap(), "Short bio");
+        set_snippet_resolution("shortest".to_string()).unwrap();
+        assert_eq!(match_snippet("insert bio").unwrap(), "Short bio");
+        set_snippet_resolution("LONGEST".to_string()).unwrap();
+        assert_eq!(match_snippet("insert bio").unwrap(), "Long bio");
+
+        assert!(set_snippet_resolution("random".to_string()).is_err());
+        SNIPPETS.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn test_snippet_prefix_required() {
+        let _lock = global_state_lock();
+        add_snippet("home address".to_string(), "1 Main St".to_string()).unwrap();
+
+        set_snippet_prefix(Some("snippet".to_string())).unwrap();
+        assert_eq!(process_snippets("Snippet, home address.".to_string()), "1 Main St");
+        // Bare trigger and trailing trigger no longer fire
+        assert_eq!(process_snippets("home address".to_string()), "home address");
+        assert_eq!(process_snippets("my home address".to_string()), "my home address");
+        assert!(match_snippet("snippet my home address").is_none());
+
+        set_snippet_prefix(Some("  ".to_string())).unwrap();
+        assert_eq!(process_snippets("home address".to_string()), "1 Main St");
+        set_snippet_prefix(None).unwrap();
+        assert_eq!(match_snippet("Home address").unwrap(), "1 Main St");
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "home address");
+    }
+
+    #[test]
+    fn test_explain_snippet_near_miss() {
+        let _lock = global_state_lock();
+        SNIPPETS.lock().unwrap().clear();
+        add_snippet("insert bio".to_string(), "Bio".to_string()).unwrap();
+        add_snippet("weekly report".to_string(), "Report".to_string()).unwrap();
+
+        let explanation = explain_snippet_match("Insert bile.".to_string());
+        assert_eq!(explanation.normalized, "insert bile");
+        assert_eq!(explanation.closest_trigger.as_deref(), Some("insert bio"));
+        assert_eq!(explanation.distance, Some(2));
+        assert_eq!(explanation.winning_trigger, None);
+        assert_eq!(explanation.reason, MatchReason::BelowFuzzyThreshold);
+
+        let explanation = explain_snippet_match("something else entirely".to_string());
+        assert_eq!(explanation.reason, MatchReason::NoMatch);
+
+        let explanation = explain_snippet_match("ok insert bio".to_string());
+        assert_eq!(explanation.reason, MatchReason::Matched);
+        assert_eq!(explanation.distance, Some(0));
+        SNIPPETS.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn test_explain_snippet_disabled_and_shadowed() {
+        let _lock = global_state_lock();
+        SNIPPETS.lock().unwrap().clear();
+        add_snippet("bio".to_string(), "Short".to_string()).unwrap();
+        add_snippet("insert bio".to_string(), "Long".to_string()).unwrap();
+
+        set_snippet_expansion(false).unwrap();
+        let explanation = explain_snippet_match("insert bio".to_string());
+        assert_eq!(explanation.reason, MatchReason::Disabled);
+        assert_eq!(explanation.closest_trigger.as_deref(), Some("insert bio"));
+        set_snippet_expansion(true).unwrap();
+
+        set_snippet_resolution("shortest".to_string()).unwrap();
+        let explanation = explain_snippet_match("insert bio".to_string());
+        assert_eq!(explanation.reason, MatchReason::Shadowed);
+        assert_eq!(explanation.winning_trigger.as_deref(), Some("bio"));
+        set_snippet_resolution("longest".to_string()).unwrap();
+        SNIPPETS.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn test_flatten_newlines_in_snippet_output() {
+        let _lock = global_state_lock();
+        add_snippet("sig block".to_string(), "Jane Doe\r\nEngineering".to_string()).unwrap();
+
+        assert_eq!(process_snippets("sig block".to_string()), "Jane Doe\r\nEngineering");
+        set_flatten_newlines(true).unwrap();
+        assert_eq!(process_snippets("sig block".to_string()), "Jane Doe Engineering");
+        assert_eq!(flatten_newlines("a  \n\n  b\n"), "a b");
+        set_flatten_newlines(false).unwrap();
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "sig block");
+    }
+
+    #[test]
+    fn test_snippet_cooldown_blocks_rapid_refire() {
+        let _lock = global_state_lock();
+        add_snippet("echo me".to_string(), "Say echo me again".to_string()).unwrap();
+
+        set_snippet_cooldown_ms(60_000).unwrap();
+        assert_eq!(process_snippets("echo me".to_string()), "Say echo me again");
+        assert_eq!(process_snippets("Echo me.".to_string()), "Echo me.");
+
+        // Expires once the cooldown has passed
+        let cooldown = std::time::Duration::from_millis(100);
         let start = Instant::now();
-        let result = inject_text("".to_string(), 10);
-        let elapsed = start.elapsed();
+        assert!(snippet_off_cooldown("other", start, cooldown, true));
+        assert!(!snippet_off_cooldown("OTHER", start + cooldown / 2, cooldown, true));
+        assert!(snippet_off_cooldown("other", start + cooldown, cooldown, true));
 
-        assert!(result.is_ok(), "Empty string should succeed");
-        assert!(
-            elapsed < std::time::Duration::from_millis(5),
-            "Empty string should complete near-instantly, took {:?}",
-            elapsed
-        );
+        set_snippet_cooldown_ms(0).unwrap();
+        assert_eq!(process_snippets("echo me".to_string()), "Say echo me again");
+
+        // An interim preview doesn't use up the cooldown its final needs
+        set_snippet_cooldown_ms(60_000).unwrap();
+        assert_eq!(finish_pass("echo me", false, None).unwrap().text, "Say echo me again");
+        assert_eq!(finish_pass("echo me", true, None).unwrap().text, "Say echo me again");
+        assert_eq!(expand_snippets("echo me".to_string(), false).text, "echo me");
+        set_committed_prefix(String::new()).unwrap();
+        set_snippet_cooldown_ms(0).unwrap();
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "echo me");
     }
 
     #[test]
-    fn test_inject_text_unicode() {
-        let result = inject_text("Fair9 ✓ héllo 日本".to_string(), 1);
-        assert!(result.is_ok(), "Unicode injection should succeed");
+    fn test_peek_transcription_is_read_only() {
+        let _lock = global_state_lock();
+        let audio = vec![0.2; 1_600];
+        *STATE.audio_buffer.lock().unwrap() = audio.clone();
+        let metrics_before = get_transcription_metrics();
+
+        let fake_model = |samples: &[f32]| Ok(format!(" heard {} samples ", samples.len()));
+        assert_eq!(peek_with(fake_model).unwrap(), "heard 1600 samples");
+        assert_eq!(peek_with(fake_model).unwrap(), "heard 1600 samples");
+
+        assert_eq!(*STATE.audio_buffer.lock().unwrap(), audio);
+        assert_eq!(get_transcription_metrics(), metrics_before);
+
+        STATE.audio_buffer.lock().unwrap().clear();
+        assert_eq!(peek_transcription().unwrap(), "");
     }
 
     #[test]
-    fn test_check_for_updates_returns_version() {
-        let version = check_for_updates().unwrap();
-        assert_eq!(version, APP_VERSION, "Should return current version");
+    fn test_read_only_data_dir_keeps_working_in_memory() {
+        let _lock = global_state_lock();
+        // A data dir nested under a regular file can never be created or written
+        let blocker = temp_data_dir("read-only").join("not-a-dir");
+        fs::write(&blocker, "").unwrap();
+        set_data_dir(blocker.join("Fair9").to_string_lossy().to_string()).unwrap();
+
+        assert_eq!(init_storage().unwrap(), StorageStatus::DataDirReadOnly);
+        assert_eq!(get_status().storage, StorageStatus::DataDirReadOnly);
+
+        add_snippet("desk phone".to_string(), "555-0100".to_string()).unwrap();
+        save_snippets().unwrap();
+        flush_pending_writes().unwrap();
+        assert_eq!(process_snippets("Desk phone".to_string()), "555-0100");
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "desk phone");
+        SNIPPETS_DIRTY.store(false, Ordering::SeqCst);
+        set_data_dir(String::new()).unwrap();
+        assert_eq!(get_status().storage, StorageStatus::Writable);
     }
 
     #[test]
-    fn test_calculate_rms_silent() {
-        let silent = vec![0.0f32; 1600];
-        let rms = calculate_rms(&silent);
-        assert_eq!(rms, 0.0, "Silent audio should have 0 RMS");
+    fn test_snippet_variants_pick_randomly_and_reproducibly() {
+        let _lock = global_state_lock();
+        let signoffs = vec!["Cheers".to_string(), "Best regards".to_string(), "Thanks!".to_string()];
+        add_snippet_variants("sign off mail".to_string(), signoffs.clone()).unwrap();
+        assert!(add_snippet_variants("no variants".to_string(), Vec::new()).is_err());
+
+        let expand_all = |seed| {
+            set_snippet_seed(seed).unwrap();
+            (0..10).map(|_| process_snippets("Sign off mail".to_string())).collect::<Vec<_>>()
+        };
+        let first = expand_all(42);
+        assert!(first.iter().all(|text| signoffs.contains(text)));
+        assert_eq!(expand_all(42), first);
+
+        // One pick per utterance: the interims settle on a variant and the final types it
+        for seed in 0..8 {
+            set_snippet_seed(seed).unwrap();
+            let shown = finish_pass("Sign off mail", false, None).unwrap().text;
+            assert!(signoffs.contains(&shown));
+            for _ in 0..5 {
+                assert_eq!(finish_pass("Sign off mail", false, None).unwrap().text, shown);
+            }
+            assert_eq!(finish_pass("Sign off mail", true, None).unwrap().text, shown);
+        }
+        set_committed_prefix(String::new()).unwrap();
+        assert!(SNIPPET_UTTERANCE_VARIANTS.lock().unwrap().is_empty());
+
+        // Files from before variants existed load with their content as the only variant
+        let legacy: VoiceSnippet = serde_json::from_str(r#"{"trigger": "bio", "content": "My bio"}"#).unwrap();
+        assert_eq!(upgrade_snippet(legacy).variants, vec!["My bio".to_string()]);
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "sign off mail");
     }
 
     #[test]
-    fn test_calculate_rms_loud() {
-        let loud = vec![1.0f32; 1600];
-        let rms = calculate_rms(&loud);
-        assert!((rms - 1.0).abs() < 0.001, "Constant 1.0 audio should have RMS ~1.0");
+    fn test_snippet_end_only_expands_finals_at_the_end() {
+        let _lock = global_state_lock();
+        add_snippet("my sig".to_string(), "Jane Doe".to_string()).unwrap();
+        set_snippet_end_only(true).unwrap();
+
+        // Interims never expand, however confident
+        assert_eq!(finish_pass(" Thanks, my sig", false, Some(0.99)).unwrap().text, "Thanks, my sig");
+        // Finals expand a trailing trigger, keeping what came before
+        assert_eq!(finish_pass(" Thanks, my sig", true, None).unwrap().text, "Thanks, Jane Doe");
+        // ...but not one in the middle
+        assert_eq!(finish_pass(" my sig is below", true, None).unwrap().text, "my sig is below");
+
+        set_snippet_end_only(false).unwrap();
+        assert_eq!(finish_pass(" Thanks, my sig", false, Some(0.99)).unwrap().text, "Thanks, Jane Doe");
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "my sig");
     }
 
     #[test]
-    fn test_calculate_rms_empty() {
-        let empty: Vec<f32> = vec![];
-        let rms = calculate_rms(&empty);
-        assert_eq!(rms, 0.0, "Empty buffer should return 0 RMS");
+    fn test_snippet_min_confidence_gates_interim_expansion() {
+        let _lock = global_state_lock();
+        add_snippet("sign it".to_string(), "Regards".to_string()).unwrap();
+        set_snippet_min_confidence(0.6).unwrap();
+
+        let interim = |confidence| finish_pass(" Sign it", false, confidence).unwrap().text;
+        assert_eq!(interim(Some(0.4)), "Sign it");
+        assert_eq!(interim(Some(0.85)), "Regards");
+        assert_eq!(finish_pass(" Sign it", true, Some(0.2)).unwrap().text, "Regards");
+
+        assert_eq!(mean_token_confidence(&[
+            ("[_BEG_]".to_string(), 0.1),
+            (" Sign".to_string(), 0.5),
+            (" it".to_string(), 0.7),
+        ]), Some(0.6));
+        assert_eq!(mean_token_confidence(&[]), None);
+
+        assert!(set_snippet_min_confidence(-0.5).is_err());
+        set_snippet_min_confidence(0.0).unwrap();
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "sign it");
     }
 
-    // ── Filler Word Removal Tests ──────────────────────────────
+    #[test]
+    fn test_snippet_expansion_disabled() {
+        let _lock = global_state_lock();
+        add_snippet("pause bio".to_string(), "Bio content".to_string()).unwrap();
+
+        assert_eq!(process_snippets("Pause bio.".to_string()), "Bio content");
+        set_snippet_expansion(false).unwrap();
+        assert_eq!(process_snippets("Pause bio.".to_string()), "Pause bio.");
+        set_snippet_expansion(true).unwrap();
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "pause bio");
+    }
 
     #[test]
-    fn test_clean_filler_basic() {
-        let input = "I um want to uh create a function";
-        let result = clean_filler_words(input);
-        assert_eq!(result, "I want to create a function");
+    fn test_snippet_escape_prefix() {
+        let _lock = global_state_lock();
+        add_snippet("escape bio".to_string(), "Bio content".to_string()).unwrap();
+
+        assert_eq!(process_snippets("Literally, escape bio".to_string()), "escape bio");
+        assert_eq!(process_snippets("literally escape bio".to_string()), "escape bio");
+        // Prefix must be a whole word
+        assert_eq!(strip_escape_prefix("literallyescape bio", "literally"), None);
+
+        set_snippet_escape_prefix("verbatim".to_string()).unwrap();
+        assert_eq!(process_snippets("verbatim escape bio".to_string()), "escape bio");
+        // "literally" is now an ordinary word, so the trailing trigger expands
+        assert_eq!(process_snippets("literally escape bio".to_string()), "literally Bio content");
+        set_snippet_escape_prefix(DEFAULT_SNIPPET_ESCAPE_PREFIX.to_string()).unwrap();
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "escape bio");
     }
 
     #[test]
-    fn test_clean_filler_multiple() {
-        let input = "so um like basically I you know think hmm we should";
-        let result = clean_filler_words(input);
-        assert_eq!(result, "so I think we should");
+    fn test_snippet_libraries_create_list_switch() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("libraries");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+        SNIPPETS.lock().unwrap().clear();
+
+        add_snippet("insert bio".to_string(), "Work bio".to_string()).unwrap();
+        switch_snippet_library("personal".to_string()).unwrap();
+        assert_eq!(get_active_snippet_library(), "personal");
+        // A fresh library starts empty rather than inheriting the previous one
+        assert!(match_snippet("insert bio").is_none());
+
+        add_snippet("insert bio".to_string(), "Personal bio".to_string()).unwrap();
+        assert_eq!(list_snippet_libraries().unwrap(), vec!["default", "personal"]);
+
+        switch_snippet_library("default".to_string()).unwrap();
+        assert_eq!(match_snippet("insert bio").unwrap(), "Work bio");
+        switch_snippet_library("personal".to_string()).unwrap();
+        assert_eq!(match_snippet("insert bio").unwrap(), "Personal bio");
+
+        assert!(switch_snippet_library("../escape".to_string()).is_err());
+
+        switch_snippet_library("default".to_string()).unwrap();
+        SNIPPETS.lock().unwrap().clear();
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
     }
 
     #[test]
-    fn test_clean_filler_no_false_positives() {
-        // "like" as legitimate word, "plumber" contains "um" substring
-        let input = "I would like to book a plumber";
-        let result = clean_filler_words(input);
-        // "like" as standalone filler IS removed, but "plumber" is preserved
-        assert_eq!(result, "I would to book a plumber");
+    fn test_snippet_ids_stable_across_reload() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("snippet-ids");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+        fs::write(dir.join("snippets.json"), r#"{"snippets": [{"trigger": "old bio", "content": "Legacy"}]}"#).unwrap();
+
+        load_snippets().unwrap();
+        let legacy_id = SNIPPETS.lock().unwrap()[0].id.clone();
+        assert!(!legacy_id.is_empty());
+        add_snippet("new bio".to_string(), "Fresh".to_string()).unwrap();
+        let new_id = SNIPPETS.lock().unwrap()[1].id.clone();
+        assert_ne!(legacy_id, new_id);
+        save_snippets().unwrap();
+
+        load_snippets().unwrap();
+        let ids: Vec<String> = SNIPPETS.lock().unwrap().iter().map(|s| s.id.clone()).collect();
+        assert_eq!(ids, vec![legacy_id.clone(), new_id]);
+
+        remove_snippet_by_id(legacy_id.clone()).unwrap();
+        assert!(match_snippet("old bio").is_none());
+        assert_eq!(match_snippet("new bio").unwrap(), "Fresh");
+        assert!(remove_snippet_by_id(legacy_id).is_err());
+        remove_snippet("New Bio".to_string()).unwrap();
+        assert!(SNIPPETS.lock().unwrap().is_empty());
+
+        SNIPPETS_DIRTY.store(false, Ordering::SeqCst);
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
     }
 
     #[test]
-    fn test_clean_filler_empty() {
-        let input = "";
-        let result = clean_filler_words(input);
-        assert_eq!(result, "");
+    fn test_snippet_placeholders() {
+        use chrono::TimeZone;
+        let _lock = global_state_lock();
+        let now = chrono::Local.with_ymd_and_hms(2024, 3, 9, 14, 5, 0).unwrap();
+        let no_clipboard = || -> Option<String> { panic!("clipboard read without a {{clipboard}} token") };
+
+        assert_eq!(expand_placeholders("Sent {date}", now, no_clipboard), "Sent 2024-03-09");
+        assert_eq!(expand_placeholders("{datetime} / {time}", now, no_clipboard), "2024-03-09 14:05 / 14:05");
+        assert_eq!(expand_placeholders("Hi {name}, {DATE} {unknown}", now, no_clipboard), "Hi {name}, {DATE} {unknown}");
+        assert_eq!(
+            expand_placeholders("See {clipboard} and {clipboard}", now, || Some("https://x.y".to_string())),
+            "See https://x.y and https://x.y"
+        );
+        assert_eq!(expand_placeholders("[{clipboard}]", now, || None), "[]");
+
+        // A marker in the clipboard is pasted as text; the snippet's own sets the caret
+        let pasted = render_snippet("[{clipboard}]({cursor})", |part| {
+            expand_placeholders(part, now, || Some("x{cursor}y".to_string()))
+        });
+        assert_eq!(pasted.text, "[x{cursor}y]()");
+        assert_eq!(&pasted.text[pasted.cursor_offset.unwrap()..], ")");
+
+        // Interims preview placeholders as written instead of reading the clipboard
+        add_snippet("paste link".to_string(), "See {clipboard}".to_string()).unwrap();
+        assert_eq!(apply_snippet_expansion("paste link", false).text, "See {clipboard}");
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "paste link");
+
+        set_snippet_date_format("%d/%m/%Y".to_string(), "%I:%M %p".to_string()).unwrap();
+        assert_eq!(expand_placeholders("{datetime}", now, no_clipboard), "09/03/2024 02:05 PM");
+        assert!(set_snippet_date_format("%Q".to_string(), "%H".to_string()).is_err());
+        set_snippet_date_format(DEFAULT_SNIPPET_DATE_FORMAT.to_string(), DEFAULT_SNIPPET_TIME_FORMAT.to_string()).unwrap();
     }
 
-    // ══ Snippet Tests ══════════════════════════════════════════════
     #[test]
-    fn test_snippet_match_exact() {
-        // Manually add a snippet to the store
-        {
-            let mut store = SNIPPETS.lock().unwrap();
-            store.push(VoiceSnippet {
-                trigger: "insert bio".to_string(),
-                content: "I am a software engineer...".to_string(),
-            });
-        }
-        let result = match_snippet("insert bio");
-        assert!(result.is_some());
-        assert_eq!(result.unwrap(), "I am a software engineer...");
-        // Cleanup
+    fn test_snippet_content_normalized_on_save() {
+        let _lock = global_state_lock();
         SNIPPETS.lock().unwrap().clear();
-    }
+        let pasted = "Best regards,  \r\nJane Doe\t\r\n\r\nACME Corp   \rSales";
+        assert_eq!(normalize_snippet_content(pasted), "Best regards,\nJane Doe\n\nACME Corp\nSales");
+
+        add_snippet("raw sig".to_string(), pasted.to_string()).unwrap();
+        set_snippet_normalize(true).unwrap();
+        add_snippet("my sig".to_string(), pasted.to_string()).unwrap();
+        import_snippets(r#"{"snippets": [{"trigger": "imported", "content": "Hi  \r\n\r\nthere "}]}"#.to_string()).unwrap();
+        set_snippet_normalize(false).unwrap();
+
+        let store = SNIPPETS.lock().unwrap().clone();
+        assert_eq!(store[0].content, pasted);
+        assert_eq!(store[1].content, "Best regards,\nJane Doe\n\nACME Corp\nSales");
+        assert_eq!(store[1].variants, vec![store[1].content.clone()]);
+        assert_eq!(store[2].content, "Hi\n\nthere");
+        assert_eq!(store[2].variants, vec!["Hi\n\nthere".to_string()]);
 
-    #[test]
-    fn test_snippet_match_case_insensitive() {
-        {
-            let mut store = SNIPPETS.lock().unwrap();
-            store.push(VoiceSnippet {
-                trigger: "Insert Bio".to_string(),
-                content: "Bio content here".to_string(),
-            });
-        }
-        let result = match_snippet("INSERT BIO");
-        assert!(result.is_some());
-        assert_eq!(result.unwrap(), "Bio content here");
         SNIPPETS.lock().unwrap().clear();
+        SNIPPETS_DIRTY.store(false, Ordering::SeqCst);
     }
 
     #[test]
-    fn test_snippet_no_match() {
-        {
-            let mut store = SNIPPETS.lock().unwrap();
-            store.push(VoiceSnippet {
-                trigger: "insert bio".to_string(),
-                content: "Bio content here".to_string(),
-            });
-        }
-        let result = match_snippet("hello world");
-        assert!(result.is_none());
+    fn test_v0_snippet_file_migrates_once() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("snippet-migration");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+        let path = dir.join("snippets.json");
+        fs::write(&path, r#"{"snippets": [
+            {"trigger": "my sig", "content": "Jane Doe"},
+            {"trigger": "sign off", "content": "Cheers", "variants": ["Cheers", "Best"]}
+        ]}"#).unwrap();
+
+        assert_eq!(load_snippets().unwrap(), 2);
+        let migrated: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(migrated["version"], SNIPPET_SCHEMA_VERSION);
+        let snippets = &migrated["snippets"];
+        assert_eq!(snippets[0]["trigger"], "my sig");
+        assert_eq!(snippets[0]["content"], "Jane Doe");
+        assert_eq!(snippets[0]["variants"], json!(["Jane Doe"]));
+        assert_eq!(snippets[1]["variants"], json!(["Cheers", "Best"]));
+        assert!(!snippets[0]["id"].as_str().unwrap().is_empty());
+        assert!(!SNIPPETS_DIRTY.load(Ordering::SeqCst));
+
+        // Already current: loads as-is, ids unchanged, nothing left to write
+        let written = fs::read_to_string(&path).unwrap();
+        load_snippets().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), written);
+        assert_eq!(SNIPPETS.lock().unwrap()[0].id, snippets[0]["id"].as_str().unwrap());
+
+        fs::write(&path, r#"{"version": 99, "snippets": []}"#).unwrap();
+        assert!(load_snippets().unwrap_err().to_string().contains("version 99"));
+
         SNIPPETS.lock().unwrap().clear();
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
     }
 
     #[test]
@@ -510,6 +7598,43 @@ mod tests {
         assert_eq!(content.unwrap(), "Hello world");
     }
 
+    #[test]
+    fn test_snippet_max_chars_boundary() {
+        let _lock = global_state_lock();
+        set_max_snippet_chars(20).unwrap();
+
+        let at_limit = "a".repeat(20);
+        assert!(add_snippet("limit exact".to_string(), at_limit).is_ok());
+
+        let under_limit = "b".repeat(19);
+        assert!(add_snippet("limit under".to_string(), under_limit).is_ok());
+
+        let over_limit = "c".repeat(21);
+        let result = add_snippet("limit over".to_string(), over_limit);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("character limit"));
+        assert!(match_snippet("limit over").is_none());
+
+        // Import is all-or-nothing
+        let json = r#"{"snippets":[
+            {"trigger":"import ok","content":"short"},
+            {"trigger":"import big","content":"this content is well over twenty chars"}
+        ]}"#;
+        let result = import_snippets(json.to_string());
+        assert!(result.is_err());
+        // Nothing from a rejected import should land in the store
+        assert!(match_snippet("import ok").is_none());
+
+        set_max_snippet_chars(DEFAULT_MAX_SNIPPET_CHARS).unwrap();
+        SNIPPETS.lock().unwrap().retain(|s| !s.trigger.starts_with("limit "));
+    }
+
+    #[test]
+    fn test_large_expansion_needs_confirmation() {
+        assert!(!needs_injection_confirmation(&"x".repeat(SNIPPET_CONFIRM_CHARS)));
+        assert!(needs_injection_confirmation(&"x".repeat(SNIPPET_CONFIRM_CHARS + 1)));
+    }
+
     // ══ AI Command Mode Tests ══════════════════════════════════════
     #[test]
     fn test_command_rejects_empty_text() {
@@ -535,6 +7660,192 @@ mod tests {
         assert!(result.unwrap_err().to_string().contains("No voice command"));
     }
 
+    #[test]
+    fn test_command_rejects_oversized_input() {
+        let _lock = global_state_lock();
+        let monologue = "word ".repeat(DEFAULT_MAX_AI_INPUT_CHARS / 5 + 1);
+        let result = process_ai_command_with_config(
+            "summarize".to_string(),
+            monologue,
+            "http://localhost:99999".to_string(),
+            "test".to_string(),
+        );
+        let message = result.unwrap_err().to_string();
+        assert!(message.contains("Select a smaller portion"), "{}", message);
+
+        assert!(check_ai_input_size("short", 5).is_ok());
+        assert!(check_ai_input_size("longer", 5).is_err());
+        assert!(set_max_ai_input_chars(0).is_err());
+    }
+
+    #[test]
+    fn test_ollama_errors_say_refused_or_timed_out() {
+        let _lock = global_state_lock();
+        set_ollama_retries(0).unwrap();
+        set_ollama_timeout_ms(300).unwrap();
+
+        // Nothing listens on a port we just released
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let refused = ollama_generate(&format!("http://127.0.0.1:{}", port), "m", "p".into(), "s".into()).unwrap_err();
+        assert!(refused.to_string().contains("connection refused"), "{:#}", refused);
+
+        // Accepted by the kernel but never answered, like a cold-starting model
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", silent.local_addr().unwrap());
+        let timed_out = ollama_generate(&url, "m", "p".into(), "s".into()).unwrap_err();
+        assert!(timed_out.to_string().contains("timed out"), "{:#}", timed_out);
+
+        // Retries back off on timeouts, but not on other failures or once streaming began
+        set_ollama_retries(2).unwrap();
+        let attempts = std::cell::Cell::new(0);
+        let flaky = with_ollama_retries("x", std::time::Duration::from_millis(1), || true, |_| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                return Err(anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::TimedOut)));
+            }
+            Ok("done")
+        });
+        assert_eq!((flaky.unwrap(), attempts.get()), ("done", 3));
+        attempts.set(0);
+        let _ = with_ollama_retries("x", std::time::Duration::from_millis(1), || true, |_| -> Result<()> {
+            attempts.set(attempts.get() + 1);
+            Err(anyhow!("Failed to parse Ollama response"))
+        });
+        assert_eq!(attempts.get(), 1);
+        attempts.set(0);
+        let _ = with_ollama_retries("x", std::time::Duration::from_millis(1), || false, |_| -> Result<()> {
+            attempts.set(attempts.get() + 1);
+            Err(anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::TimedOut)))
+        });
+        assert_eq!(attempts.get(), 1);
+
+        set_ollama_retries(DEFAULT_OLLAMA_RETRIES as u32).unwrap();
+        set_ollama_timeout_ms(DEFAULT_OLLAMA_TIMEOUT_MS).unwrap();
+    }
+
+    #[test]
+    fn test_openai_compatible_provider() {
+        let _lock = global_state_lock();
+        assert_eq!(chat_completions_url("https://api.example.com/"), "https://api.example.com/v1/chat/completions");
+        assert_eq!(chat_completions_url("http://localhost:8080/v1"), "http://localhost:8080/v1/chat/completions");
+        let body = chat_request_body("gpt-x", "Be terse.", "Say \"hi\"\nplease");
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["content"], "Say \"hi\"\nplease");
+        assert!(parse_chat_response(r#"{"choices":[]}"#).is_err());
+
+        // A one-shot local endpoint that records what it was sent
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", server.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            use std::io::{BufRead, Read, Write};
+            let (stream, _) = server.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream);
+            let mut head = String::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                head.push_str(&line);
+                line.clear();
+            }
+            let length: usize = head.lines()
+                .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse().unwrap()))
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let reply = r#"{"choices":[{"message":{"role":"assistant","content":" Hello, world. "}}]}"#;
+            write!(reader.get_mut(), "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", reply.len(), reply).unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+
+        set_ai_provider("openai-compatible".to_string()).unwrap();
+        set_ai_api_key(Some("sk-test".to_string())).unwrap();
+        let edited = process_ai_command_with_config(
+            "fix it".to_string(),
+            "hello world".to_string(),
+            base,
+            "gpt-x".to_string(),
+        ).unwrap();
+        assert_eq!(edited, "Hello, world.");
+        let (head, body) = handle.join().unwrap();
+        assert!(head.starts_with("POST /v1/chat/completions"));
+        assert!(head.contains("Authorization: Bearer sk-test"));
+        let sent: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(sent["model"], "gpt-x");
+        assert!(sent["messages"][1]["content"].as_str().unwrap().contains("hello world"));
+
+        assert!(set_ai_provider("bard".to_string()).is_err());
+        set_ai_provider("ollama".to_string()).unwrap();
+        set_ai_api_key(None).unwrap();
+        COMMAND_HISTORY.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn test_ollama_stream_reassembles_split_lines() {
+        // Hand out a few bytes per read so JSON lines arrive in pieces
+        struct Trickle(std::io::Cursor<Vec<u8>>);
+        impl std::io::Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(5);
+                self.0.read(&mut buf[..n])
+            }
+        }
+        let body = concat!(
+            "{\"response\":\"Hello\",\"done\":false}\n",
+            "{\"response\":\", wörld\",\"done\":false}\n",
+            "\n",
+            "{\"response\":\"\",\"done\":true,\"total_duration\":12}\n",
+            "{\"response\":\"ignored\"}\n",
+        );
+        let reader = std::io::BufReader::with_capacity(4, Trickle(std::io::Cursor::new(body.as_bytes().to_vec())));
+        let mut pieces = Vec::new();
+        let response = read_ollama_stream(reader, |p| pieces.push(p.to_string())).unwrap();
+        assert_eq!(pieces, vec!["Hello", ", wörld"]);
+        assert_eq!(response, "Hello, wörld");
+
+        let failed = read_ollama_stream("{\"error\":\"model not found\"}\n".as_bytes(), |_| {});
+        assert!(failed.unwrap_err().to_string().contains("model not found"));
+        let cut_off = read_ollama_stream("{\"response\":\"Hel\"}\n{\"resp".as_bytes(), |_| {});
+        assert!(cut_off.is_err());
+    }
+
+    #[test]
+    fn test_heartbeat_stops_when_request_resolves() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = ticks.clone();
+        let heartbeat = Heartbeat::start(std::time::Duration::from_millis(30), move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // Simulated slow request
+        thread::sleep(std::time::Duration::from_millis(150));
+        heartbeat.stop();
+        let at_stop = ticks.load(Ordering::SeqCst);
+        assert!(at_stop >= 2, "should tick while working, got {}", at_stop);
+
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(ticks.load(Ordering::SeqCst), at_stop, "no ticks after completion");
+    }
+
+    #[test]
+    fn test_recent_commands_dedupe_newest_first() {
+        let _lock = global_state_lock();
+        COMMAND_HISTORY.lock().unwrap().clear();
+
+        record_ai_command("fix grammar");
+        record_ai_command("make concise");
+        record_ai_command("Fix Grammar");
+        record_ai_command("translate to French");
+        record_ai_command("  ");
+
+        assert_eq!(
+            get_recent_commands(10),
+            vec!["translate to French", "Fix Grammar", "make concise"]
+        );
+        assert_eq!(get_recent_commands(2), vec!["translate to French", "Fix Grammar"]);
+
+        COMMAND_HISTORY.lock().unwrap().clear();
+        HISTORY_DIRTY.store(false, Ordering::SeqCst);
+    }
+
     #[test]
     fn test_ai_system_prompt_format() {
         // Verify the system prompt contains key instructions
@@ -543,10 +7854,63 @@ mod tests {
         assert!(AI_SYSTEM_PROMPT.contains("ONLY the modified text"));
     }
 
+    #[test]
+    fn test_translation_prompt_uses_target_language() {
+        let prompt = translation_system_prompt("French");
+        assert!(prompt.contains("into French"));
+        assert!(prompt.contains("ONLY the translated text"));
+    }
+
+    #[test]
+    fn test_translate_rejects_empty_input() {
+        let result = translate_text("".to_string(), "French".to_string());
+        assert!(result.unwrap_err().to_string().contains("No text to translate"));
+
+        let result = translate_text("Guten Morgen".to_string(), " ".to_string());
+        assert!(result.unwrap_err().to_string().contains("No target language"));
+    }
+
+    #[test]
+    fn test_looks_like_code_detects_code() {
+        let code = "fn main() {\n    let x = compute(1, 2);\n    println!(\"{}\", x);\n}";
+        assert!(looks_like_code(code));
+        assert!(is_code_selection(code.to_string()));
+        assert_eq!(select_system_prompt(code, true, AI_SYSTEM_PROMPT), CODE_SYSTEM_PROMPT);
+        // Toggle off keeps the prose prompt
+        assert_eq!(select_system_prompt(code, false, AI_SYSTEM_PROMPT), AI_SYSTEM_PROMPT);
+    }
+
+    #[test]
+    fn test_looks_like_code_rejects_prose() {
+        let prose = "Thanks for the update. I think we should ship this on Friday (after the review), if everyone agrees.";
+        assert!(!looks_like_code(prose));
+        assert!(!is_code_selection(prose.to_string()));
+        assert_eq!(select_system_prompt(prose, true, AI_SYSTEM_PROMPT), AI_SYSTEM_PROMPT);
+        assert!(!looks_like_code(""));
+    }
+
+    #[test]
+    fn test_custom_ai_system_prompt() {
+        let _lock = global_state_lock();
+        assert_eq!(ai_system_prompt(), AI_SYSTEM_PROMPT);
+
+        set_ai_system_prompt("  Summarize the text in one sentence. ".to_string()).unwrap();
+        let custom = ai_system_prompt();
+        assert_eq!(custom, "Summarize the text in one sentence.");
+        assert_eq!(select_system_prompt("Ship it on Friday.", true, &custom), custom);
+        assert!(set_ai_system_prompt(" ".to_string()).is_err());
+        assert_eq!(ai_system_prompt(), custom);
+
+        reset_ai_system_prompt().unwrap();
+        assert_eq!(ai_system_prompt(), AI_SYSTEM_PROMPT);
+    }
+
     #[test]
     fn test_whisper_mode_params() {
+        let _lock = global_state_lock();
         set_whisper_mode(true).unwrap();
         assert!(WHISPER_MODE.load(Ordering::SeqCst));
+        assert!(!WHISPER_MODE_DSP.load(Ordering::SeqCst), "decode tuning alone doesn't change the audio");
         
         let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
         if WHISPER_MODE.load(Ordering::SeqCst) {
@@ -559,8 +7923,323 @@ mod tests {
         assert_eq!(WHISPER_MODE.load(Ordering::SeqCst), false);
     }
 
+    #[test]
+    fn test_whisper_mode_dsp_boosts_and_removes_dc() {
+        // Quiet 1kHz tone riding on a DC offset
+        let input: Vec<f32> = (0..SAMPLE_RATE)
+            .map(|i| 0.2 + 0.01 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect();
+        let output = whisper_mode_dsp(&input);
+        assert_eq!(output.len(), input.len());
+
+        let tail = &output[SAMPLE_RATE / 2..];
+        let mean = tail.iter().sum::<f32>() / tail.len() as f32;
+        let peak = tail.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!(mean.abs() < 0.01, "DC should be filtered, mean {}", mean);
+        assert!(peak > 0.05 && peak <= 1.0, "tone should be ~15dB louder, peak {}", peak);
+    }
+
+    #[test]
+    fn test_compare_dsp_produces_both_transcriptions() {
+        let samples = vec![0.01f32; SAMPLE_RATE];
+        let mut passes = Vec::new();
+        let result = compare_dsp(&samples, |audio| {
+            let peak = audio.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            passes.push(peak);
+            Ok(" hello world ".to_string())
+        })
+        .unwrap();
+        assert_eq!(result.with_dsp, "hello world");
+        assert_eq!(result.without_dsp, "hello world");
+        assert_eq!(passes.len(), 2);
+        assert_ne!(passes[0], passes[1], "only one pass should see the processed audio");
+
+        let _lock = global_state_lock();
+        if STATE.model_ctx.lock().unwrap().is_none() {
+            assert!(transcribe_samples_both(samples).is_err());
+        }
+    }
+
+    #[test]
+    fn test_suppress_settings_applied_to_params() {
+        let _lock = global_state_lock();
+        let defaults = DecodeOptions::current();
+        assert!(defaults.suppress_blank);
+        assert!(!defaults.suppress_non_speech);
+
+        set_suppress_blank(false).unwrap();
+        set_suppress_non_speech(true).unwrap();
+        let opts = DecodeOptions::current();
+        assert!(!opts.suppress_blank);
+        assert!(opts.suppress_non_speech);
+        // Both transcription paths build their params from the same snapshot
+        let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+        opts.apply(&mut params);
+
+        set_suppress_blank(true).unwrap();
+        set_suppress_non_speech(false).unwrap();
+        assert_eq!(DecodeOptions::current(), defaults);
+    }
+
+    #[test]
+    fn test_keyword_boost_builds_prompt() {
+        let _lock = global_state_lock();
+        let words = vec!["Fair9".to_string(), " Grafana ".to_string(), "fair9".to_string(), "".to_string()];
+
+        set_keyword_boost(words.clone(), 0.0).unwrap();
+        let light = DecodeOptions::current().initial_prompt.unwrap();
+        assert_eq!(light, "Glossary: Fair9, Grafana.");
+
+        set_keyword_boost(words, 1.0).unwrap();
+        let heavy = DecodeOptions::current().initial_prompt.unwrap();
+        assert_eq!(heavy.matches("Grafana").count(), KEYWORD_BOOST_MAX_REPEATS);
+        assert!(heavy.starts_with(&light));
+
+        // A long list still gets listed once, just not repeated
+        let many: Vec<String> = (0..80).map(|i| format!("term{}", i)).collect();
+        assert_eq!(keyword_prompt(&many, 1.0).unwrap().matches("term79").count(), 1);
+
+        assert!(set_keyword_boost(vec!["x".to_string()], 1.5).is_err());
+        set_keyword_boost(Vec::new(), 0.0).unwrap();
+        assert_eq!(DecodeOptions::current().initial_prompt, None);
+    }
+
+    #[test]
+    fn test_segment_length_applied_to_params() {
+        let _lock = global_state_lock();
+        let defaults = DecodeOptions::current();
+        assert_eq!(defaults.max_segment_chars, 0);
+        assert!(!defaults.split_on_word);
+
+        set_max_segment_chars(42).unwrap();
+        set_split_on_word(true).unwrap();
+        let opts = DecodeOptions::current();
+        assert_eq!(opts.max_segment_chars, 42);
+        assert!(opts.split_on_word);
+        let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+        opts.apply(&mut params);
+
+        assert!(set_max_segment_chars(-1).is_err());
+        set_max_segment_chars(0).unwrap();
+        set_split_on_word(false).unwrap();
+        assert_eq!(DecodeOptions::current(), defaults);
+    }
+
+    #[test]
+    fn test_language_applied_to_params() {
+        let _lock = global_state_lock();
+        assert_eq!(DecodeOptions::current().language, Some("en"));
+
+        set_language(" DE ".to_string()).unwrap();
+        assert_eq!(DecodeOptions::current().language, Some("de"));
+        set_language("auto".to_string()).unwrap();
+        let opts = DecodeOptions::current();
+        assert_eq!(opts.language, None);
+        let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+        opts.apply(&mut params);
+
+        assert!(set_language("klingon".to_string()).is_err());
+        assert_eq!(DecodeOptions::current().language, None);
+        set_language("en".to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_translate_mode_composes_with_language() {
+        let _lock = global_state_lock();
+        assert!(!DecodeOptions::current().translate);
+
+        set_language("es".to_string()).unwrap();
+        set_translate_mode(true).unwrap();
+        let opts = DecodeOptions::current();
+        assert!(opts.translate);
+        assert_eq!(opts.language, Some("es"));
+        let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+        opts.apply(&mut params);
+        // Translated output goes through the same cleanup as any other
+        assert_eq!(clean_transcript(" I need the uh report um tomorrow."), "I need the report tomorrow.");
+
+        set_translate_mode(false).unwrap();
+        set_language("en".to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_flush_all_persists_pending_writes() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("flush-all");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+        write_settings(&Settings::current()).unwrap();
+
+        add_snippet("flush me".to_string(), "flushed".to_string()).unwrap();
+        record_ai_command("make it shorter");
+        set_min_interim_words(4).unwrap();
+        flush_all().unwrap();
+
+        assert!(fs::read_to_string(dir.join("snippets.json")).unwrap().contains("flush me"));
+        assert!(fs::read_to_string(dir.join("command_history.json")).unwrap().contains("make it shorter"));
+        let saved: Settings = serde_json::from_str(&fs::read_to_string(dir.join("settings.json")).unwrap()).unwrap();
+        assert_eq!(saved.min_interim_words, 4);
+        assert!(!SNIPPETS_DIRTY.load(Ordering::SeqCst) && !HISTORY_DIRTY.load(Ordering::SeqCst));
+        assert!(settings_pending().is_none());
+
+        // A data dir whose settings were never read is left alone
+        set_min_interim_words(0).unwrap();
+        set_data_dir(dir.join("fresh").to_string_lossy().to_string()).unwrap();
+        flush_all().unwrap();
+        assert!(!dir.join("fresh").join("settings.json").exists());
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "flush me");
+        COMMAND_HISTORY.lock().unwrap().clear();
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_settings_survive_restart() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("load-settings");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+
+        assert!(!load_settings().unwrap());
+        assert_eq!(Settings::current(), Settings::default());
+
+        set_whisper_mode(true).unwrap();
+        set_language("de".to_string()).unwrap();
+        set_vad_threshold(0.03).unwrap();
+        set_live_processing(false).unwrap();
+        save_settings().unwrap();
+        let saved = Settings::current();
+
+        // Next launch starts from defaults, then loads
+        Settings::default().apply();
+        assert!(load_settings().unwrap());
+        assert_eq!(Settings::current(), saved);
+        assert_eq!(*LANGUAGE.lock().unwrap(), Some("de"));
+
+        fs::write(dir.join("settings.json"), "{ not json").unwrap();
+        assert!(!load_settings().unwrap());
+        assert_eq!(Settings::current(), Settings::default());
+        // The corrupt file isn't overwritten behind the user's back
+        flush_all().unwrap();
+        assert_eq!(fs::read_to_string(dir.join("settings.json")).unwrap(), "{ not json");
+
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reset_settings_restores_defaults() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("reset");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+
+        set_whisper_mode(true).unwrap();
+        set_min_interim_words(4).unwrap();
+        set_suppress_blank(false).unwrap();
+        set_snippet_escape_prefix("verbatim".to_string()).unwrap();
+        add_snippet("reset keeps me".to_string(), "still here".to_string()).unwrap();
+
+        let summary = reset_settings().unwrap();
+        assert!(summary.contains("whisper_mode"));
+        assert!(summary.contains("min_interim_words"));
+        assert_eq!(Settings::current(), Settings::default());
+
+        let saved: Settings = serde_json::from_str(&fs::read_to_string(dir.join("settings.json")).unwrap()).unwrap();
+        assert_eq!(saved, Settings::default());
+        // Snippets are untouched
+        assert_eq!(match_snippet("reset keeps me").unwrap(), "still here");
+
+        assert!(reset_settings().unwrap().contains("already"));
+
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "reset keeps me");
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_session_record_and_replay() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("session_replay");
+        fs::create_dir_all(&dir).unwrap();
+        let fixture_path = dir.join("session.json").to_string_lossy().to_string();
+
+        // Deterministic stand-in for the model: one word per 0.5s of audio
+        let fake_model = |samples: &[f32]| -> Result<String> {
+            let words = ["so", "um", "the", "quick", "brown", "fox"];
+            Ok(words[..(samples.len() / MIN_INTERIM_SAMPLES).min(words.len())].join(" "))
+        };
+
+        start_session_recording().unwrap();
+        assert!(start_session_recording().is_err());
+        let mut live = Vec::new();
+        for (seconds, is_final) in [(1usize, false), (2, false), (3, true)] {
+            let samples = vec![0.1f32; SAMPLE_RATE * seconds];
+            record_session_pass(&samples, is_final);
+            if let Some(result) = finish_pass(&fake_model(&samples).unwrap(), is_final, None) {
+                live.push(result.text);
+            }
+        }
+        assert_eq!(stop_session_recording(fixture_path.clone()).unwrap(), 3);
+
+        // Settings changed after recording must not leak into the replay
+        set_strip_nonspeech_tags(false).unwrap();
+        let fixture: SessionFixture = serde_json::from_str(&fs::read_to_string(&fixture_path).unwrap()).unwrap();
+        let replayed = replay_with(&fixture, |samples, _| fake_model(samples)).unwrap();
+        assert_eq!(replayed, live);
+        assert_eq!(replayed.last().unwrap(), "so the quick brown fox");
+        assert!(!STRIP_NONSPEECH_TAGS.load(Ordering::SeqCst), "settings are restored after replay");
+        set_strip_nonspeech_tags(true).unwrap();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_transcription_metrics_counts_outcomes() {
+        let _lock = global_state_lock();
+        let dir = temp_data_dir("metrics");
+        set_data_dir(dir.to_string_lossy().to_string()).unwrap();
+        reset_transcription_metrics().unwrap();
+
+        record_transcription_outcome(Some(" Hello there. "));
+        record_transcription_outcome(Some(" [BLANK_AUDIO] "));
+        record_transcription_outcome(None);
+        record_transcription_outcome(Some("Again"));
+        let expected = TranscriptionMetrics { successes: 2, failures: 1, no_speech: 1 };
+        assert_eq!(get_transcription_metrics(), expected);
+
+        // Persisted only when enabled
+        flush_pending_writes().unwrap();
+        assert!(!dir.join("metrics.json").exists());
+        set_persist_metrics(true).unwrap();
+        flush_pending_writes().unwrap();
+        reset_transcription_metrics().unwrap();
+        assert_eq!(load_transcription_metrics().unwrap(), expected);
+        assert_eq!(get_transcription_metrics(), expected);
+
+        set_persist_metrics(false).unwrap();
+        reset_transcription_metrics().unwrap();
+        set_data_dir(String::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pick_fastest_thread_count() {
+        use std::time::Duration;
+        let timings = [(2, Duration::from_millis(900)), (4, Duration::from_millis(520)), (8, Duration::from_millis(610))];
+        assert_eq!(pick_fastest(&timings), Some(4));
+
+        // Ties prefer fewer threads
+        let tied = [(8, Duration::from_millis(500)), (4, Duration::from_millis(500))];
+        assert_eq!(pick_fastest(&tied), Some(4));
+        assert_eq!(pick_fastest(&[]), None);
+
+        let candidates = thread_count_candidates();
+        assert!(!candidates.is_empty());
+        assert!(candidates.windows(2).all(|w| w[0] < w[1]));
+    }
+
     #[test]
     fn test_set_semantic_correction() {
+        let _lock = global_state_lock();
         set_semantic_correction(true).unwrap();
         assert!(SEMANTIC_CORRECTION.load(Ordering::SeqCst));
         set_semantic_correction(false).unwrap();
@@ -569,6 +8248,7 @@ mod tests {
 
     #[test]
     fn test_apply_semantic_correction_no_keywords() {
+        let _lock = global_state_lock();
         set_semantic_correction(true).unwrap();
         let input = "Today is a beautiful day.";
         let result = apply_semantic_correction(input);
@@ -578,8 +8258,204 @@ mod tests {
         assert!(!result.is_empty());
     }
 
+    #[test]
+    fn test_auto_ai_cleanup_with_mock_model() {
+        let mock = |prompt: String, _system: &str| -> Result<String> {
+            assert!(prompt.starts_with("Command: fix punctuation and capitalization"));
+            Ok("Hello, world. How are you?".to_string())
+        };
+        let cleaned = auto_ai_cleanup_with("hello world how are you".to_string(), "fix punctuation and capitalization", mock);
+        assert_eq!(cleaned, "Hello, world. How are you?");
+
+        let offline = |_: String, _: &str| -> Result<String> { Err(anyhow!("Failed to connect to Ollama")) };
+        assert_eq!(auto_ai_cleanup_with("keep me".to_string(), "fix it", offline), "keep me");
+        let empty = |_: String, _: &str| -> Result<String> { Ok("  ".to_string()) };
+        assert_eq!(auto_ai_cleanup_with("keep me".to_string(), "fix it", empty), "keep me");
+    }
+
+    #[test]
+    fn test_edited_committed_prefix_carries_forward() {
+        let _lock = global_state_lock();
+        set_committed_prefix(String::new()).unwrap();
+        assert_eq!(finish_pass(" Dear team,", true, None).unwrap().transcript, "Dear team,");
+        assert_eq!(finish_pass(" the launch", false, None).unwrap().transcript, "Dear team, the launch");
+        assert_eq!(get_committed_prefix(), "Dear team,");
+
+        set_committed_prefix("Hi all,".to_string()).unwrap();
+        let result = finish_pass(" the launch moved.", true, None).unwrap();
+        assert_eq!(result.text, "the launch moved.");
+        assert_eq!(result.transcript, "Hi all, the launch moved.");
+        assert_eq!(get_committed_prefix(), "Hi all, the launch moved.");
+        set_committed_prefix(String::new()).unwrap();
+    }
+
+    #[test]
+    fn test_regex_snippet_triggers() {
+        let _lock = global_state_lock();
+        add_regex_snippet(r"call (?P<name>\w+)".to_string(), "Calling $name now".to_string()).unwrap();
+        add_regex_snippet(r"meet at (\d+)".to_string(), "See you at ${1}pm".to_string()).unwrap();
+        add_snippet("a.b".to_string(), "Literal".to_string()).unwrap();
+        assert!(add_regex_snippet("broken (".to_string(), "x".to_string()).is_err());
+        // A bad pattern that got in by hand (or import) is skipped, not fatal
+        SNIPPETS.lock().unwrap().insert(0, VoiceSnippet {
+            id: String::new(),
+            trigger: "call (".to_string(),
+            content: "never".to_string(),
+            variants: Vec::new(),
+            is_regex: true,
+        });
+
+        assert_eq!(match_snippet("Please call Bob."), Some("Calling Bob now".to_string()));
+        assert_eq!(process_snippets("OK, meet at 3.".to_string()), "OK, See you at 3pm");
+        assert_eq!(match_snippet("recall Bob"), None);
+        assert_eq!(match_snippet("call Bob later"), None);
+        // Literal triggers are still literal
+        assert_eq!(match_snippet("a.b"), Some("Literal".to_string()));
+        assert_eq!(match_snippet("axb"), None);
+
+        SNIPPETS.lock().unwrap().retain(|s| !s.is_regex && s.trigger != "a.b");
+    }
+
+    #[test]
+    fn test_fuzzy_snippet_matching() {
+        let _lock = global_state_lock();
+        add_snippet("insert bio".to_string(), "Bio content".to_string()).unwrap();
+        add_snippet("insert big".to_string(), "Big content".to_string()).unwrap();
+
+        assert_eq!(match_snippet("insert bile"), None);
+        set_snippet_fuzzy(true, 2).unwrap();
+        assert_eq!(match_snippet("Please insert bile."), Some("Bio content".to_string()));
+        // Exact beats a fuzzy candidate that's also in range
+        assert_eq!(match_snippet("insert big"), Some("Big content".to_string()));
+        // Closest fuzzy candidate wins: one edit from "big", two from "bio"
+        assert_eq!(match_snippet("insert bigs"), Some("Big content".to_string()));
+        assert_eq!(match_snippet("insert bottle"), None);
+        assert_eq!(fuzzy_trigger_start(&snippet_words("ok insert bile"), "insert bio", 2), Some((1, 2)));
+        assert_eq!(fuzzy_trigger_start(&snippet_words("ab"), "xy", 5), None);
+
+        set_snippet_fuzzy(false, DEFAULT_SNIPPET_FUZZY_DISTANCE).unwrap();
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "insert bio" && s.trigger != "insert big");
+    }
+
+    #[test]
+    fn test_gpu_failure_falls_back_once() {
+        use Acceleration::*;
+        // Simulated GPU failure mid-session
+        let after_gpu_failure = Gpu.after_failure(true);
+        assert_eq!(after_gpu_failure, CpuFallback);
+        // Further failures on the CPU don't flip anything back
+        assert_eq!(after_gpu_failure.after_failure(true), CpuFallback);
+        assert_eq!(Gpu.after_failure(false), Gpu);
+        assert_eq!(Cpu.after_failure(true), Cpu);
+
+        let gpu_error = anyhow::Error::new(whisper_rs::WhisperError::GenericError(-6)).context("failed to run model");
+        assert!(is_inference_failure(&gpu_error));
+        assert!(!is_inference_failure(&anyhow!("Model not loaded")));
+        let mut model = None;
+        assert!(!fall_back_to_cpu(&mut model, &anyhow!("Model not loaded")));
+        assert!(model.is_none());
+    }
+
+    #[test]
+    fn test_snippet_cursor_marker() {
+        let _lock = global_state_lock();
+        add_snippet("wrap call".to_string(), "print({cursor})".to_string()).unwrap();
+        add_snippet("plain sig".to_string(), "Best, Sam".to_string()).unwrap();
+
+        let expansion = apply_snippet_expansion("wrap call", true);
+        assert_eq!(expansion, SnippetExpansion { text: "print()".to_string(), cursor_offset: Some(6) });
+        let expansion = apply_snippet_expansion("Now wrap call", true);
+        assert_eq!(expansion.text, "Now print()");
+        assert_eq!(&expansion.text[expansion.cursor_offset.unwrap()..], ")");
+        assert_eq!(apply_snippet_expansion("plain sig", true).cursor_offset, None);
+        assert_eq!(finish_pass(" wrap call", true, None).unwrap().cursor_offset, Some(6));
+
+        assert_eq!(render_snippet("a{cursor}b{cursor}c", str::to_string), SnippetExpansion { text: "abc".to_string(), cursor_offset: Some(1) });
+        assert_eq!(caret_steps_back("print()", 6).unwrap(), 1);
+        assert_eq!(caret_steps_back("é\r\nx", 0).unwrap(), 3);
+        assert!(caret_steps_back("é", 1).is_err());
+        SNIPPETS.lock().unwrap().retain(|s| s.trigger != "wrap call" && s.trigger != "plain sig");
+        set_committed_prefix(String::new()).unwrap();
+    }
+
+    #[test]
+    fn test_emission_reports_word_count_and_elapsed() {
+        let _lock = global_state_lock();
+        set_committed_prefix(String::new()).unwrap();
+        *LISTENING_STARTED.lock().unwrap() = Some(std::time::Instant::now() - std::time::Duration::from_millis(2500));
+
+        let result = finish_pass(" um, the quarterly numbers look good", true, None).unwrap();
+        assert_eq!(result.text, "the quarterly numbers look good");
+        assert_eq!(result.word_count, result.text.split_whitespace().count() as u32);
+        assert_eq!(result.word_count, 5);
+        assert!((2500..10_000).contains(&result.elapsed_ms), "elapsed {}", result.elapsed_ms);
+
+        *LISTENING_STARTED.lock().unwrap() = None;
+        assert_eq!(finish_pass(" done", true, None).unwrap().elapsed_ms, 0);
+        set_committed_prefix(String::new()).unwrap();
+    }
+
+    #[test]
+    fn test_preview_ai_chunks_splits_between_sentences() {
+        let text = "First sentence here. Second one is a bit longer! Is this the third? \
+                    Fourth.\n\nA new paragraph starts here.";
+        let chunks = preview_ai_chunks(text.to_string(), 50);
+        assert_eq!(chunks, vec![
+            "First sentence here. Second one is a bit longer!",
+            "Is this the third? Fourth.",
+            "A new paragraph starts here.",
+        ]);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 50));
+
+        // One run-on sentence falls back to word boundaries, one huge word to characters
+        let run_on = preview_ai_chunks("alpha beta gamma delta epsilon".to_string(), 12);
+        assert_eq!(run_on, vec!["alpha beta", "gamma delta", "epsilon"]);
+        assert_eq!(preview_ai_chunks("abcdefgh".to_string(), 3), vec!["abc", "def", "gh"]);
+        assert_eq!(preview_ai_chunks(" Short. ".to_string(), 0), vec!["Short."]);
+        assert!(preview_ai_chunks("   ".to_string(), 10).is_empty());
+
+        // The command path sends exactly these chunks and keeps the breaks between them
+        let mut sent = Vec::new();
+        let edited = edit_in_chunks(text, 50, |chunk| {
+            sent.push(chunk.to_string());
+            Ok(chunk.to_uppercase())
+        }).unwrap();
+        assert_eq!(sent, chunks);
+        assert_eq!(edited, "FIRST SENTENCE HERE. SECOND ONE IS A BIT LONGER! IS THIS THE THIRD? FOURTH.\n\nA NEW PARAGRAPH STARTS HERE.");
+    }
+
+    #[test]
+    fn test_short_transcriptions_skip_ai() {
+        let _lock = global_state_lock();
+        let calls = std::cell::Cell::new(0);
+        let mock = |_: String, _: &str| -> Result<String> {
+            calls.set(calls.get() + 1);
+            Ok("Cleaned up.".to_string())
+        };
+        assert_eq!(auto_ai_cleanup_with("Okay.".to_string(), "fix it", mock), "Okay.");
+        assert_eq!(auto_ai_cleanup_with("[BLANK_AUDIO]".to_string(), "fix it", mock), "[BLANK_AUDIO]");
+        assert_eq!(calls.get(), 0);
+        assert_eq!(auto_ai_cleanup_with("send the report".to_string(), "fix it", mock), "Cleaned up.");
+        assert_eq!(calls.get(), 1);
+
+        set_min_words_for_ai(4).unwrap();
+        assert_eq!(auto_ai_cleanup_with("send the report".to_string(), "fix it", mock), "send the report");
+        set_min_words_for_ai(DEFAULT_MIN_WORDS_FOR_AI).unwrap();
+    }
+
+    #[test]
+    fn test_auto_ai_cleanup_toggle() {
+        let _lock = global_state_lock();
+        assert!(set_auto_ai_cleanup(true, "  ".to_string()).is_err());
+        set_auto_ai_cleanup(true, " fix punctuation ".to_string()).unwrap();
+        assert_eq!(AUTO_AI_CLEANUP.lock().unwrap().as_deref(), Some("fix punctuation"));
+        set_auto_ai_cleanup(false, String::new()).unwrap();
+        assert_eq!(apply_auto_ai_cleanup("untouched".to_string()), "untouched");
+    }
+
     #[test]
     fn test_apply_semantic_correction_disabled() {
+        let _lock = global_state_lock();
         set_semantic_correction(false).unwrap();
         let input = "Actually, no wait, I meant this.";
         let result = apply_semantic_correction(input);
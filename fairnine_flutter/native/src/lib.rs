@@ -1,3 +1,6 @@
+// The global-state lazy_static! block outgrows the default macro recursion limit
+#![recursion_limit = "256"]
+
 mod api;
 mod bridge_generated;
 